@@ -0,0 +1,137 @@
+//! Tree-walking evaluation against compiled bytecode and the shared
+//! network, for one condition and for many triggers matched against one
+//! event.
+
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+use triggerlang::bytecode::{Frame, SlotTable, compile};
+use triggerlang::engine::{Event, evaluate_with_state};
+use triggerlang::network::Network;
+use triggerlang::parse_triggers_to_ast;
+use triggerlang::state::MemoryStateStore;
+
+// `count` triggers on message_receive, each testing a few of the same
+// fields with different constants, as moderation rule sets tend to.
+fn synthetic_triggers(count: usize) -> String {
+    let mut source = String::new();
+    for i in 0..count {
+        source.push_str(&format!(
+            r#"
+            trigger Rule{i} {{
+                on: message_receive
+                description: "rule {i}"
+                condition: !player.is_banned && (message.sender.is_admin == false || player.score > {threshold})
+                    && message.length < {length} && (message.channel == "global" || player.level >= {i})
+                action: warn_player(player.id)
+            }};
+            "#,
+            threshold = i * 10,
+            length = 200 + i,
+        ));
+    }
+    source
+}
+
+// `count` triggers, each combining a few comparisons from a small pool, as
+// rule sets testing the same handful of fields do.
+fn shared_triggers(count: usize) -> String {
+    const POOL: [&str; 12] = [
+        "!player.is_banned",
+        "message.sender.is_admin == false",
+        "message.sender.is_moderator == true",
+        "player.score > 1000",
+        "player.level >= 10",
+        "message.length < 200",
+        "message.channel == \"global\"",
+        "message.channel == \"trade\"",
+        "player.is_new",
+        "!player.is_muted",
+        "player.warnings < 3",
+        "message.has_link",
+    ];
+    let mut source = String::new();
+    for i in 0..count {
+        let pick = |n: usize| POOL[(i * (2 * n + 3) + n) % POOL.len()];
+        source.push_str(&format!(
+            r#"
+            trigger Rule{i} {{
+                on: message_receive
+                description: "rule {i}"
+                condition: {} && {} && ({} || {})
+                action: warn_player(player.id)
+            }};
+            "#,
+            pick(0),
+            pick(1),
+            pick(2),
+            pick(3),
+        ));
+    }
+    source
+}
+
+fn event() -> Event {
+    Event::new("message_receive")
+        .with("player.id", 42)
+        .with("player.is_banned", false)
+        .with("player.score", 1_250)
+        .with("player.level", 17)
+        .with("message.sender.is_admin", false)
+        .with("message.length", 120)
+        .with("message.channel", "global")
+        .with("message.text", "hello there")
+        .with("message.sender.is_moderator", false)
+        .with("player.is_new", false)
+        .with("player.is_muted", false)
+        .with("player.warnings", 1)
+        .with("message.has_link", false)
+}
+
+fn conditions(c: &mut Criterion) {
+    let store = MemoryStateStore::new();
+    let event = event();
+
+    let sets = [
+        ("distinct", 1, synthetic_triggers(1)),
+        ("distinct", 100, synthetic_triggers(100)),
+        ("shared", 100, shared_triggers(100)),
+        ("shared", 500, shared_triggers(500)),
+    ];
+    for (kind, count, source) in sets {
+        let file = parse_triggers_to_ast(&source).unwrap();
+        let exprs: Vec<_> = file
+            .triggers()
+            .iter()
+            .filter_map(|trigger| trigger.condition.clone())
+            .collect();
+        let mut slots = SlotTable::new();
+        let programs: Vec<_> = exprs.iter().map(|expr| compile(expr, &mut slots)).collect();
+
+        let network = Network::new(file.triggers());
+
+        let mut group = c.benchmark_group(format!("{} {} conditions", count, kind));
+        group.bench_function("tree walker", |b| {
+            b.iter(|| {
+                exprs
+                    .iter()
+                    .filter(|expr| evaluate_with_state(expr, black_box(&event), &store))
+                    .count()
+            })
+        });
+        group.bench_function("bytecode", |b| {
+            b.iter(|| {
+                let frame = Frame::new(&slots, black_box(&event));
+                programs
+                    .iter()
+                    .filter(|program| program.run(&frame, &store))
+                    .count()
+            })
+        });
+        group.bench_function("network", |b| {
+            b.iter(|| network.matching(black_box(&event), &store).len())
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, conditions);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+//! Parsing a large generated trigger file into the owned AST and into the
+//! AST borrowing from the source, and converting the latter into the
+//! former, which is the part of owned parsing that copies the tree.
+
+use criterion::{BatchSize, Criterion, black_box, criterion_group, criterion_main};
+use triggerlang::{parse_triggers_borrowed, parse_triggers_to_ast};
+
+// `count` triggers of the kind rule generators emit: long descriptions,
+// several string comparisons and a few actions each.
+fn generated_file(count: usize) -> String {
+    let mut source = String::new();
+    for i in 0..count {
+        source.push_str(&format!(
+            r#"
+trigger Generated{i} {{
+  on: message_receive
+  description: "Generated moderation rule number {i} for the global and trade channels"
+  condition: message.channel == "channel_{channel}" && message.text != "allowed phrase {i}"
+    && player.level >= {level} && !player.is_banned
+  action: warn_player(player.id, "Rule {i} violated")
+  action: log_event("generated_{i}", message.text)
+  tags: ["generated", "batch_{batch}"]
+}};
+"#,
+            channel = i % 50,
+            level = i % 100,
+            batch = i / 1000,
+        ));
+    }
+    source
+}
+
+fn parse(c: &mut Criterion) {
+    let count = 20_000;
+    let source = generated_file(count);
+
+    let mut group = c.benchmark_group(format!("parse {} triggers", count));
+    group.sample_size(10);
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            parse_triggers_to_ast(black_box(&source))
+                .unwrap()
+                .triggers()
+                .len()
+        })
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            parse_triggers_borrowed(black_box(&source))
+                .unwrap()
+                .triggers
+                .len()
+        })
+    });
+    let borrowed = parse_triggers_borrowed(&source).unwrap();
+    group.bench_function("into_owned", |b| {
+        b.iter_batched(
+            || borrowed.clone(),
+            |file| file.into_owned().triggers().len(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,617 @@
+//! Satisfiability checks for trigger conditions and overlap detection
+//! between triggers.
+//!
+//! A condition is expanded into disjunctive normal form and every term is
+//! checked on its own: the comparisons of a field against constants are
+//! checked together, numbers as intervals, and everything else (function
+//! calls, field-to-field comparisons) is treated as an opaque atom.
+//!
+//! Fields are read as the engine reads them: any field may be missing from
+//! an event, and every comparison against a missing field is false. So
+//! `!(x > 100)` is not `x <= 100`, and `x > 100 || x <= 100` is not always
+//! true.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::engine::compare;
+use crate::simplify::{constant, dnf_terms, negate_op};
+use crate::{Comparison, ComparisonOp, Expr, FieldPath, Trigger, TriggerFile, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Satisfiability {
+    /// The condition can never be true. Holds one conflict per DNF term.
+    Unsatisfiable {
+        conflicts: Vec<Conflict>,
+    },
+    /// The condition is true for every event.
+    AlwaysTrue {
+        example: Assignment,
+    },
+    Satisfiable {
+        example: Assignment,
+    },
+}
+
+/// A minimal set of literals that cannot hold together: dropping any one of
+/// them makes the rest satisfiable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict(pub Vec<Expr>);
+
+/// Values for the fields, function calls and opaque comparisons of a
+/// condition, keyed by their source text. A field left out is missing from
+/// the event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Assignment(pub BTreeMap<String, Value>);
+
+pub fn check_condition(expr: &Expr) -> Satisfiability {
+    let terms = dnf_terms(expr.clone());
+
+    let example = terms
+        .iter()
+        .find_map(|term| Facts::from_literals(term).example());
+    let Some(example) = example else {
+        let mut conflicts: Vec<Conflict> = Vec::new();
+        for term in terms {
+            let conflict = minimal_conflict(term);
+            if !conflicts.contains(&conflict) {
+                conflicts.push(conflict);
+            }
+        }
+        return Satisfiability::Unsatisfiable { conflicts };
+    };
+
+    let negated = dnf_terms(Expr::Not(Box::new(expr.clone())));
+    if negated
+        .iter()
+        .all(|term| !Facts::from_literals(term).consistent())
+    {
+        Satisfiability::AlwaysTrue { example }
+    } else {
+        Satisfiability::Satisfiable { example }
+    }
+}
+
+fn minimal_conflict(mut literals: Vec<Expr>) -> Conflict {
+    let mut i = 0;
+    while i < literals.len() {
+        let mut candidate = literals.clone();
+        candidate.remove(i);
+        if Facts::from_literals(&candidate).consistent() {
+            i += 1;
+        } else {
+            literals = candidate;
+        }
+    }
+    Conflict(literals)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bound {
+    value: f64,
+    inclusive: bool,
+}
+
+#[derive(Debug, Default)]
+struct NumericDomain {
+    lower: Option<Bound>,
+    upper: Option<Bound>,
+    excluded: Vec<f64>,
+}
+
+impl NumericDomain {
+    fn add(&mut self, op: &ComparisonOp, value: f64) {
+        let bound = |inclusive| Bound { value, inclusive };
+        match op {
+            ComparisonOp::Eq => {
+                self.raise_lower(bound(true));
+                self.lower_upper(bound(true));
+            }
+            ComparisonOp::Neq => self.excluded.push(value),
+            ComparisonOp::Gt => self.raise_lower(bound(false)),
+            ComparisonOp::Gte => self.raise_lower(bound(true)),
+            ComparisonOp::Lt => self.lower_upper(bound(false)),
+            ComparisonOp::Lte => self.lower_upper(bound(true)),
+        }
+    }
+
+    fn raise_lower(&mut self, bound: Bound) {
+        self.lower = Some(match self.lower {
+            Some(old) if old.value > bound.value => old,
+            Some(old) if old.value == bound.value => Bound {
+                value: old.value,
+                inclusive: old.inclusive && bound.inclusive,
+            },
+            _ => bound,
+        });
+    }
+
+    fn lower_upper(&mut self, bound: Bound) {
+        self.upper = Some(match self.upper {
+            Some(old) if old.value < bound.value => old,
+            Some(old) if old.value == bound.value => Bound {
+                value: old.value,
+                inclusive: old.inclusive && bound.inclusive,
+            },
+            _ => bound,
+        });
+    }
+
+    fn contains(&self, v: f64) -> bool {
+        let above = self
+            .lower
+            .is_none_or(|b| v > b.value || (b.inclusive && v == b.value));
+        let below = self
+            .upper
+            .is_none_or(|b| v < b.value || (b.inclusive && v == b.value));
+        above && below && !self.excluded.contains(&v)
+    }
+
+    fn consistent(&self) -> bool {
+        match (self.lower, self.upper) {
+            (Some(l), Some(u)) if l.value > u.value => false,
+            (Some(l), Some(u)) if l.value == u.value => {
+                l.inclusive && u.inclusive && !self.excluded.contains(&l.value)
+            }
+            _ => true,
+        }
+    }
+
+    fn pick(&self) -> f64 {
+        // Prefer integers near whichever bound exists; there are only
+        // finitely many exclusions, so a few candidates always suffice.
+        let attempts = self.excluded.len() + 3;
+        let start = match (self.lower, self.upper) {
+            (Some(l), _) => l.value.floor(),
+            (None, Some(u)) => u.value.ceil() - attempts as f64,
+            (None, None) => 0.0,
+        };
+        for i in 0..attempts {
+            let v = start + i as f64;
+            if self.contains(v) {
+                return v;
+            }
+        }
+
+        // A narrow interval without usable integers: bisect towards the
+        // lower bound.
+        let (l, u) = match (self.lower, self.upper) {
+            (Some(l), Some(u)) => (l.value, u.value),
+            _ => return start,
+        };
+        let mut v = (l + u) / 2.0;
+        for _ in 0..attempts {
+            if self.contains(v) {
+                break;
+            }
+            v = (l + v) / 2.0;
+        }
+        v
+    }
+}
+
+// What a literal asks of a field, compared against a constant.
+#[derive(Debug)]
+struct FieldLiteral {
+    operator: ComparisonOp,
+    value: Value,
+    positive: bool,
+}
+
+impl FieldLiteral {
+    fn holds(&self, field: &Value) -> bool {
+        compare(field, &self.operator, &self.value) == self.positive
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Boolean,
+    Number,
+    String,
+}
+
+impl Kind {
+    fn of(value: &Value) -> Option<Kind> {
+        match value {
+            Value::Boolean(_) => Some(Kind::Boolean),
+            Value::Number(_) => Some(Kind::Number),
+            Value::String(_) => Some(Kind::String),
+            _ => None,
+        }
+    }
+}
+
+// Outcomes of an opaque comparison or call. A comparison is neither true
+// nor false but unknown when a side is missing, which the engine treats as
+// false.
+const HOLDS: u8 = 1;
+const FAILS: u8 = 2;
+const UNKNOWN: u8 = 4;
+
+#[derive(Debug, Default)]
+struct Facts {
+    contradiction: bool,
+    fields: BTreeMap<String, Vec<FieldLiteral>>,
+    atoms: BTreeMap<String, u8>,
+}
+
+impl Facts {
+    fn from_literals(literals: &[Expr]) -> Self {
+        let mut facts = Facts::default();
+        for literal in literals {
+            facts.add(literal, true);
+        }
+        facts
+    }
+
+    fn add(&mut self, literal: &Expr, positive: bool) {
+        match literal {
+            Expr::Not(inner) | Expr::Parenthesized(inner) => {
+                let positive = positive != matches!(literal, Expr::Not(_));
+                self.add(inner, positive);
+            }
+            Expr::Comparison(comp) => self.add_comparison(comp, positive),
+            Expr::Ident(id) if id == "true" || id == "false" => {
+                if (id == "true") != positive {
+                    self.contradiction = true;
+                }
+            }
+            // A bare field holds exactly when it is `true`.
+            Expr::Ident(field) => {
+                self.add_field(field, ComparisonOp::Eq, Value::Boolean(true), positive)
+            }
+            other => self.add_atom(
+                other.to_string(),
+                if positive { HOLDS } else { FAILS | UNKNOWN },
+            ),
+        }
+    }
+
+    fn add_atom(&mut self, key: String, outcomes: u8) {
+        *self.atoms.entry(key).or_insert(HOLDS | FAILS | UNKNOWN) &= outcomes;
+    }
+
+    fn add_field(
+        &mut self,
+        field: &FieldPath,
+        operator: ComparisonOp,
+        value: Value,
+        positive: bool,
+    ) {
+        self.fields
+            .entry(field.to_string())
+            .or_default()
+            .push(FieldLiteral {
+                operator,
+                value,
+                positive,
+            });
+    }
+
+    fn add_comparison(&mut self, comp: &Comparison, positive: bool) {
+        if let (Some(field), Some(_)) = (comp.field(), Kind::of(&comp.right)) {
+            return self.add_field(field, comp.operator, comp.right.clone(), positive);
+        }
+        self.add_opaque(comp, positive);
+    }
+
+    // Comparisons we cannot reason about are atoms; `a != b` is stored
+    // under `a == b`, as at most one of the two holds.
+    fn add_opaque(&mut self, comp: &Comparison, positive: bool) {
+        let (operator, outcome) = match comp.operator {
+            ComparisonOp::Neq => (ComparisonOp::Eq, FAILS),
+            operator => (operator, HOLDS),
+        };
+        let key = Comparison {
+            operator,
+            ..comp.clone()
+        };
+        let outcomes = if positive {
+            outcome
+        } else {
+            (HOLDS | FAILS) & !outcome | UNKNOWN
+        };
+        self.add_atom(key.to_string(), outcomes);
+    }
+
+    fn consistent(&self) -> bool {
+        self.example().is_some()
+    }
+
+    fn example(&self) -> Option<Assignment> {
+        if self.contradiction || self.atoms.values().any(|outcomes| *outcomes == 0) {
+            return None;
+        }
+
+        let mut values = BTreeMap::new();
+        for (key, outcomes) in &self.atoms {
+            values.insert(key.clone(), Value::Boolean(outcomes & HOLDS != 0));
+        }
+        for (field, literals) in &self.fields {
+            if let Some(value) = field_value(literals)? {
+                values.insert(field.clone(), value);
+            }
+        }
+        Some(Assignment(values))
+    }
+}
+
+// A value for a field that satisfies all its literals: `Some(None)` when
+// only a missing field does, `None` when nothing does. The kinds of value
+// the literals compare against are tried first, as they make the likelier
+// examples; any other kind only ever differs.
+fn field_value(literals: &[FieldLiteral]) -> Option<Option<Value>> {
+    let mut kinds: Vec<Kind> = Vec::new();
+    for kind in literals
+        .iter()
+        .filter_map(|literal| Kind::of(&literal.value))
+        .chain([Kind::Boolean, Kind::Number, Kind::String])
+    {
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+
+    for kind in kinds {
+        let value = match kind {
+            Kind::Boolean => [true, false]
+                .into_iter()
+                .map(Value::Boolean)
+                .find(|value| literals.iter().all(|literal| literal.holds(value))),
+            Kind::Number => number_value(literals),
+            Kind::String => string_value(literals),
+        };
+        if value.is_some() {
+            return Some(value);
+        }
+    }
+
+    // Every comparison against a missing field is false.
+    literals
+        .iter()
+        .all(|literal| !literal.positive)
+        .then_some(None)
+}
+
+fn number_value(literals: &[FieldLiteral]) -> Option<Value> {
+    let mut domain = NumericDomain::default();
+    let mut others = Vec::new();
+    for literal in literals {
+        let number = match &literal.value {
+            Value::Number(n) => n.trim().parse::<f64>().ok(),
+            _ => None,
+        };
+        let Some(value) = number else {
+            others.push(literal);
+            continue;
+        };
+        let op = if literal.positive {
+            literal.operator
+        } else {
+            negate_op(&literal.operator)
+        };
+        domain.add(&op, value);
+    }
+
+    let value = Value::Number(format_number(domain.pick()));
+    (domain.consistent() && others.iter().all(|literal| literal.holds(&value))).then_some(value)
+}
+
+// Between the constants the literals mention, comparisons cannot tell two
+// strings apart. Every such stretch contains the empty string, a constant,
+// or a constant followed by the smallest character, so those candidates
+// cover all strings.
+fn string_value(literals: &[FieldLiteral]) -> Option<Value> {
+    let constants: Vec<&str> = literals
+        .iter()
+        .filter_map(|literal| match &literal.value {
+            Value::String(s) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect();
+    let readable = (0..=constants.len() + 1).map(|i| {
+        if i == 0 {
+            String::new()
+        } else {
+            format!("value{}", i)
+        }
+    });
+    let adjacent = constants
+        .iter()
+        .flat_map(|c| [c.to_string(), format!("{}\0", c)]);
+
+    readable
+        .chain(adjacent)
+        .map(Value::String)
+        .find(|value| literals.iter().all(|literal| literal.holds(value)))
+}
+
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, literal) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " AND ")?;
+            }
+            write!(f, "{}", literal)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} = {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Satisfiability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Satisfiability::Unsatisfiable { conflicts } => {
+                write!(f, "never true: ")?;
+                for (i, conflict) in conflicts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{} cannot hold together", conflict)?;
+                }
+                Ok(())
+            }
+            Satisfiability::AlwaysTrue { example } if example.0.is_empty() => {
+                write!(f, "always true")
+            }
+            Satisfiability::AlwaysTrue { example } => {
+                write!(f, "always true, e.g. {}", example)
+            }
+            Satisfiability::Satisfiable { example } => {
+                write!(f, "satisfiable, e.g. {}", example)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverlapKind {
+    /// Both conditions can hold for the same event.
+    Overlapping,
+    /// Whenever `first` fires, `second` fires too.
+    Subsumed,
+    /// The two conditions accept exactly the same events.
+    Equivalent,
+}
+
+/// Two triggers on the same event type that can fire together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlap {
+    pub event_type: String,
+    pub first: String,
+    pub second: String,
+    pub kind: OverlapKind,
+    /// An event on which both triggers fire.
+    pub example: Assignment,
+}
+
+/// Compares every pair of triggers sharing an `event_type`. A trigger
+/// without a condition, or with else actions, fires on every event of its
+/// type. Disabled triggers, which the engine does not run, are skipped, as
+/// are triggers that never fire because their own condition is
+/// unsatisfiable, since [`check_condition`] already reports them.
+pub fn find_overlaps(file: &TriggerFile) -> Vec<Overlap> {
+    overlaps(file)
+        .into_iter()
+        .map(|(_, _, overlap)| overlap)
+        .collect()
+}
+
+// Like `find_overlaps`, with the indices of the overlap's `first` and
+// `second` triggers in the file.
+pub(crate) fn overlaps(file: &TriggerFile) -> Vec<(usize, usize, Overlap)> {
+    let live: Vec<(usize, &Trigger, Expr)> = file
+        .triggers
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.enabled)
+        .map(|(index, t)| {
+            let fires = match &t.condition {
+                Some(cond) if t.else_actions.is_empty() => cond.clone(),
+                _ => constant(true),
+            };
+            (index, t, fires)
+        })
+        .filter(|(_, _, cond)| {
+            !matches!(check_condition(cond), Satisfiability::Unsatisfiable { .. })
+        })
+        .collect();
+
+    let mut overlaps = Vec::new();
+    for (i, (a_index, a, a_cond)) in live.iter().enumerate() {
+        for (b_index, b, b_cond) in &live[i + 1..] {
+            if a.event_type != b.event_type {
+                continue;
+            }
+
+            let both = Expr::And(Box::new(a_cond.clone()), Box::new(b_cond.clone()));
+            let example = match check_condition(&both) {
+                Satisfiability::Unsatisfiable { .. } => continue,
+                Satisfiability::AlwaysTrue { example }
+                | Satisfiability::Satisfiable { example } => example,
+            };
+
+            let a_in_b = implies(a_cond, b_cond);
+            let b_in_a = implies(b_cond, a_cond);
+            let a = (*a_index, a);
+            let b = (*b_index, b);
+            let (first, second, kind) = match (a_in_b, b_in_a) {
+                (true, true) => (a, b, OverlapKind::Equivalent),
+                (true, false) => (a, b, OverlapKind::Subsumed),
+                (false, true) => (b, a, OverlapKind::Subsumed),
+                (false, false) => (a, b, OverlapKind::Overlapping),
+            };
+
+            overlaps.push((
+                first.0,
+                second.0,
+                Overlap {
+                    event_type: a.1.event_type.clone(),
+                    first: first.1.name.clone(),
+                    second: second.1.name.clone(),
+                    kind,
+                    example,
+                },
+            ));
+        }
+    }
+    overlaps
+}
+
+fn implies(premise: &Expr, conclusion: &Expr) -> bool {
+    let counterexample = Expr::And(
+        Box::new(premise.clone()),
+        Box::new(Expr::Not(Box::new(conclusion.clone()))),
+    );
+    matches!(
+        check_condition(&counterexample),
+        Satisfiability::Unsatisfiable { .. }
+    )
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            OverlapKind::Overlapping => write!(
+                f,
+                "triggers `{}` and `{}` can both fire on {}",
+                self.first, self.second, self.event_type
+            )?,
+            OverlapKind::Subsumed => write!(
+                f,
+                "trigger `{}` is subsumed by `{}` on {}: whenever it fires, `{}` fires too",
+                self.first, self.second, self.event_type, self.second
+            )?,
+            OverlapKind::Equivalent => write!(
+                f,
+                "triggers `{}` and `{}` fire on exactly the same {} events",
+                self.first, self.second, self.event_type
+            )?,
+        }
+        if !self.example.0.is_empty() {
+            write!(f, ", e.g. {}", self.example)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! A trigger AST borrowing its text from the source.
+//!
+//! [`parse_triggers_borrowed`](crate::parse_triggers_borrowed) builds a
+//! [`TriggerFile`] whose names, identifiers, strings and numbers are slices
+//! of the input instead of fresh `String`s, which makes loading large
+//! generated files much cheaper when only part of the AST is needed, or
+//! when it is converted lazily. [`TriggerFile::into_owned`] gives the
+//! [`crate::TriggerFile`] that [`parse_triggers_to_ast`] returns; the
+//! owned parser is in fact this one followed by `into_owned`, once it has
+//! checked the file's field paths against
+//! [`Limits::max_interned_bytes`](crate::Limits::max_interned_bytes).
+//! `into_owned` interns them without that check.
+//!
+//! Text not found in the source as is, such as the triggers expanded from
+//! templates, is held as [`Cow::Owned`]. Schedules, patterns, cooldowns,
+//! aggregates and extra fields use the owned types, as do the file's
+//! imports, named conditions and templates.
+//!
+//! [`parse_triggers_to_ast`]: crate::parse_triggers_to_ast
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::fields::FieldValue;
+use crate::schedule::Schedule;
+use crate::{
+    Aggregate, ComparisonOp, ConditionDef, Cooldown, EventPattern, FieldPath, Import, Location,
+    RateLimit, Span, TemplateDef, TriggerParserError,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerFile<'src> {
+    pub triggers: Vec<Trigger<'src>>,
+    pub imports: Vec<Import>,
+    pub conditions: Vec<ConditionDef>,
+    pub templates: Vec<TemplateDef>,
+}
+
+/// A trigger as in [`crate::Trigger`]. It has no origin file, as borrowed
+/// files are parsed from a string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trigger<'src> {
+    pub name: Cow<'src, str>,
+    pub event_type: Cow<'src, str>,
+    pub schedule: Option<Schedule>,
+    pub pattern: Option<EventPattern>,
+    pub description: Cow<'src, str>,
+    pub condition: Option<Expr<'src>>,
+    pub actions: Vec<Action<'src>>,
+    pub else_actions: Vec<Action<'src>>,
+    pub cooldown: Option<Cooldown>,
+    pub max_fires: Option<RateLimit>,
+    pub priority: i32,
+    pub enabled: bool,
+    pub tags: Vec<Cow<'src, str>>,
+    pub fields: BTreeMap<String, Vec<FieldValue>>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<'src> {
+    And(Box<Expr<'src>>, Box<Expr<'src>>),
+    Or(Box<Expr<'src>>, Box<Expr<'src>>),
+    Not(Box<Expr<'src>>),
+    Comparison(Comparison<'src>),
+    FuncCall(FuncCall<'src>),
+    /// A field path as written, split into a [`FieldPath`] by
+    /// [`into_owned`](Expr::into_owned).
+    Ident(Cow<'src, str>),
+    Parenthesized(Box<Expr<'src>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison<'src> {
+    pub left: Value<'src>,
+    pub operator: ComparisonOp,
+    pub right: Value<'src>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncCall<'src> {
+    pub name: Cow<'src, str>,
+    pub args: Vec<Value<'src>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action<'src> {
+    Call(FuncCall<'src>),
+    If {
+        condition: Expr<'src>,
+        then: Vec<Action<'src>>,
+        otherwise: Vec<Action<'src>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value<'src> {
+    Boolean(bool),
+    Number(Cow<'src, str>),
+    String(Cow<'src, str>),
+    Ident(Cow<'src, str>),
+    Aggregate(Box<Aggregate>),
+}
+
+impl TriggerFile<'_> {
+    pub fn into_owned(self) -> crate::TriggerFile {
+        crate::TriggerFile::new(
+            self.triggers.into_iter().map(Trigger::into_owned).collect(),
+            self.imports,
+            self.conditions,
+            self.templates,
+        )
+    }
+
+    pub(crate) fn check_unique_names(&self) -> Result<(), TriggerParserError> {
+        let mut seen: HashMap<&str, &Trigger> = HashMap::new();
+        for trigger in &self.triggers {
+            if let Some(first) = seen.get(&*trigger.name) {
+                return Err(TriggerParserError::DuplicateTrigger {
+                    name: trigger.name.to_string(),
+                    first: Box::new(first.location()),
+                    second: Box::new(trigger.location()),
+                });
+            }
+            seen.insert(&trigger.name, trigger);
+        }
+        Ok(())
+    }
+}
+
+impl Trigger<'_> {
+    pub fn location(&self) -> Location {
+        Location {
+            file: None,
+            span: self.span,
+        }
+    }
+
+    pub fn into_owned(self) -> crate::Trigger {
+        crate::Trigger {
+            name: self.name.into_owned(),
+            event_type: self.event_type.into_owned(),
+            schedule: self.schedule,
+            pattern: self.pattern,
+            description: self.description.into_owned(),
+            condition: self.condition.map(Expr::into_owned),
+            actions: into_owned_actions(self.actions),
+            else_actions: into_owned_actions(self.else_actions),
+            cooldown: self.cooldown,
+            max_fires: self.max_fires,
+            priority: self.priority,
+            enabled: self.enabled,
+            tags: self.tags.into_iter().map(Cow::into_owned).collect(),
+            fields: self.fields,
+            span: self.span,
+            origin: None,
+        }
+    }
+}
+
+fn into_owned_actions(actions: Vec<Action>) -> Vec<crate::Action> {
+    actions.into_iter().map(Action::into_owned).collect()
+}
+
+impl Expr<'_> {
+    /// As [`crate::Expr::depth`].
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut stack = vec![(self, 0)];
+        while let Some((expr, depth)) = stack.pop() {
+            deepest = deepest.max(depth);
+            match expr {
+                Expr::And(left, right) | Expr::Or(left, right) => {
+                    stack.push((left, depth + 1));
+                    stack.push((right, depth + 1));
+                }
+                Expr::Not(inner) | Expr::Parenthesized(inner) => stack.push((inner, depth + 1)),
+                Expr::Comparison(_) | Expr::FuncCall(_) | Expr::Ident(_) => {}
+            }
+        }
+        deepest
+    }
+
+    pub fn into_owned(self) -> crate::Expr {
+        let boxed = |expr: Box<Expr>| Box::new(expr.into_owned());
+        match self {
+            Expr::And(left, right) => crate::Expr::And(boxed(left), boxed(right)),
+            Expr::Or(left, right) => crate::Expr::Or(boxed(left), boxed(right)),
+            Expr::Not(inner) => crate::Expr::Not(boxed(inner)),
+            Expr::Comparison(comp) => crate::Expr::Comparison(comp.into_owned()),
+            Expr::FuncCall(func) => crate::Expr::FuncCall(func.into_owned()),
+            Expr::Ident(id) => crate::Expr::Ident(FieldPath::parse(&id)),
+            Expr::Parenthesized(inner) => crate::Expr::Parenthesized(boxed(inner)),
+        }
+    }
+}
+
+impl Comparison<'_> {
+    pub fn into_owned(self) -> crate::Comparison {
+        crate::Comparison {
+            left: self.left.into_owned(),
+            operator: self.operator,
+            right: self.right.into_owned(),
+        }
+    }
+}
+
+impl FuncCall<'_> {
+    pub fn into_owned(self) -> crate::FuncCall {
+        crate::FuncCall {
+            name: self.name.into_owned(),
+            args: self.args.into_iter().map(Value::into_owned).collect(),
+        }
+    }
+}
+
+impl Action<'_> {
+    pub fn into_owned(self) -> crate::Action {
+        match self {
+            Action::Call(func) => crate::Action::Call(func.into_owned()),
+            Action::If {
+                condition,
+                then,
+                otherwise,
+            } => crate::Action::If {
+                condition: condition.into_owned(),
+                then: into_owned_actions(then),
+                otherwise: into_owned_actions(otherwise),
+            },
+        }
+    }
+}
+
+impl Value<'_> {
+    pub fn into_owned(self) -> crate::Value {
+        match self {
+            Value::Boolean(b) => crate::Value::Boolean(b),
+            Value::Number(n) => crate::Value::Number(n.into_owned()),
+            Value::String(s) => crate::Value::String(s.into_owned()),
+            Value::Ident(id) => crate::Value::Ident(FieldPath::parse(&id)),
+            Value::Aggregate(aggregate) => crate::Value::Aggregate(aggregate),
+        }
+    }
+}
+
+// Owned nodes, for triggers expanded from templates and conditions defined
+// in other files.
+
+impl From<crate::Trigger> for Trigger<'static> {
+    fn from(trigger: crate::Trigger) -> Self {
+        Trigger {
+            name: Cow::Owned(trigger.name),
+            event_type: Cow::Owned(trigger.event_type),
+            schedule: trigger.schedule,
+            pattern: trigger.pattern,
+            description: Cow::Owned(trigger.description),
+            condition: trigger.condition.map(Expr::from),
+            actions: trigger.actions.into_iter().map(Action::from).collect(),
+            else_actions: trigger.else_actions.into_iter().map(Action::from).collect(),
+            cooldown: trigger.cooldown,
+            max_fires: trigger.max_fires,
+            priority: trigger.priority,
+            enabled: trigger.enabled,
+            tags: trigger.tags.into_iter().map(Cow::Owned).collect(),
+            fields: trigger.fields,
+            span: trigger.span,
+        }
+    }
+}
+
+impl From<crate::Expr> for Expr<'static> {
+    fn from(expr: crate::Expr) -> Self {
+        let boxed = |expr: Box<crate::Expr>| Box::new(Expr::from(*expr));
+        match expr {
+            crate::Expr::And(left, right) => Expr::And(boxed(left), boxed(right)),
+            crate::Expr::Or(left, right) => Expr::Or(boxed(left), boxed(right)),
+            crate::Expr::Not(inner) => Expr::Not(boxed(inner)),
+            crate::Expr::Comparison(comp) => Expr::Comparison(Comparison {
+                left: comp.left.into(),
+                operator: comp.operator,
+                right: comp.right.into(),
+            }),
+            crate::Expr::FuncCall(func) => Expr::FuncCall(func.into()),
+            crate::Expr::Ident(id) => Expr::Ident(Cow::Owned(id.to_string())),
+            crate::Expr::Parenthesized(inner) => Expr::Parenthesized(boxed(inner)),
+        }
+    }
+}
+
+impl From<crate::FuncCall> for FuncCall<'static> {
+    fn from(func: crate::FuncCall) -> Self {
+        FuncCall {
+            name: Cow::Owned(func.name),
+            args: func.args.into_iter().map(Value::from).collect(),
+        }
+    }
+}
+
+impl From<crate::Action> for Action<'static> {
+    fn from(action: crate::Action) -> Self {
+        match action {
+            crate::Action::Call(func) => Action::Call(func.into()),
+            crate::Action::If {
+                condition,
+                then,
+                otherwise,
+            } => Action::If {
+                condition: condition.into(),
+                then: then.into_iter().map(Action::from).collect(),
+                otherwise: otherwise.into_iter().map(Action::from).collect(),
+            },
+        }
+    }
+}
+
+impl From<crate::Value> for Value<'static> {
+    fn from(value: crate::Value) -> Self {
+        match value {
+            crate::Value::Boolean(b) => Value::Boolean(b),
+            crate::Value::Number(n) => Value::Number(Cow::Owned(n)),
+            crate::Value::String(s) => Value::String(Cow::Owned(s)),
+            crate::Value::Ident(id) => Value::Ident(Cow::Owned(id.to_string())),
+            crate::Value::Aggregate(aggregate) => Value::Aggregate(aggregate),
+        }
+    }
+}
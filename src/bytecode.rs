@@ -0,0 +1,388 @@
+//! Conditions compiled to bytecode.
+//!
+//! A condition compiled into a [`Program`] runs without walking the
+//! [`Expr`] tree. Field paths are resolved to slots of a [`SlotTable`]
+//! shared by all programs, so that a [`Frame`] looks each path up in an
+//! event at most once however many conditions use it. Literals are pooled
+//! per program. The [`Engine`](crate::engine::Engine) runs the conditions
+//! of `if` blocks as programs. For trigger conditions it goes further and
+//! shares whole comparisons between triggers; see
+//! [`network`](crate::network).
+//!
+//! Programs run on a small stack machine of booleans. `&&` and `||` compile
+//! to jumps that skip the right operand once the left one decides the
+//! result, exactly like the tree walker in [`engine::evaluate`]. The
+//! network's tests are the same leaf instructions, run the same way.
+//!
+//! [`engine::evaluate`]: crate::engine::evaluate
+
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::engine::{Event, History, compare};
+use crate::limits::Budget;
+use crate::state::{self, StateStore};
+use crate::{Aggregate, ComparisonOp, Expr, FieldPath, Value};
+
+/// Field paths used by a set of programs, each with a slot index.
+#[derive(Debug, Clone, Default)]
+pub struct SlotTable {
+    paths: Vec<FieldPath>,
+    index: HashMap<FieldPath, u32>,
+}
+
+impl SlotTable {
+    pub fn new() -> Self {
+        SlotTable::default()
+    }
+
+    pub(crate) fn slot(&mut self, path: &FieldPath) -> u32 {
+        if let Some(slot) = self.index.get(path) {
+            return *slot;
+        }
+        let slot = self.paths.len() as u32;
+        self.paths.push(path.clone());
+        self.index.insert(path.clone(), slot);
+        slot
+    }
+
+    pub fn path(&self, slot: u32) -> &FieldPath {
+        &self.paths[slot as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// Pushes a constant result.
+    Push(bool),
+    /// Pushes whether the field in the slot is `true`.
+    Truthy(u32),
+    /// Pushes the result of a comparison.
+    Compare {
+        left: Operand,
+        op: ComparisonOp,
+        right: Operand,
+    },
+    /// Pushes whether the state flag named by the constant is `true`, for
+    /// `get("<name>")`.
+    Get(u32),
+    /// Negates the top of the stack.
+    Not,
+    /// Jumps if the top of the stack is false, keeping it; otherwise pops
+    /// it.
+    JumpIfFalse(u32),
+    /// Jumps if the top of the stack is true, keeping it; otherwise pops it.
+    JumpIfTrue(u32),
+}
+
+/// A side of a comparison: a field slot, a pooled constant or an aggregate
+/// of the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Slot(u32),
+    Const(u32),
+    Aggregate(u32),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    code: Vec<Op>,
+    pool: Pool,
+}
+
+// The constants and aggregates that operands refer to, each once.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Pool {
+    constants: Vec<Value>,
+    constant_ids: HashMap<Value, u32>,
+    aggregates: Vec<Aggregate>,
+    aggregate_ids: HashMap<Aggregate, u32>,
+}
+
+/// Compiles `expr`, adding the field paths it uses to `slots`.
+pub fn compile(expr: &Expr, slots: &mut SlotTable) -> Program {
+    let mut compiler = Compiler {
+        slots,
+        program: Program::default(),
+    };
+    compiler.expr(expr);
+    compiler.program
+}
+
+struct Compiler<'s> {
+    slots: &'s mut SlotTable,
+    program: Program,
+}
+
+impl Compiler<'_> {
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::And(left, right) => self.short_circuit(left, right, Op::JumpIfFalse),
+            Expr::Or(left, right) => self.short_circuit(left, right, Op::JumpIfTrue),
+            Expr::Not(inner) => {
+                self.expr(inner);
+                self.emit(Op::Not);
+            }
+            Expr::Parenthesized(inner) => self.expr(inner),
+            leaf => {
+                let op = self.program.pool.leaf(leaf, self.slots);
+                self.emit(op);
+            }
+        }
+    }
+
+    fn short_circuit(&mut self, left: &Expr, right: &Expr, jump: fn(u32) -> Op) {
+        self.expr(left);
+        let at = self.program.code.len();
+        self.emit(jump(0));
+        self.expr(right);
+        self.program.code[at] = jump(self.program.code.len() as u32);
+    }
+
+    fn emit(&mut self, op: Op) {
+        self.program.code.push(op);
+    }
+}
+
+impl Pool {
+    // The instruction for a comparison, call or identifier, adding what it
+    // uses to the pool and `slots`.
+    pub(crate) fn leaf(&mut self, leaf: &Expr, slots: &mut SlotTable) -> Op {
+        match leaf {
+            Expr::Comparison(comp) => {
+                let left = self.operand(&comp.left, slots);
+                let right = self.operand(&comp.right, slots);
+                let op = comp.operator;
+                Op::Compare { left, op, right }
+            }
+            Expr::FuncCall(func) => match (func.name.as_str(), func.args.as_slice()) {
+                (state::GET, [name @ Value::String(_)]) => Op::Get(self.constant(name)),
+                _ => Op::Push(false),
+            },
+            Expr::Ident(path) => match path.as_name() {
+                Some("true") => Op::Push(true),
+                Some("false") => Op::Push(false),
+                _ => Op::Truthy(slots.slot(path)),
+            },
+            Expr::And(..) | Expr::Or(..) | Expr::Not(_) | Expr::Parenthesized(_) => {
+                unreachable!("not a leaf")
+            }
+        }
+    }
+
+    fn operand(&mut self, value: &Value, slots: &mut SlotTable) -> Operand {
+        match value {
+            Value::Ident(path) => Operand::Slot(slots.slot(path)),
+            Value::Aggregate(aggregate) => {
+                let next = self.aggregates.len() as u32;
+                let id = *self
+                    .aggregate_ids
+                    .entry(aggregate.as_ref().clone())
+                    .or_insert(next);
+                if id == next {
+                    self.aggregates.push(aggregate.as_ref().clone());
+                }
+                Operand::Aggregate(id)
+            }
+            value => Operand::Const(self.constant(value)),
+        }
+    }
+
+    fn constant(&mut self, value: &Value) -> u32 {
+        if let Some(index) = self.constant_ids.get(value) {
+            return *index;
+        }
+        let index = self.constants.len() as u32;
+        self.constants.push(value.clone());
+        self.constant_ids.insert(value.clone(), index);
+        index
+    }
+
+    // Runs a leaf instruction. Aggregates take a step of `budget` for each
+    // recorded event they look at.
+    pub(crate) fn test(
+        &self,
+        op: Op,
+        frame: &Frame,
+        store: &dyn StateStore,
+        history: Option<(&History, SystemTime)>,
+        budget: Option<&Budget>,
+    ) -> bool {
+        match op {
+            Op::Push(b) => b,
+            Op::Truthy(slot) => matches!(
+                frame.get(slot, store).as_deref(),
+                Some(Value::Boolean(true))
+            ),
+            Op::Compare { left, op, right } => {
+                let left = self.operand_value(left, frame, store, history, budget);
+                let right = self.operand_value(right, frame, store, history, budget);
+                match (left, right) {
+                    (Some(left), Some(right)) => compare(&left, &op, &right),
+                    _ => false,
+                }
+            }
+            Op::Get(constant) => {
+                let Value::String(name) = &self.constants[constant as usize] else {
+                    unreachable!("get() takes a string constant");
+                };
+                let key = state::state_key(name, frame.event);
+                matches!(store.get(&key), Some(Value::Boolean(true)))
+            }
+            Op::Not | Op::JumpIfFalse(_) | Op::JumpIfTrue(_) => unreachable!("not a leaf"),
+        }
+    }
+
+    fn operand_value<'a>(
+        &'a self,
+        operand: Operand,
+        frame: &'a Frame<'_>,
+        store: &dyn StateStore,
+        history: Option<(&History, SystemTime)>,
+        budget: Option<&Budget>,
+    ) -> Option<Cow<'a, Value>> {
+        match operand {
+            Operand::Slot(slot) => frame.get(slot, store),
+            Operand::Const(index) => Some(Cow::Borrowed(&self.constants[index as usize])),
+            Operand::Aggregate(index) => {
+                let (history, now) = history?;
+                history
+                    .aggregate(&self.aggregates[index as usize], frame.event, now, budget)
+                    .map(Cow::Owned)
+            }
+        }
+    }
+}
+
+/// The fields of one event, looked up by slot on first use.
+pub struct Frame<'e> {
+    slots: &'e SlotTable,
+    event: &'e Event,
+    values: Vec<OnceCell<Option<&'e Value>>>,
+}
+
+impl<'e> Frame<'e> {
+    pub fn new(slots: &'e SlotTable, event: &'e Event) -> Self {
+        Frame {
+            slots,
+            event,
+            values: (0..slots.len()).map(|_| OnceCell::new()).collect(),
+        }
+    }
+
+    pub fn event(&self) -> &'e Event {
+        self.event
+    }
+
+    // The slot's value: the event's field, or else stored state. State is
+    // not cached, as actions may change it between two programs.
+    pub(crate) fn get(&self, slot: u32, store: &dyn StateStore) -> Option<Cow<'e, Value>> {
+        let path = self.slots.path(slot);
+        let field = self.values[slot as usize].get_or_init(|| self.event.get_path(path));
+        match field {
+            Some(value) => Some(Cow::Borrowed(*value)),
+            None => state::lookup(path, self.event, store).map(Cow::Owned),
+        }
+    }
+}
+
+impl Program {
+    /// Runs the program for the event of `frame`. Aggregates never hold
+    /// here, as they need the engine's event history.
+    pub fn run(&self, frame: &Frame, store: &dyn StateStore) -> bool {
+        self.execute(frame, store, None, None)
+    }
+
+    // Runs the program, taking a step of `budget` for every instruction.
+    // Once it is spent, the program does not hold.
+    pub(crate) fn execute(
+        &self,
+        frame: &Frame,
+        store: &dyn StateStore,
+        history: Option<(&History, SystemTime)>,
+        budget: Option<&Budget>,
+    ) -> bool {
+        let mut stack = Stack::default();
+        let mut pc = 0;
+
+        while let Some(op) = self.code.get(pc) {
+            if budget.is_some_and(|budget| !budget.step()) {
+                return false;
+            }
+            pc += 1;
+            match *op {
+                Op::Not => {
+                    let top = stack.pop();
+                    stack.push(!top);
+                }
+                Op::JumpIfFalse(target) => {
+                    if stack.peek() {
+                        stack.pop();
+                    } else {
+                        pc = target as usize;
+                    }
+                }
+                Op::JumpIfTrue(target) => {
+                    if stack.peek() {
+                        pc = target as usize;
+                    } else {
+                        stack.pop();
+                    }
+                }
+                leaf => stack.push(self.pool.test(leaf, frame, store, history, budget)),
+            }
+        }
+
+        stack.pop()
+    }
+
+    pub fn code(&self) -> &[Op] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.pool.constants
+    }
+}
+
+// A stack of booleans packed into a word. Since `&&` and `||` pop their left
+// operand before pushing the right one, programs never need more than one
+// entry; the spare bits only make that an implementation detail.
+#[derive(Default)]
+struct Stack {
+    bits: u64,
+    len: u32,
+}
+
+impl Stack {
+    fn push(&mut self, value: bool) {
+        debug_assert!(self.len < u64::BITS, "condition stack overflow");
+        self.bits = (self.bits << 1) | u64::from(value);
+        self.len += 1;
+    }
+
+    // An empty program, like an absent condition, holds.
+    fn pop(&mut self) -> bool {
+        if self.len == 0 {
+            return true;
+        }
+        let top = self.bits & 1 == 1;
+        self.bits >>= 1;
+        self.len -= 1;
+        top
+    }
+
+    fn peek(&self) -> bool {
+        self.len == 0 || self.bits & 1 == 1
+    }
+}
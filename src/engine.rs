@@ -0,0 +1,1233 @@
+//! Running triggers against events.
+//!
+//! An [`Engine`] holds the triggers of a [`TriggerFile`] and, for every
+//! dispatched [`Event`], returns the actions of the triggers that fire.
+//! Cooldowns and rate limits read the time from a [`Clock`], which tests
+//! replace with a [`ManualClock`].
+//!
+//! Triggers matching the same event run by descending `priority`; triggers
+//! with equal priority run in file order (for projects, the merged order
+//! produced by [`load_project`](crate::project::load_project)). When a
+//! trigger that fires calls [`STOP_PROPAGATION`], the triggers after it are
+//! not run for that event.
+//!
+//! Trigger conditions are merged into a [`Network`] when the engine is
+//! created, so that a comparison shared by several triggers runs once per
+//! event; conditions of `if` blocks are compiled to
+//! [`bytecode`](crate::bytecode) programs.
+//!
+//! State set by triggers lives in a [`StateStore`], in memory unless the
+//! engine is given another one with [`Engine::with_state`]; see
+//! [`state`](crate::state) for how triggers read and change it.
+//!
+//! For aggregates such as `count(message_receive, player.id, 1m)`, the
+//! engine records the recent events of the types they use, keeping only
+//! the fields they read. Events are dropped once older than every window
+//! using them or than the retention, and the oldest ones go first when
+//! more are kept than the cap; see [`Engine::with_history_limits`].
+//!
+//! The work done for one event is bounded by the steps and time of
+//! [`Limits`]; see [`Engine::dispatch_checked`].
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+
+use crate::bytecode::{self, Frame, Program, SlotTable};
+use crate::limits::{Budget, Limits, Overrun};
+use crate::network::Network;
+use crate::path::FieldPath;
+use crate::payload::EventPayload;
+use crate::state::{self, MemoryStateStore, StateStore};
+use crate::visit::{Visitor, walk_action, walk_value};
+use crate::{
+    Action, Aggregate, AggregateFn, ComparisonOp, EventPattern, Expr, FuncCall, Trigger,
+    TriggerFile, Value,
+};
+
+/// What can go wrong while the engine runs triggers, as opposed to
+/// [`TriggerParserError`](crate::TriggerParserError)s, which come from
+/// loading them.
+#[derive(Error, Debug)]
+pub enum EngineError {
+    #[error("Ran out of the budget of {kind} per event at trigger '{trigger}'")]
+    BudgetExceeded { trigger: String, kind: Overrun },
+    #[error("Invalid action '{action}': expected {expected}")]
+    InvalidAction { action: String, expected: String },
+    #[error("{0} is not a finite number")]
+    NonFiniteNumber(f64),
+    #[error("State store {}: {message}", path.display())]
+    StateError { path: PathBuf, message: String },
+}
+
+/// An event as seen by the engine: its type and its fields, nested by the
+/// segments of their paths (e.g. `player.score`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    pub event_type: String,
+    pub payload: EventPayload,
+}
+
+impl Event {
+    pub fn new(event_type: impl Into<String>) -> Self {
+        Event {
+            event_type: event_type.into(),
+            payload: EventPayload::default(),
+        }
+    }
+
+    /// An event carrying a payload received from elsewhere.
+    pub fn from_payload(event_type: impl Into<String>, payload: EventPayload) -> Self {
+        Event {
+            event_type: event_type.into(),
+            payload,
+        }
+    }
+
+    pub fn with(mut self, path: &str, value: impl Into<Value>) -> Self {
+        self.payload.insert(path, EventPayload::Value(value.into()));
+        self
+    }
+
+    /// The scalar field at `path`, as written.
+    pub fn get(&self, path: &str) -> Option<&Value> {
+        self.payload.get_str(path)?.as_value()
+    }
+
+    /// The scalar field at `path`.
+    pub fn get_path(&self, path: &FieldPath) -> Option<&Value> {
+        self.payload.get(path)?.as_value()
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Number(n.to_string())
+    }
+}
+
+/// Fails for NaN and infinities, which have no number literal.
+impl TryFrom<f64> for Value {
+    type Error = EngineError;
+
+    fn try_from(n: f64) -> Result<Self, Self::Error> {
+        if !n.is_finite() {
+            return Err(EngineError::NonFiniteNumber(n));
+        }
+        Ok(Value::Number(n.to_string()))
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test can keep one and hand another to the engine.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// The action that stops lower-priority triggers from running. `action: stop`
+/// is shorthand for it.
+pub const STOP_PROPAGATION: &str = "stop_propagation";
+
+/// A trigger that fired, with the actions to run. The engine handles
+/// [`STOP_PROPAGATION`] and the [state actions](crate::state) itself and
+/// leaves them out of `actions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firing {
+    pub trigger: String,
+    pub actions: Vec<FuncCall>,
+    /// The trigger stopped propagation; it is the last firing of the event.
+    pub stopped: bool,
+    /// State actions that could not be applied, e.g. because the store
+    /// failed to save.
+    pub state_errors: Vec<String>,
+    // Whether any state action was applied, and so needs saving.
+    changed_state: bool,
+}
+
+pub struct Engine {
+    triggers: Vec<Trigger>,
+    clock: Box<dyn Clock>,
+    limiter: Limiter,
+    state: Box<dyn StateStore>,
+    history: History,
+    network: Network,
+    branches: Branches,
+    // Whether each trigger, by index, has state actions.
+    writes_state: Vec<bool>,
+    started: SystemTime,
+    // When each scheduled trigger, by index, is next due.
+    next_due: Vec<Option<SystemTime>>,
+    // Partial matches of each trigger's event pattern, by index.
+    patterns: Vec<PatternState>,
+    max_pattern_keys: usize,
+    limits: Limits,
+}
+
+/// How many correlation keys each pattern trigger tracks partial matches
+/// for, unless changed with [`Engine::with_max_pattern_keys`].
+pub const DEFAULT_MAX_PATTERN_KEYS: usize = 10_000;
+
+/// How long recorded events are kept at most, unless changed with
+/// [`Engine::with_history_limits`]. Aggregates over longer windows only see
+/// this far back.
+pub const DEFAULT_HISTORY_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How many recorded events are kept at most, unless changed with
+/// [`Engine::with_history_limits`].
+pub const DEFAULT_MAX_HISTORY_EVENTS: usize = 100_000;
+
+impl Engine {
+    pub fn new(file: TriggerFile) -> Self {
+        Engine::with_clock(file, SystemClock)
+    }
+
+    /// An engine running the enabled triggers of `file`. To run only some
+    /// of them, pass a file narrowed with [`TriggerFile::filtered`].
+    pub fn with_clock(file: TriggerFile, clock: impl Clock + 'static) -> Self {
+        let mut triggers = file.triggers;
+        triggers.retain(|trigger| trigger.enabled);
+        // Stable, so equal priorities keep their file order.
+        triggers.sort_by_key(|trigger| std::cmp::Reverse(trigger.priority));
+        let started = clock.now();
+        let next_due = triggers
+            .iter()
+            .map(|trigger| {
+                let schedule = trigger.schedule.as_ref()?;
+                schedule.next_after(started, started)
+            })
+            .collect();
+        let writes_state = triggers
+            .iter()
+            .map(|trigger| trigger.calls().into_iter().any(state::is_state_action))
+            .collect();
+        Engine {
+            clock: Box::new(clock),
+            limiter: Limiter::default(),
+            state: Box::new(MemoryStateStore::new()),
+            history: History::for_triggers(&triggers),
+            network: Network::new(&triggers),
+            branches: Branches::new(&triggers),
+            writes_state,
+            started,
+            next_due,
+            patterns: triggers.iter().map(|_| PatternState::default()).collect(),
+            triggers,
+            max_pattern_keys: DEFAULT_MAX_PATTERN_KEYS,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Bounds the work done per event by the steps and time of `limits`,
+    /// instead of the defaults. The parsing limits are not used.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Caps the correlation keys tracked per pattern trigger. When a new
+    /// key would exceed the cap, keys whose partial matches all expired are
+    /// dropped first, then the key least recently started on.
+    pub fn with_max_pattern_keys(mut self, max: usize) -> Self {
+        self.max_pattern_keys = max.max(1);
+        self
+    }
+
+    /// Keeps trigger state in `store`, e.g. a
+    /// [`FileStateStore`](crate::state::FileStateStore) so that it survives
+    /// restarts.
+    pub fn with_state(mut self, store: impl StateStore + 'static) -> Self {
+        self.state = Box::new(store);
+        self
+    }
+
+    pub fn state(&self) -> &dyn StateStore {
+        self.state.as_ref()
+    }
+
+    /// Keeps recorded events for at most `retention`, and at most
+    /// `max_events` of them.
+    pub fn with_history_limits(mut self, retention: Duration, max_events: usize) -> Self {
+        self.history.retention = retention;
+        self.history.max_events = max_events;
+        self
+    }
+
+    /// Events currently recorded for aggregates.
+    pub fn recorded_events(&self) -> usize {
+        self.history.len
+    }
+
+    /// Partial pattern matches currently tracked, over all triggers and keys.
+    pub fn pending_matches(&self) -> usize {
+        self.patterns
+            .iter()
+            .flat_map(|state| state.keys.values())
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// All triggers, in the order they run.
+    pub fn triggers(&self) -> &[Trigger] {
+        &self.triggers
+    }
+
+    /// The triggers listening for `event_type`, in the order they run,
+    /// pattern triggers with a step of that type included.
+    pub fn firing_order<'a>(&'a self, event_type: &'a str) -> impl Iterator<Item = &'a Trigger> {
+        self.triggers
+            .iter()
+            .filter(move |trigger| trigger.listens_for(event_type))
+    }
+
+    /// The scheduled triggers, in the order [`tick`](Engine::tick) runs
+    /// them.
+    pub fn scheduled(&self) -> impl Iterator<Item = &Trigger> {
+        self.triggers
+            .iter()
+            .filter(|trigger| trigger.schedule.is_some())
+    }
+
+    /// Runs the triggers listening for the event's type, in firing order,
+    /// and returns those that fire. A trigger fires when its condition
+    /// holds, running its actions, or when it does not and the trigger has
+    /// else actions, running those. A trigger held back by its cooldown or
+    /// rate limit does not fire, and that attempt does not count towards its
+    /// limits. Scheduled triggers only run from [`tick`](Engine::tick).
+    ///
+    /// Pattern triggers count the event towards their pattern if it is of
+    /// one of the pattern's types, carries the correlation key and satisfies
+    /// the trigger's condition, and fire when it completes a match. The
+    /// match is then consumed: its key starts over.
+    ///
+    /// The event is recorded for aggregates first, so those over its own
+    /// type count it.
+    pub fn dispatch(&mut self, event: &Event) -> Vec<Firing> {
+        self.dispatch_checked(event).0
+    }
+
+    /// Like [`dispatch`](Engine::dispatch), also telling whether the event
+    /// ran out of its budget of steps or time. If it did, the trigger it ran
+    /// out at does not fire and the triggers after it are not run; those
+    /// that fired before are returned as usual.
+    pub fn dispatch_checked(&mut self, event: &Event) -> (Vec<Firing>, Option<EngineError>) {
+        let (mut fired, error) = self.dispatch_unsaved(event);
+        self.save_state(&mut fired);
+        (fired, error)
+    }
+
+    fn dispatch_unsaved(&mut self, event: &Event) -> (Vec<Firing>, Option<EngineError>) {
+        let now = self.clock.now();
+        let mut fired = Vec::new();
+        self.history.record(event, now);
+
+        let budget = Budget::new(&self.limits);
+        let mut evaluation = self.network.evaluation(event).with_budget(&budget);
+        let cx = Context {
+            event,
+            frame: Frame::new(&self.branches.slots, event),
+            history: &self.history,
+            now,
+            budget: &budget,
+        };
+
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            if !budget.check_time() {
+                return (fired, budget.error(&trigger.name));
+            }
+            let mut holds = |store: &dyn StateStore| {
+                evaluation.holds_at(index, store, Some((&self.history, now)))
+            };
+            // Whether the condition holds, if the trigger runs at all.
+            let outcome = if !trigger.listens_for(&event.event_type) {
+                None
+            } else if let Some(pattern) = &trigger.pattern {
+                let completed = holds(self.state.as_ref())
+                    && match &pattern.key {
+                        Some(path) => event.get(path).is_some_and(|key| {
+                            self.patterns[index].feed(
+                                pattern,
+                                Some(key.to_string()),
+                                event,
+                                now,
+                                self.max_pattern_keys,
+                            )
+                        }),
+                        None => self.patterns[index].feed(
+                            pattern,
+                            None,
+                            event,
+                            now,
+                            self.max_pattern_keys,
+                        ),
+                    };
+                completed.then_some(true)
+            } else {
+                Some(holds(self.state.as_ref()))
+            };
+            if budget.overrun().is_some() {
+                return (fired, budget.error(&trigger.name));
+            }
+            let Some(holds) = outcome else {
+                continue;
+            };
+            let firing = run(
+                &mut self.limiter,
+                self.state.as_mut(),
+                &cx,
+                index,
+                trigger,
+                &self.branches.programs[index],
+                holds,
+            );
+            if budget.overrun().is_some() {
+                return (fired, budget.error(&trigger.name));
+            }
+            if let Some(firing) = firing {
+                // Later conditions must see the state this firing changed.
+                if self.writes_state[index] {
+                    evaluation.invalidate();
+                }
+                let stopped = firing.stopped;
+                fired.push(firing);
+                if stopped {
+                    break;
+                }
+            }
+        }
+
+        (fired, None)
+    }
+
+    /// Runs the scheduled triggers that came due since the last tick, or
+    /// since the engine was created, in firing order. A trigger that came
+    /// due several times in between runs once. Conditions are evaluated
+    /// against an event with no fields whose type is the trigger's schedule,
+    /// and stopping propagation has no effect on other scheduled triggers.
+    pub fn tick(&mut self) -> Vec<Firing> {
+        self.tick_checked().0
+    }
+
+    /// Like [`tick`](Engine::tick), with the budget of steps and time of
+    /// one event for the whole tick, as in
+    /// [`dispatch_checked`](Engine::dispatch_checked).
+    pub fn tick_checked(&mut self) -> (Vec<Firing>, Option<EngineError>) {
+        let (mut fired, error) = self.tick_unsaved();
+        self.save_state(&mut fired);
+        (fired, error)
+    }
+
+    fn tick_unsaved(&mut self) -> (Vec<Firing>, Option<EngineError>) {
+        let now = self.clock.now();
+        let mut fired = Vec::new();
+        let budget = Budget::new(&self.limits);
+
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            let (Some(schedule), Some(due)) = (&trigger.schedule, self.next_due[index]) else {
+                continue;
+            };
+            if due > now {
+                continue;
+            }
+            if !budget.check_time() {
+                return (fired, budget.error(&trigger.name));
+            }
+            self.next_due[index] = schedule.next_after(self.started, now);
+
+            let event = Event::new(trigger.event_type.clone());
+            let holds = self
+                .network
+                .evaluation(&event)
+                .with_budget(&budget)
+                .holds_at(index, self.state.as_ref(), Some((&self.history, now)));
+            let cx = Context {
+                event: &event,
+                frame: Frame::new(&self.branches.slots, &event),
+                history: &self.history,
+                now,
+                budget: &budget,
+            };
+            let firing = run(
+                &mut self.limiter,
+                self.state.as_mut(),
+                &cx,
+                index,
+                trigger,
+                &self.branches.programs[index],
+                holds,
+            );
+            if budget.overrun().is_some() {
+                return (fired, budget.error(&trigger.name));
+            }
+            fired.extend(firing);
+        }
+
+        (fired, None)
+    }
+
+    // Saves the state the firings of one event changed, all at once. If that
+    // fails, each of those firings reports it.
+    fn save_state(&mut self, fired: &mut [Firing]) {
+        if !fired.iter().any(|firing| firing.changed_state) {
+            return;
+        }
+        if let Err(e) = self.state.flush() {
+            for firing in fired.iter_mut().filter(|firing| firing.changed_state) {
+                firing.state_errors.push(e.to_string());
+            }
+        }
+    }
+
+    /// When the scheduled trigger `name` is next due, if it is scheduled
+    /// and enabled.
+    pub fn next_due(&self, name: &str) -> Option<SystemTime> {
+        let index = self.triggers.iter().position(|t| t.name == name)?;
+        self.next_due[index]
+    }
+}
+
+// The event being handled.
+struct Context<'a> {
+    event: &'a Event,
+    // The event's fields used by `if` blocks.
+    frame: Frame<'a>,
+    history: &'a History,
+    now: SystemTime,
+    budget: &'a Budget,
+}
+
+// Runs one trigger for an event, given whether its condition holds and the
+// compiled conditions of its `if` blocks, returning the firing if it fires.
+// It does not fire if the budget runs out while choosing the branches of
+// its `if` blocks.
+fn run(
+    limiter: &mut Limiter,
+    store: &mut dyn StateStore,
+    cx: &Context,
+    index: usize,
+    trigger: &Trigger,
+    programs: &[Program],
+    holds: bool,
+) -> Option<Firing> {
+    let Context { event, now, .. } = *cx;
+    if !holds && trigger.else_actions.is_empty() {
+        return None;
+    }
+
+    let mut actions = Vec::new();
+    {
+        let test = |program: &Program| {
+            program.execute(&cx.frame, &*store, Some((cx.history, now)), Some(cx.budget))
+        };
+        let mut programs = programs.iter();
+        select_actions(&trigger.actions, holds, &mut programs, &test, &mut actions);
+        select_actions(
+            &trigger.else_actions,
+            !holds,
+            &mut programs,
+            &test,
+            &mut actions,
+        );
+    }
+    if cx.budget.overrun().is_some() || !limiter.allow(index, trigger, event, now) {
+        return None;
+    }
+    let stopped = actions.iter().any(|action| action.name == STOP_PROPAGATION);
+    actions.retain(|action| action.name != STOP_PROPAGATION);
+
+    let mut state_errors = Vec::new();
+    let mut changed_state = false;
+    actions.retain(|action| {
+        if !state::is_state_action(action) {
+            return true;
+        }
+        match state::apply(action, event, store) {
+            Ok(()) => changed_state = true,
+            Err(e) => state_errors.push(e.to_string()),
+        }
+        false
+    });
+
+    Some(Firing {
+        trigger: trigger.name.clone(),
+        actions,
+        stopped,
+        state_errors,
+        changed_state,
+    })
+}
+
+// Partial matches of one trigger's event pattern, per correlation key.
+#[derive(Default)]
+struct PatternState {
+    keys: HashMap<Option<String>, Vec<Partial>>,
+}
+
+// A match in progress: `count` events of step `step` seen so far.
+#[derive(Clone, Copy)]
+struct Partial {
+    started: SystemTime,
+    step: usize,
+    count: u32,
+}
+
+impl PatternState {
+    // Counts `event` towards the pattern for `key`. Returns whether it
+    // completes a match.
+    fn feed(
+        &mut self,
+        pattern: &EventPattern,
+        key: Option<String>,
+        event: &Event,
+        now: SystemTime,
+        max_keys: usize,
+    ) -> bool {
+        let live = |partial: &Partial| {
+            now.duration_since(partial.started).unwrap_or_default() <= pattern.within
+        };
+        if !self.keys.contains_key(&key) && self.keys.len() >= max_keys {
+            self.keys.retain(|_, partials| partials.iter().any(live));
+            if self.keys.len() >= max_keys {
+                let stalest = self
+                    .keys
+                    .iter()
+                    .min_by_key(|(_, partials)| partials.iter().map(|p| p.started).max())
+                    .map(|(key, _)| key.clone());
+                if let Some(stalest) = stalest {
+                    self.keys.remove(&stalest);
+                }
+            }
+        }
+
+        let partials = self.keys.entry(key.clone()).or_default();
+        partials.retain(live);
+
+        let advance = |partial: &mut Partial| {
+            partial.count += 1;
+            if partial.count == pattern.steps[partial.step].count {
+                partial.step += 1;
+                partial.count = 0;
+            }
+        };
+        for partial in partials.iter_mut() {
+            if pattern.steps[partial.step].event_type == event.event_type {
+                advance(partial);
+            }
+        }
+        if pattern.steps[0].event_type == event.event_type {
+            let mut partial = Partial {
+                started: now,
+                step: 0,
+                count: 0,
+            };
+            advance(&mut partial);
+            partials.push(partial);
+        }
+
+        if partials.iter().any(|p| p.step == pattern.steps.len()) {
+            self.keys.remove(&key);
+            return true;
+        }
+
+        // Of partials with the same progress, the one started last expires
+        // last, so the others can never complete first. This bounds the
+        // partials per key by the number of events in the pattern.
+        partials.sort_by_key(|p| std::cmp::Reverse(p.started));
+        let mut progress = Vec::new();
+        partials.retain(|p| {
+            let seen = progress.contains(&(p.step, p.count));
+            progress.push((p.step, p.count));
+            !seen
+        });
+        if partials.is_empty() {
+            self.keys.remove(&key);
+        }
+        false
+    }
+}
+
+// The calls to run from `actions` if they are `taken`, taking the branches
+// of `if` blocks whose condition passes `test`. `programs` holds the
+// conditions of these blocks and of those after them, in order; blocks not
+// taken are passed over without running theirs.
+fn select_actions(
+    actions: &[Action],
+    taken: bool,
+    programs: &mut std::slice::Iter<'_, Program>,
+    test: &dyn Fn(&Program) -> bool,
+    selected: &mut Vec<FuncCall>,
+) {
+    for action in actions {
+        match action {
+            Action::Call(func) => {
+                if taken {
+                    selected.push(func.clone());
+                }
+            }
+            Action::If {
+                then, otherwise, ..
+            } => {
+                let program = programs.next().expect("compiled condition");
+                let holds = taken && test(program);
+                select_actions(then, holds, programs, test, selected);
+                select_actions(otherwise, taken && !holds, programs, test, selected);
+            }
+        }
+    }
+}
+
+// The conditions of the triggers' `if` blocks, compiled to programs sharing
+// one slot table. Each trigger's programs are in the order its blocks
+// appear, those of its actions before those of its else actions.
+struct Branches {
+    slots: SlotTable,
+    programs: Vec<Vec<Program>>,
+}
+
+impl Branches {
+    fn new(triggers: &[Trigger]) -> Self {
+        struct Compiler<'s> {
+            slots: &'s mut SlotTable,
+            programs: Vec<Program>,
+        }
+
+        impl Visitor for Compiler<'_> {
+            fn visit_action(&mut self, action: &Action) {
+                if let Action::If { condition, .. } = action {
+                    let program = bytecode::compile(condition, self.slots);
+                    self.programs.push(program);
+                }
+                walk_action(self, action);
+            }
+
+            fn visit_expr(&mut self, _: &Expr) {}
+        }
+
+        let mut slots = SlotTable::new();
+        let programs = triggers
+            .iter()
+            .map(|trigger| {
+                let mut compiler = Compiler {
+                    slots: &mut slots,
+                    programs: Vec::new(),
+                };
+                for action in trigger.actions.iter().chain(&trigger.else_actions) {
+                    compiler.visit_action(action);
+                }
+                compiler.programs
+            })
+            .collect();
+        Branches { slots, programs }
+    }
+}
+
+impl Trigger {
+    /// Whether the engine runs this trigger for events of `event_type`:
+    /// those of its own type, or of any step of its pattern. Scheduled
+    /// triggers only run from [`Engine::tick`].
+    pub fn listens_for(&self, event_type: &str) -> bool {
+        match (&self.pattern, &self.schedule) {
+            (Some(pattern), _) => pattern
+                .steps
+                .iter()
+                .any(|step| step.event_type == event_type),
+            (None, Some(_)) => false,
+            (None, None) => self.event_type == event_type,
+        }
+    }
+
+    /// The event types [`listens_for`](Trigger::listens_for) accepts, in
+    /// the order they first appear.
+    pub fn event_types(&self) -> Vec<&str> {
+        match (&self.pattern, &self.schedule) {
+            (Some(pattern), _) => {
+                let mut types = Vec::new();
+                for step in &pattern.steps {
+                    if !types.contains(&step.event_type.as_str()) {
+                        types.push(step.event_type.as_str());
+                    }
+                }
+                types
+            }
+            (None, Some(_)) => Vec::new(),
+            (None, None) => vec![self.event_type.as_str()],
+        }
+    }
+
+    /// Whether firing this trigger may stop the triggers after it, i.e. any
+    /// of its actions, possibly inside a branch, stops propagation.
+    pub fn stops_propagation(&self) -> bool {
+        self.calls()
+            .iter()
+            .any(|action| action.name == STOP_PROPAGATION)
+    }
+}
+
+// Per trigger (by index) and key value, when the trigger last fired and its
+// firings within the current rate limit window. Each entry keeps its
+// cooldown or window, so that entries past it can be dropped.
+struct Limiter {
+    last_fired: HashMap<(usize, Option<String>), (SystemTime, Duration)>,
+    recent: HashMap<(usize, Option<String>), (Duration, VecDeque<SystemTime>)>,
+    // Entries are pruned once there are more than this many.
+    prune_at: usize,
+}
+
+// The least `prune_at` gets, so that few keys are not pruned over and over.
+const MIN_LIMITER_ENTRIES: usize = 1024;
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter {
+            last_fired: HashMap::new(),
+            recent: HashMap::new(),
+            prune_at: MIN_LIMITER_ENTRIES,
+        }
+    }
+}
+
+impl Limiter {
+    fn allow(&mut self, index: usize, trigger: &Trigger, event: &Event, now: SystemTime) -> bool {
+        let key = |field: &Option<String>| {
+            let value = field.as_deref().and_then(|path| event.get(path));
+            (index, value.map(ToString::to_string))
+        };
+        // A clock that went backwards counts as no time having passed.
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+
+        let cooldown = trigger.cooldown.as_ref().map(|c| (c, key(&c.key)));
+        if let Some((cooldown, key)) = &cooldown
+            && let Some((last, _)) = self.last_fired.get(key)
+            && elapsed(*last) < cooldown.period
+        {
+            return false;
+        }
+
+        let rate_limit = trigger.max_fires.as_ref().map(|l| (l, key(&l.key)));
+        if let Some((limit, key)) = &rate_limit
+            && let Some((_, fires)) = self.recent.get_mut(key)
+        {
+            while fires
+                .front()
+                .is_some_and(|fired| elapsed(*fired) >= limit.window)
+            {
+                fires.pop_front();
+            }
+            if fires.len() >= limit.count as usize {
+                return false;
+            }
+        }
+
+        if let Some((cooldown, key)) = cooldown {
+            self.last_fired.insert(key, (now, cooldown.period));
+        }
+        if let Some((limit, key)) = rate_limit {
+            let (_, fires) = self
+                .recent
+                .entry(key)
+                .or_insert_with(|| (limit.window, VecDeque::new()));
+            fires.push_back(now);
+        }
+        self.prune(now);
+        true
+    }
+
+    // Drops the entries that no longer hold a trigger back, once there are
+    // more than `prune_at`. Doubling `prune_at` after each pass keeps the
+    // work per firing constant on average.
+    fn prune(&mut self, now: SystemTime) {
+        if self.last_fired.len() + self.recent.len() <= self.prune_at {
+            return;
+        }
+        let elapsed = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+        self.last_fired
+            .retain(|_, (last, period)| elapsed(*last) < *period);
+        self.recent.retain(|_, (window, fires)| {
+            fires.back().is_some_and(|fired| elapsed(*fired) < *window)
+        });
+        self.prune_at = (2 * (self.last_fired.len() + self.recent.len())).max(MIN_LIMITER_ENTRIES);
+    }
+}
+
+/// Evaluates a condition against an event, without any stored state.
+pub fn evaluate(expr: &Expr, event: &Event) -> bool {
+    evaluate_with_state(expr, event, &MemoryStateStore::new())
+}
+
+/// Evaluates a condition against an event and stored state.
+///
+/// A comparison holds only if its field is present and of a comparable
+/// type; `!=` also holds between values of different types. A bare field
+/// holds if it is `true`. Fields missing from the event are looked up in
+/// `store` as described in [`state`](crate::state). Of function calls, only
+/// `get("<name>")` is supported by the engine; others never hold.
+/// Aggregates need the engine's event history, so comparisons with them
+/// never hold here.
+pub fn evaluate_with_state(expr: &Expr, event: &Event, store: &dyn StateStore) -> bool {
+    Scope { event, store }.eval(expr)
+}
+
+// What a condition is evaluated against.
+struct Scope<'a> {
+    event: &'a Event,
+    store: &'a dyn StateStore,
+}
+
+impl Scope<'_> {
+    // Walks the tree with an explicit stack of what is left to do, so that
+    // deep conditions cannot overflow the thread's stack. `result` holds the
+    // value of the last subexpression finished.
+    fn eval(&self, expr: &Expr) -> bool {
+        enum Task<'e> {
+            Eval(&'e Expr),
+            Not,
+            // The right operand, still to be evaluated unless the left one
+            // decided the result.
+            And(&'e Expr),
+            Or(&'e Expr),
+        }
+
+        let mut result = true;
+        let mut tasks = vec![Task::Eval(expr)];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Eval(Expr::And(left, right)) => {
+                    tasks.extend([Task::And(right), Task::Eval(left)]);
+                }
+                Task::Eval(Expr::Or(left, right)) => {
+                    tasks.extend([Task::Or(right), Task::Eval(left)]);
+                }
+                Task::Eval(Expr::Not(inner)) => tasks.extend([Task::Not, Task::Eval(inner)]),
+                Task::Eval(Expr::Parenthesized(inner)) => tasks.push(Task::Eval(inner)),
+                Task::Eval(leaf) => result = self.test(leaf),
+                Task::Not => result = !result,
+                Task::And(right) if result => tasks.push(Task::Eval(right)),
+                Task::Or(right) if !result => tasks.push(Task::Eval(right)),
+                Task::And(_) | Task::Or(_) => {}
+            }
+        }
+        result
+    }
+
+    // A comparison, call or identifier.
+    fn test(&self, leaf: &Expr) -> bool {
+        match leaf {
+            Expr::Comparison(comp) => match (self.resolve(&comp.left), self.resolve(&comp.right)) {
+                (Some(left), Some(right)) => compare(&left, &comp.operator, &right),
+                _ => false,
+            },
+            Expr::FuncCall(func) => match (func.name.as_str(), func.args.as_slice()) {
+                (state::GET, [Value::String(name)]) => matches!(
+                    self.store.get(&state::state_key(name, self.event)),
+                    Some(Value::Boolean(true))
+                ),
+                _ => false,
+            },
+            Expr::Ident(path) => match path.as_name() {
+                Some("true") => true,
+                Some("false") => false,
+                _ => matches!(self.lookup(path), Some(Value::Boolean(true))),
+            },
+            Expr::And(..) | Expr::Or(..) | Expr::Not(_) | Expr::Parenthesized(_) => {
+                unreachable!("not a leaf")
+            }
+        }
+    }
+
+    // The value a side of a comparison stands for, if known. Aggregates
+    // need the engine's history, so they never are.
+    fn resolve(&self, value: &Value) -> Option<Value> {
+        match value {
+            Value::Ident(path) => self.lookup(path),
+            Value::Aggregate(_) => None,
+            value => Some(value.clone()),
+        }
+    }
+
+    fn lookup(&self, path: &FieldPath) -> Option<Value> {
+        self.event
+            .get_path(path)
+            .cloned()
+            .or_else(|| state::lookup(path, self.event, self.store))
+    }
+}
+
+// Recent events of the types aggregates use, oldest first.
+pub(crate) struct History {
+    recorded: HashMap<String, Recorded>,
+    retention: Duration,
+    max_events: usize,
+    len: usize,
+}
+
+#[derive(Default)]
+struct Recorded {
+    // The fields aggregates read, the fields they are keyed on, and the
+    // longest window over the type.
+    fields: BTreeSet<FieldPath>,
+    keys: BTreeSet<FieldPath>,
+    window: Duration,
+    // Events are numbered in arrival order, `first` being the number of the
+    // front one, so that an event's position is its number minus `first`.
+    first: u64,
+    events: VecDeque<(SystemTime, BTreeMap<FieldPath, Value>)>,
+    // The numbers of the events with each value of each key, oldest first.
+    by_key: HashMap<FieldPath, HashMap<Value, VecDeque<u64>>>,
+}
+
+impl Recorded {
+    fn push(&mut self, at: SystemTime, fields: BTreeMap<FieldPath, Value>) {
+        let number = self.first + self.events.len() as u64;
+        for key in &self.keys {
+            if let Some(value) = fields.get(key) {
+                let numbers = self.by_key.entry(key.clone()).or_default();
+                numbers.entry(value.clone()).or_default().push_back(number);
+            }
+        }
+        self.events.push_back((at, fields));
+    }
+
+    fn pop_front(&mut self) {
+        let Some((_, fields)) = self.events.pop_front() else {
+            return;
+        };
+        for key in &self.keys {
+            let Some(value) = fields.get(key) else {
+                continue;
+            };
+            let Some(values) = self.by_key.get_mut(key) else {
+                continue;
+            };
+            if let Some(numbers) = values.get_mut(value) {
+                numbers.pop_front();
+                if numbers.is_empty() {
+                    values.remove(value);
+                }
+            }
+        }
+        self.first += 1;
+    }
+
+    // The recorded fields of the events with `value` for `key`, or of all
+    // events without a key, newest first.
+    fn newest_first<'a>(
+        &'a self,
+        key: Option<(&FieldPath, &Value)>,
+    ) -> Box<dyn Iterator<Item = &'a (SystemTime, BTreeMap<FieldPath, Value>)> + 'a> {
+        match key {
+            None => Box::new(self.events.iter().rev()),
+            Some((path, value)) => {
+                let numbers = self.by_key.get(path).and_then(|values| values.get(value));
+                Box::new(
+                    numbers
+                        .into_iter()
+                        .flat_map(|numbers| numbers.iter().rev())
+                        .map(|number| &self.events[(number - self.first) as usize]),
+                )
+            }
+        }
+    }
+}
+
+impl History {
+    fn for_triggers(triggers: &[Trigger]) -> Self {
+        struct Aggregates(HashMap<String, Recorded>);
+
+        impl Visitor for Aggregates {
+            fn visit_value(&mut self, value: &Value) {
+                if let Value::Aggregate(aggregate) = value {
+                    let recorded = self.0.entry(aggregate.event_type.clone()).or_default();
+                    recorded.fields.extend(aggregate.field.clone());
+                    recorded.fields.extend(aggregate.key.clone());
+                    recorded.keys.extend(aggregate.key.clone());
+                    recorded.window = recorded.window.max(aggregate.window);
+                }
+                walk_value(self, value);
+            }
+        }
+
+        let mut aggregates = Aggregates(HashMap::new());
+        for trigger in triggers {
+            aggregates.visit_trigger(trigger);
+        }
+        History {
+            recorded: aggregates.0,
+            retention: DEFAULT_HISTORY_RETENTION,
+            max_events: DEFAULT_MAX_HISTORY_EVENTS,
+            len: 0,
+        }
+    }
+
+    fn record(&mut self, event: &Event, now: SystemTime) {
+        let Some(recorded) = self.recorded.get_mut(&event.event_type) else {
+            return;
+        };
+        let keep = recorded.window.min(self.retention);
+        while recorded
+            .events
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at).unwrap_or_default() > keep)
+        {
+            recorded.pop_front();
+            self.len -= 1;
+        }
+
+        let fields = recorded
+            .fields
+            .iter()
+            .filter_map(|field| Some((field.clone(), event.get_path(field)?.clone())))
+            .collect();
+        recorded.push(now, fields);
+        self.len += 1;
+
+        while self.len > self.max_events {
+            let oldest = self
+                .recorded
+                .values_mut()
+                .filter(|recorded| !recorded.events.is_empty())
+                .min_by_key(|recorded| recorded.events[0].0);
+            match oldest {
+                Some(recorded) => {
+                    recorded.pop_front();
+                    self.len -= 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    // The aggregate's value for `event`, or `None` if the event lacks the
+    // key or there is nothing to take the average, minimum or maximum of.
+    // Only the events with the event's key are looked at, each taking a
+    // step of `budget`; once it is spent, the rest are left out.
+    pub(crate) fn aggregate(
+        &self,
+        aggregate: &Aggregate,
+        event: &Event,
+        now: SystemTime,
+        budget: Option<&Budget>,
+    ) -> Option<Value> {
+        let key = match &aggregate.key {
+            Some(path) => Some((path, event.get_path(path)?)),
+            None => None,
+        };
+        let window = aggregate.window.min(self.retention);
+        let matching = self
+            .recorded
+            .get(&aggregate.event_type)
+            .into_iter()
+            .flat_map(|recorded| recorded.newest_first(key))
+            .take_while(|(at, _)| now.duration_since(*at).unwrap_or_default() <= window)
+            .take_while(|_| budget.is_none_or(Budget::step))
+            .map(|(_, fields)| fields);
+
+        if aggregate.function == AggregateFn::Count {
+            return Some(Value::from(matching.count() as i64));
+        }
+        let field = aggregate.field.as_ref()?;
+        let numbers = matching.filter_map(|fields| match fields.get(field)? {
+            Value::Number(n) => n.parse::<f64>().ok(),
+            _ => None,
+        });
+        let result = match aggregate.function {
+            AggregateFn::Count | AggregateFn::Sum => numbers.sum(),
+            AggregateFn::Avg => {
+                let (sum, count) = numbers.fold((0.0, 0), |(sum, n), x| (sum + x, n + 1));
+                (count > 0).then(|| sum / f64::from(count))?
+            }
+            AggregateFn::Min => numbers.reduce(f64::min)?,
+            AggregateFn::Max => numbers.reduce(f64::max)?,
+        };
+        Value::try_from(result).ok()
+    }
+}
+
+pub(crate) fn compare(left: &Value, op: &ComparisonOp, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Number(a), Value::Number(b)) => match (a.parse::<f64>(), b.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b),
+            _ => None,
+        },
+        (Value::Boolean(a), Value::Boolean(b)) => Some(a.cmp(b)),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Ident(a), Value::Ident(b)) => Some(a.cmp(b)),
+        (Value::String(a), Value::Ident(b)) => Some(a.as_str().cmp(&b.to_string())),
+        (Value::Ident(a), Value::String(b)) => Some(a.to_string().as_str().cmp(b)),
+        _ => None,
+    };
+
+    match (op, ordering) {
+        (ComparisonOp::Neq, None) => true,
+        (_, None) => false,
+        (ComparisonOp::Eq, Some(o)) => o == Ordering::Equal,
+        (ComparisonOp::Neq, Some(o)) => o != Ordering::Equal,
+        (ComparisonOp::Gt, Some(o)) => o == Ordering::Greater,
+        (ComparisonOp::Lt, Some(o)) => o == Ordering::Less,
+        (ComparisonOp::Gte, Some(o)) => o != Ordering::Less,
+        (ComparisonOp::Lte, Some(o)) => o != Ordering::Greater,
+    }
+}
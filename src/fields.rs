@@ -0,0 +1,145 @@
+//! Trigger fields registered by the host application.
+//!
+//! Besides the built-in fields such as `on`, `condition` and `action`, a
+//! trigger may set any field registered with [`ParseOptions::register_field`]. Each
+//! registered field has a [`FieldType`] its value is checked against; the
+//! parsed values end up in [`Trigger::fields`].
+//!
+//! [`ParseOptions::register_field`]: crate::ParseOptions::register_field
+//! [`Trigger::fields`]: crate::Trigger::fields
+
+use crate::{Expr, FuncCall, Value};
+
+/// Field names handled by the parser itself. They cannot be registered.
+pub const BUILTIN_FIELDS: &[&str] = &[
+    "on",
+    "description",
+    "condition",
+    "action",
+    "else_action",
+    "cooldown",
+    "max_fires",
+    "priority",
+    "enabled",
+    "tags",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Number,
+    Boolean,
+    /// A bare identifier or field path, e.g. `player.id`.
+    Ident,
+    /// A condition, parsed like `condition:`. Named conditions are expanded.
+    Expr,
+    FuncCall,
+    /// `[a, b, c]`, each element of the given type.
+    List(Box<FieldType>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldSpec {
+    pub ty: FieldType,
+    /// Triggers without the field are rejected.
+    pub required: bool,
+    /// The field may be given several times, like `action:`.
+    pub repeated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FieldValue {
+    Value(Value),
+    Expr(Expr),
+    FuncCall(FuncCall),
+    List(Vec<FieldValue>),
+}
+
+impl FieldSpec {
+    /// An optional field that may be given at most once.
+    pub fn new(ty: FieldType) -> Self {
+        FieldSpec {
+            ty,
+            required: false,
+            repeated: false,
+        }
+    }
+
+    pub fn required(self) -> Self {
+        FieldSpec {
+            required: true,
+            ..self
+        }
+    }
+
+    pub fn repeated(self) -> Self {
+        FieldSpec {
+            repeated: true,
+            ..self
+        }
+    }
+}
+
+impl FieldType {
+    // Checks a value as written in the source against the type. Identifiers
+    // and booleans are accepted where a condition is expected.
+    pub(crate) fn accept(&self, raw: FieldValue) -> Option<FieldValue> {
+        match (self, raw) {
+            (FieldType::String, raw @ FieldValue::Value(Value::String(_)))
+            | (FieldType::Number, raw @ FieldValue::Value(Value::Number(_)))
+            | (FieldType::Boolean, raw @ FieldValue::Value(Value::Boolean(_)))
+            | (FieldType::Ident, raw @ FieldValue::Value(Value::Ident(_)))
+            | (FieldType::FuncCall, raw @ FieldValue::FuncCall(_))
+            | (FieldType::Expr, raw @ FieldValue::Expr(_)) => Some(raw),
+            (FieldType::Expr, FieldValue::Value(Value::Ident(id))) => {
+                Some(FieldValue::Expr(Expr::Ident(id)))
+            }
+            (FieldType::Expr, FieldValue::Value(Value::Boolean(b))) => {
+                Some(FieldValue::Expr(Expr::Ident(b.to_string().into())))
+            }
+            (FieldType::Expr, FieldValue::FuncCall(func)) => {
+                Some(FieldValue::Expr(Expr::FuncCall(func)))
+            }
+            (FieldType::List(item), FieldValue::List(items)) => items
+                .into_iter()
+                .map(|raw| item.accept(raw))
+                .collect::<Option<_>>()
+                .map(FieldValue::List),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldType::String => write!(f, "a string"),
+            FieldType::Number => write!(f, "a number"),
+            FieldType::Boolean => write!(f, "a boolean"),
+            FieldType::Ident => write!(f, "an identifier"),
+            FieldType::Expr => write!(f, "a condition"),
+            FieldType::FuncCall => write!(f, "a function call"),
+            FieldType::List(item) => write!(f, "a list of {}", item),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Value(value) => write!(f, "{}", value),
+            FieldValue::Expr(expr) => write!(f, "{}", expr),
+            FieldValue::FuncCall(func) => write!(f, "{}", func),
+            FieldValue::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::fields::FieldValue;
+use crate::{Action, Comparison, Expr, FieldPath, FuncCall, Trigger, Value, normalize_number};
+
+// FNV-1a, chosen over `DefaultHasher` because its output is specified and
+// therefore stable across processes and compiler versions.
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(FNV_OFFSET)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn tag(&mut self, tag: u8) {
+        self.bytes(&[tag]);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(&(s.len() as u64).to_le_bytes());
+        self.bytes(s.as_bytes());
+    }
+
+    // Hashed like its text, without joining the segments.
+    fn path(&mut self, path: &FieldPath) {
+        self.bytes(&(path.text_len() as u64).to_le_bytes());
+        for (i, segment) in path.segments().iter().enumerate() {
+            if i > 0 {
+                self.bytes(b".");
+            }
+            self.bytes(segment.as_str().as_bytes());
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::And(left, right) => {
+                self.tag(b'&');
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Or(left, right) => {
+                self.tag(b'|');
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Not(inner) => {
+                self.tag(b'!');
+                self.expr(inner);
+            }
+            Expr::Comparison(comp) => self.comparison(comp),
+            Expr::FuncCall(func) => self.func_call(func),
+            Expr::Ident(id) => {
+                self.tag(b'i');
+                self.path(id);
+            }
+            // Parentheses only affect how the source was written.
+            Expr::Parenthesized(inner) => self.expr(inner),
+        }
+    }
+
+    fn comparison(&mut self, comp: &Comparison) {
+        self.tag(b'c');
+        match &comp.left {
+            Value::Ident(path) => self.path(path),
+            left => self.value(left),
+        }
+        self.str(&comp.operator.to_string());
+        self.value(&comp.right);
+    }
+
+    fn func_call(&mut self, func: &FuncCall) {
+        self.tag(b'f');
+        self.str(&func.name);
+        self.bytes(&(func.args.len() as u64).to_le_bytes());
+        for arg in &func.args {
+            self.value(arg);
+        }
+    }
+
+    // Calls hash exactly like before actions could branch, so existing
+    // triggers keep their hash.
+    fn action(&mut self, action: &Action) {
+        match action {
+            Action::Call(func) => self.func_call(func),
+            Action::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                self.tag(b'?');
+                self.expr(condition);
+                for branch in [then, otherwise] {
+                    self.bytes(&(branch.len() as u64).to_le_bytes());
+                    for action in branch {
+                        self.action(action);
+                    }
+                }
+            }
+        }
+    }
+
+    fn field_value(&mut self, value: &FieldValue) {
+        match value {
+            FieldValue::Value(value) => self.value(value),
+            FieldValue::Expr(expr) => {
+                self.tag(b'e');
+                self.expr(expr);
+            }
+            FieldValue::FuncCall(func) => self.func_call(func),
+            FieldValue::List(items) => {
+                self.tag(b'l');
+                self.bytes(&(items.len() as u64).to_le_bytes());
+                for item in items {
+                    self.field_value(item);
+                }
+            }
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Boolean(b) => {
+                self.tag(b'b');
+                self.tag(u8::from(*b));
+            }
+            Value::Number(n) => {
+                self.tag(b'n');
+                self.str(&normalize_number(n));
+            }
+            Value::String(s) => {
+                self.tag(b's');
+                self.str(s);
+            }
+            Value::Ident(id) => {
+                self.tag(b'i');
+                self.path(id);
+            }
+            Value::Aggregate(aggregate) => {
+                self.tag(b'a');
+                self.str(&aggregate.function.to_string());
+                self.str(&aggregate.event_type);
+                self.path(aggregate.field.as_ref().unwrap_or(&FieldPath::default()));
+                self.path(aggregate.key.as_ref().unwrap_or(&FieldPath::default()));
+                self.bytes(&aggregate.window.as_millis().to_le_bytes());
+            }
+        }
+    }
+}
+
+impl Trigger {
+    /// Hash of the trigger's meaning, stable across runs. Whitespace,
+    /// redundant parentheses and number formatting (`1` vs `1.0`) do not
+    /// affect it, so it can be used to detect changed triggers on reload.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.str(&self.name);
+        hasher.str(&self.event_type);
+        hasher.str(&self.description);
+        match &self.condition {
+            Some(cond) => {
+                hasher.tag(1);
+                hasher.expr(cond);
+            }
+            None => hasher.tag(0),
+        }
+        hasher.bytes(&(self.actions.len() as u64).to_le_bytes());
+        for action in &self.actions {
+            hasher.action(action);
+        }
+        if !self.else_actions.is_empty() {
+            hasher.str("else_action");
+            hasher.bytes(&(self.else_actions.len() as u64).to_le_bytes());
+            for action in &self.else_actions {
+                hasher.action(action);
+            }
+        }
+        // Optional fields only contribute when set, so adding new ones does
+        // not change the hash of existing triggers.
+        if let Some(cooldown) = &self.cooldown {
+            hasher.str("cooldown");
+            hasher.str(&cooldown.to_string());
+        }
+        if let Some(max_fires) = &self.max_fires {
+            hasher.str("max_fires");
+            hasher.str(&max_fires.to_string());
+        }
+        if self.priority != 0 {
+            hasher.str("priority");
+            hasher.str(&self.priority.to_string());
+        }
+        if !self.enabled {
+            hasher.str("enabled");
+        }
+        if !self.tags.is_empty() {
+            hasher.str("tags");
+            hasher.bytes(&(self.tags.len() as u64).to_le_bytes());
+            for tag in &self.tags {
+                hasher.str(tag);
+            }
+        }
+        for (name, values) in &self.fields {
+            hasher.str(name);
+            hasher.bytes(&(values.len() as u64).to_le_bytes());
+            for value in values {
+                hasher.field_value(value);
+            }
+        }
+        hasher.0
+    }
+}
@@ -1,51 +1,355 @@
 use pest::Parser;
-use pest::iterators::Pair;
+use pest::iterators::{Pair, Pairs};
 use pest_derive::Parser;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::fields::{BUILTIN_FIELDS, FieldSpec, FieldType, FieldValue};
+pub use crate::limits::Limits;
+pub use crate::path::FieldPath;
+use crate::path::Symbol;
+use crate::schedule::{Cron, Schedule};
+
+pub mod analysis;
+pub mod borrowed;
+pub mod bytecode;
+pub mod engine;
+pub mod fields;
+pub mod hash;
+pub mod limits;
+pub mod lint;
+pub mod network;
+pub mod path;
+pub mod payload;
+pub mod project;
+pub mod schedule;
+pub mod simplify;
+pub mod state;
+mod template;
+pub mod visit;
+
 #[derive(Error, Debug)]
 pub enum TriggerParserError {
     #[error("Failed to parse input: {0}")]
     PestError(String),
+    #[error("Invalid config: {0}")]
+    ConfigError(String),
+    #[error("Duplicate trigger '{name}' at {second}, first defined at {first}")]
+    DuplicateTrigger {
+        name: String,
+        first: Box<Location>,
+        second: Box<Location>,
+    },
+    #[error("Failed to read {}: {message}", path.display())]
+    IoError { path: PathBuf, message: String },
+    #[error("{location}: cannot import '{}': {message}", path.display())]
+    ImportError {
+        location: Location,
+        path: PathBuf,
+        message: String,
+    },
+    #[error("{location}: import cycle: {}", format_chain(chain))]
+    ImportCycle {
+        location: Location,
+        chain: Vec<PathBuf>,
+    },
+    #[error("Undefined condition '{name}' at {span}")]
+    UndefinedCondition { name: String, span: Span },
+    #[error("Recursive condition at {span}: {}", chain.join(" -> "))]
+    RecursiveCondition { chain: Vec<String>, span: Span },
+    #[error("Nesting deeper than {limit} levels at {span}")]
+    NestingTooDeep { limit: usize, span: Span },
+    #[error("File of {size} bytes is larger than the limit of {limit}")]
+    FileTooLarge { size: usize, limit: usize },
+    #[error("More than {limit} triggers, at {span}")]
+    TooManyTriggers { limit: usize, span: Span },
+    #[error("Trigger '{trigger}' at {span} has more than {limit} actions")]
+    TooManyActions {
+        trigger: String,
+        limit: usize,
+        span: Span,
+    },
+    #[error("String at {span} is longer than {limit} bytes")]
+    StringTooLong { limit: usize, span: Span },
+    #[error("More than {limit} conditions and actions, at {span}")]
+    TooManyNodes { limit: usize, span: Span },
+    #[error("Name at {span} takes interned names past {limit} bytes")]
+    TooManyNames { limit: usize, span: Span },
+    #[error("Duplicate condition '{name}' at {second}, first defined at {first}")]
+    DuplicateCondition {
+        name: String,
+        first: Box<Location>,
+        second: Box<Location>,
+    },
+    #[error("Undefined template '{name}' at {span}")]
+    UndefinedTemplate { name: String, span: Span },
+    #[error("{span}: {message} (template defined at {template})")]
+    TemplateError {
+        message: String,
+        span: Span,
+        template: Box<Location>,
+    },
+    #[error("Duplicate template '{name}' at {second}, first defined at {first}")]
+    DuplicateTemplate {
+        name: String,
+        first: Box<Location>,
+        second: Box<Location>,
+    },
+    #[error("Trigger '{trigger}' at {span} is missing required field '{field}'")]
+    MissingField {
+        field: String,
+        trigger: String,
+        span: Span,
+    },
+    #[error("Duplicate field '{field}' at {second}, first set at {first}")]
+    DuplicateField {
+        field: String,
+        first: Span,
+        second: Span,
+    },
+    #[error("Unknown field '{field}' at {span}")]
+    UnknownField { field: String, span: Span },
+    #[error("'{field}' is a built-in field and cannot be registered")]
+    ReservedField { field: String },
+    #[error("Invalid value for field '{field}' at {span}: expected {expected}")]
+    InvalidField {
+        field: String,
+        expected: String,
+        span: Span,
+    },
+    #[error("Invalid {aggregate}() at {span}: {message}")]
+    InvalidAggregate {
+        aggregate: String,
+        message: String,
+        span: Span,
+    },
+    #[error("{}: {source}", path.display())]
+    InFile {
+        path: PathBuf,
+        source: Box<TriggerParserError>,
+    },
+}
+
+fn format_chain(chain: &[PathBuf]) -> String {
+    chain
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
 }
 
 #[derive(Parser)]
 #[grammar = "./grammar.pest"]
 pub struct TriggerParser;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct TriggerFile {
-    pub triggers: Vec<Trigger>,
+    triggers: Vec<Trigger>,
+    // Positions in `triggers` by name, in file order. Kept in step with
+    // `triggers`, which is why that is only changed through methods.
+    by_name: BTreeMap<String, Vec<usize>>,
+    /// `import "path";` directives, unresolved. See [`project::load_project`].
+    pub imports: Vec<Import>,
+    /// Top-level `condition Name = ...;` definitions. References to them are
+    /// already expanded inside trigger conditions.
+    pub conditions: Vec<ConditionDef>,
+    /// Top-level templates. `trigger Name = Template(...);` instantiations
+    /// are already expanded into `triggers`.
+    pub templates: Vec<TemplateDef>,
+}
+
+/// `template Name(params) { ... };`. The body is parsed like a trigger
+/// named after the template, with parameters left as identifiers.
+#[derive(Debug, Clone)]
+pub struct TemplateDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Trigger,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConditionDef {
+    pub name: String,
+    /// The definition with its own references expanded.
+    pub condition: Expr,
+    pub span: Span,
+    pub origin: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub path: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Accept several triggers with the same name instead of failing with
+    /// [`TriggerParserError::DuplicateTrigger`].
+    pub allow_duplicates: bool,
+    // Extra trigger fields, keyed by name, added with `register_field`.
+    fields: BTreeMap<String, FieldSpec>,
+    /// Caps on the size of the file and of its AST. See [`limits`].
+    pub limits: Limits,
+}
+
+impl ParseOptions {
+    /// Allows triggers to set the field `name`. See [`fields`]. Built-in
+    /// field names are reserved and fail with
+    /// [`TriggerParserError::ReservedField`].
+    pub fn register_field(
+        &mut self,
+        name: impl Into<String>,
+        spec: FieldSpec,
+    ) -> Result<&mut Self, TriggerParserError> {
+        let name = name.into();
+        if BUILTIN_FIELDS.contains(&name.as_str()) {
+            return Err(TriggerParserError::ReservedField { field: name });
+        }
+        self.fields.insert(name, spec);
+        Ok(self)
+    }
+}
+
+/// Selects triggers by their `enabled` flag and tags.
+#[derive(Debug, Clone, Default)]
+pub struct TriggerFilter {
+    /// If not empty, only triggers with at least one of these tags match.
+    pub tags: Vec<String>,
+    /// Triggers with any of these tags do not match.
+    pub exclude_tags: Vec<String>,
+    /// Let disabled triggers match too.
+    pub include_disabled: bool,
+}
+
+impl TriggerFilter {
+    pub fn matches(&self, trigger: &Trigger) -> bool {
+        let has = |tags: &[String]| trigger.tags.iter().any(|tag| tags.contains(tag));
+        (trigger.enabled || self.include_disabled)
+            && (self.tags.is_empty() || has(&self.tags))
+            && !has(&self.exclude_tags)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Trigger {
     pub name: String,
+    /// The event the trigger reacts to. For scheduled triggers, the
+    /// schedule as written in canonical form, e.g. `every 10m`.
     pub event_type: String,
+    /// Set for `on: every ...` and `on: schedule(...)` triggers, which the
+    /// engine runs from [`Engine::tick`](engine::Engine::tick) instead of
+    /// for events.
+    pub schedule: Option<Schedule>,
+    /// Set for `on: <pattern> within <duration>` triggers, which fire when a
+    /// sequence of events completes instead of for single events.
+    pub pattern: Option<EventPattern>,
     pub description: String,
     pub condition: Option<Expr>,
-    pub actions: Vec<FuncCall>,
+    /// `action:` fields and `if` blocks, in source order.
+    pub actions: Vec<Action>,
+    /// `else_action:` fields, run instead of `actions` when the condition
+    /// does not hold.
+    pub else_actions: Vec<Action>,
+    /// `cooldown: 30s [by field]`: minimum time between two firings.
+    pub cooldown: Option<Cooldown>,
+    /// `max_fires: 3 per 1m [by field]`: at most `count` firings per window.
+    pub max_fires: Option<RateLimit>,
+    /// `priority: n`. When several triggers match an event, higher
+    /// priorities run first. Defaults to 0.
+    pub priority: i32,
+    /// `enabled: false` keeps the trigger in the file but out of the engine.
+    pub enabled: bool,
+    /// `tags: ["pvp", "holiday"]`, for selecting triggers with a
+    /// [`TriggerFilter`].
+    pub tags: Vec<String>,
+    /// Values of registered extra fields, in source order per field.
+    pub fields: BTreeMap<String, Vec<FieldValue>>,
+    pub span: Span,
+    /// The file the trigger was loaded from, when loaded from disk.
+    pub origin: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Cooldown {
+    pub period: Duration,
+    /// Event field the cooldown is tracked per, e.g. `player.id`. Without
+    /// one the cooldown applies to the trigger as a whole.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RateLimit {
+    pub count: u32,
+    pub window: Duration,
+    /// Event field the limit is tracked per, like [`Cooldown::key`].
+    pub key: Option<String>,
+}
+
+/// `player_join then 5 message_receive within 10s by player.id`: events of
+/// each step's type, in order, all within `within` of the first one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EventPattern {
+    pub steps: Vec<PatternStep>,
+    pub within: Duration,
+    /// Event field correlating the events, e.g. `player.id`. Without one,
+    /// any events of the right types make up a match.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PatternStep {
+    pub event_type: String,
+    /// How many events of the type the step needs.
+    pub count: u32,
+}
+
+/// Location of a node in the source. Not part of a node's identity: AST
+/// equality, ordering and hashing ignore it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A span together with the file it belongs to, when known.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub file: Option<PathBuf>,
+    pub span: Span,
+}
+
+/// A condition. Parsed conditions are at most [`Limits::max_depth`] deep.
+/// Cloning, comparing, hashing, dropping and most passes over an `Expr`
+/// recurse, so trees built by hand must respect the same bound.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Expr {
     And(Box<Expr>, Box<Expr>),
     Or(Box<Expr>, Box<Expr>),
     Not(Box<Expr>),
     Comparison(Comparison),
     FuncCall(FuncCall),
-    Ident(String),
+    Ident(FieldPath),
     Parenthesized(Box<Expr>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Comparison {
-    pub left: String,
+    /// A field path ([`Value::Ident`]) or an aggregate.
+    pub left: Value,
     pub operator: ComparisonOp,
     pub right: Value,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ComparisonOp {
     Eq,
     Neq,
@@ -55,18 +359,323 @@ pub enum ComparisonOp {
     Lte,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct FuncCall {
     pub name: String,
     pub args: Vec<Value>,
 }
 
+/// A step of a trigger's actions: a call, or a branch on a condition.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Action {
+    Call(FuncCall),
+    /// `if cond { ... } else { ... }`. `else if` is an `If` as the only
+    /// action of `otherwise`.
+    If {
+        condition: Expr,
+        then: Vec<Action>,
+        otherwise: Vec<Action>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Boolean(bool),
     Number(String),
     String(String),
-    Ident(String),
+    Ident(FieldPath),
+    Aggregate(Box<Aggregate>),
+}
+
+/// A value computed over recent events, e.g.
+/// `count(message_receive, player.id, 1m)`: how many `message_receive`
+/// events with the current event's `player.id` arrived within a minute.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Aggregate {
+    pub function: AggregateFn,
+    pub event_type: String,
+    /// The field of those events that `sum`, `avg`, `min` and `max` use.
+    pub field: Option<FieldPath>,
+    /// Only events whose value of this field equals the current event's.
+    pub key: Option<FieldPath>,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AggregateFn {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Span {
+    fn from_pair(pair: &Pair<Rule>) -> Self {
+        let (line, column) = pair.line_col();
+        Span {
+            start: pair.as_span().start(),
+            end: pair.as_span().end(),
+            line,
+            column,
+        }
+    }
+}
+
+impl TriggerFile {
+    pub(crate) fn new(
+        triggers: Vec<Trigger>,
+        imports: Vec<Import>,
+        conditions: Vec<ConditionDef>,
+        templates: Vec<TemplateDef>,
+    ) -> Self {
+        let mut file = TriggerFile {
+            triggers,
+            by_name: BTreeMap::new(),
+            imports,
+            conditions,
+            templates,
+        };
+        file.reindex();
+        file
+    }
+
+    // Rebuilds `by_name` after `triggers` changed in place.
+    pub(crate) fn reindex(&mut self) {
+        self.by_name.clear();
+        for (index, trigger) in self.triggers.iter().enumerate() {
+            self.by_name
+                .entry(trigger.name.clone())
+                .or_default()
+                .push(index);
+        }
+    }
+
+    /// The triggers in file order.
+    pub fn triggers(&self) -> &[Trigger] {
+        &self.triggers
+    }
+
+    pub fn into_triggers(self) -> Vec<Trigger> {
+        self.triggers
+    }
+
+    /// The trigger called `name`. If duplicates were allowed, the first one
+    /// in file order.
+    pub fn get(&self, name: &str) -> Option<&Trigger> {
+        let index = *self.by_name.get(name)?.first()?;
+        Some(&self.triggers[index])
+    }
+
+    /// Appends `trigger`, unless the file already has one of that name.
+    pub fn push(&mut self, trigger: Trigger) -> Result<(), TriggerParserError> {
+        if let Some(first) = self.get(&trigger.name) {
+            return Err(TriggerParserError::DuplicateTrigger {
+                name: trigger.name.clone(),
+                first: Box::new(first.location()),
+                second: Box::new(trigger.location()),
+            });
+        }
+        self.by_name
+            .insert(trigger.name.clone(), vec![self.triggers.len()]);
+        self.triggers.push(trigger);
+        Ok(())
+    }
+
+    /// Removes the triggers called `name` and returns the first of them.
+    pub fn remove(&mut self, name: &str) -> Option<Trigger> {
+        let indices = self.by_name.remove(name)?;
+        let mut removed = Vec::with_capacity(indices.len());
+        for index in indices.into_iter().rev() {
+            removed.push(self.triggers.remove(index));
+        }
+        self.reindex();
+        removed.pop()
+    }
+
+    /// The file with only the triggers matching `filter`.
+    pub fn filtered(self, filter: &TriggerFilter) -> TriggerFile {
+        let triggers = self
+            .triggers
+            .into_iter()
+            .filter(|trigger| filter.matches(trigger))
+            .collect();
+        TriggerFile::new(triggers, self.imports, self.conditions, self.templates)
+    }
+
+    /// For every tag, the triggers carrying it, in file order.
+    pub fn tag_usage(&self) -> BTreeMap<&str, Vec<&Trigger>> {
+        let mut usage: BTreeMap<&str, Vec<&Trigger>> = BTreeMap::new();
+        for trigger in &self.triggers {
+            for tag in &trigger.tags {
+                let triggers = usage.entry(tag).or_default();
+                if !triggers.iter().any(|t| std::ptr::eq(*t, trigger)) {
+                    triggers.push(trigger);
+                }
+            }
+        }
+        usage
+    }
+
+    /// Triggers keyed by name, sorted by name. Duplicates, if allowed, keep
+    /// their file order.
+    pub fn iter_by_name(&self) -> impl Iterator<Item = (&str, &Trigger)> {
+        self.by_name.iter().flat_map(|(name, indices)| {
+            indices
+                .iter()
+                .map(|&index| (name.as_str(), &self.triggers[index]))
+        })
+    }
+}
+
+impl Trigger {
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.origin.clone(),
+            span: self.span,
+        }
+    }
+
+    /// Every call in the trigger's actions and else actions, including
+    /// those inside `if` blocks, in source order.
+    pub fn calls(&self) -> Vec<&FuncCall> {
+        fn collect<'a>(actions: &'a [Action], calls: &mut Vec<&'a FuncCall>) {
+            for action in actions {
+                match action {
+                    Action::Call(func) => calls.push(func),
+                    Action::If {
+                        then, otherwise, ..
+                    } => {
+                        collect(then, calls);
+                        collect(otherwise, calls);
+                    }
+                }
+            }
+        }
+
+        let mut calls = Vec::new();
+        collect(&self.actions, &mut calls);
+        collect(&self.else_actions, &mut calls);
+        calls
+    }
+
+    /// The first value of the extra field `name`, if set.
+    pub fn field(&self, name: &str) -> Option<&FieldValue> {
+        self.fields.get(name).and_then(|values| values.first())
+    }
+
+    fn key(&self) -> TriggerKey<'_> {
+        (
+            &self.name,
+            &self.event_type,
+            &self.description,
+            &self.condition,
+            &self.actions,
+            &self.else_actions,
+            &self.cooldown,
+            &self.max_fires,
+            self.priority,
+            self.enabled,
+            &self.tags,
+            &self.fields,
+        )
+    }
+}
+
+type TriggerKey<'a> = (
+    &'a str,
+    &'a str,
+    &'a str,
+    &'a Option<Expr>,
+    &'a [Action],
+    &'a [Action],
+    &'a Option<Cooldown>,
+    &'a Option<RateLimit>,
+    i32,
+    bool,
+    &'a [String],
+    &'a BTreeMap<String, Vec<FieldValue>>,
+);
+
+impl ConditionDef {
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.origin.clone(),
+            span: self.span,
+        }
+    }
+
+    fn key(&self) -> (&str, &Expr) {
+        (&self.name, &self.condition)
+    }
+}
+
+impl Import {
+    fn key(&self) -> &str {
+        &self.path
+    }
+}
+
+impl TemplateDef {
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.body.origin.clone(),
+            span: self.body.span,
+        }
+    }
+
+    fn key(&self) -> (&str, &[String], &Trigger) {
+        (&self.name, &self.params, &self.body)
+    }
+}
+
+// Nodes carrying a span compare, order and hash by `key()`, which leaves the
+// span (and origin file) out.
+macro_rules! identity_by_key {
+    ($($ty:ty),*) => {$(
+        impl PartialEq for $ty {
+            fn eq(&self, other: &Self) -> bool {
+                self.key() == other.key()
+            }
+        }
+
+        impl Eq for $ty {}
+
+        impl PartialOrd for $ty {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for $ty {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.key().cmp(&other.key())
+            }
+        }
+
+        impl Hash for $ty {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.key().hash(state);
+            }
+        }
+    )*};
+}
+
+identity_by_key!(Trigger, ConditionDef, Import, TemplateDef);
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}", self.span)
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
 }
 
 impl std::fmt::Display for TriggerFile {
@@ -92,20 +701,178 @@ impl std::fmt::Display for Trigger {
             writeln!(f, " {},", action)?;
         }
         writeln!(f, " ]")?;
+        if !self.else_actions.is_empty() {
+            writeln!(f, "- else actions: [")?;
+            for action in &self.else_actions {
+                writeln!(f, " {},", action)?;
+            }
+            writeln!(f, " ]")?;
+        }
+        if let Some(cooldown) = &self.cooldown {
+            writeln!(f, "- cooldown: {}", cooldown)?;
+        }
+        if let Some(max_fires) = &self.max_fires {
+            writeln!(f, "- max_fires: {}", max_fires)?;
+        }
+        if self.priority != 0 {
+            writeln!(f, "- priority: {}", self.priority)?;
+        }
+        if !self.enabled {
+            writeln!(f, "- enabled: false")?;
+        }
+        if !self.tags.is_empty() {
+            writeln!(f, "- tags: {:?}", self.tags)?;
+        }
+        for (name, values) in &self.fields {
+            for value in values {
+                writeln!(f, "- {}: {}", name, value)?;
+            }
+        }
         write!(f, " }}")
     }
 }
 
+impl std::fmt::Display for Cooldown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", format_duration(self.period))?;
+        if let Some(key) = &self.key {
+            write!(f, " by {}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} per {}", self.count, format_duration(self.window))?;
+        if let Some(key) = &self.key {
+            write!(f, " by {}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for EventPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, " then ")?;
+            }
+            if step.count != 1 {
+                write!(f, "{} ", step.count)?;
+            }
+            write!(f, "{}", step.event_type)?;
+        }
+        write!(f, " within {}", format_duration(self.within))?;
+        if let Some(key) = &self.key {
+            write!(f, " by {}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({}", self.function, self.event_type)?;
+        if let Some(field) = &self.field {
+            write!(f, ".{}", field)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, ", {}", key)?;
+        }
+        write!(f, ", {})", format_duration(self.window))
+    }
+}
+
+impl std::fmt::Display for AggregateFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AggregateFn::Count => "count",
+            AggregateFn::Sum => "sum",
+            AggregateFn::Avg => "avg",
+            AggregateFn::Min => "min",
+            AggregateFn::Max => "max",
+        })
+    }
+}
+
+// Written with an explicit stack rather than recursion, so that printing
+// does not overflow on deep expressions.
 impl std::fmt::Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Expr::And(left, right) => write!(f, "({} AND {})", left, right),
-            Expr::Or(left, right) => write!(f, "({} OR {})", left, right),
-            Expr::Not(expr) => write!(f, "(NOT {})", expr),
-            Expr::Comparison(comp) => write!(f, "{}", comp),
-            Expr::FuncCall(func) => write!(f, "{}", func),
-            Expr::Ident(id) => write!(f, "{}", id),
-            Expr::Parenthesized(expr) => write!(f, "({})", expr),
+        enum Piece<'a> {
+            Expr(&'a Expr),
+            Text(&'static str),
+        }
+
+        let mut pieces = vec![Piece::Expr(self)];
+        while let Some(piece) = pieces.pop() {
+            let expr = match piece {
+                Piece::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Piece::Expr(expr) => expr,
+            };
+            match expr {
+                Expr::And(left, right) | Expr::Or(left, right) => {
+                    let op = if matches!(expr, Expr::And(..)) {
+                        " AND "
+                    } else {
+                        " OR "
+                    };
+                    f.write_str("(")?;
+                    pieces.extend([
+                        Piece::Text(")"),
+                        Piece::Expr(right),
+                        Piece::Text(op),
+                        Piece::Expr(left),
+                    ]);
+                }
+                Expr::Not(inner) => {
+                    f.write_str("(NOT ")?;
+                    pieces.extend([Piece::Text(")"), Piece::Expr(inner)]);
+                }
+                Expr::Parenthesized(inner) => {
+                    f.write_str("(")?;
+                    pieces.extend([Piece::Text(")"), Piece::Expr(inner)]);
+                }
+                Expr::Comparison(comp) => write!(f, "{}", comp)?,
+                Expr::FuncCall(func) => write!(f, "{}", func)?,
+                Expr::Ident(id) => write!(f, "{}", id)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Expr {
+    /// How many levels of `&&`, `||`, `!` and parentheses lie above the
+    /// deepest comparison, identifier or call; 0 for those alone.
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut stack = vec![(self, 0)];
+        while let Some((expr, depth)) = stack.pop() {
+            deepest = deepest.max(depth);
+            match expr {
+                Expr::And(left, right) | Expr::Or(left, right) => {
+                    stack.push((left, depth + 1));
+                    stack.push((right, depth + 1));
+                }
+                Expr::Not(inner) | Expr::Parenthesized(inner) => stack.push((inner, depth + 1)),
+                Expr::Comparison(_) | Expr::FuncCall(_) | Expr::Ident(_) => {}
+            }
+        }
+        deepest
+    }
+}
+
+impl Comparison {
+    /// The field compared, unless the left side is an aggregate.
+    pub fn field(&self) -> Option<&FieldPath> {
+        match &self.left {
+            Value::Ident(path) => Some(path),
+            _ => None,
         }
     }
 }
@@ -129,6 +896,42 @@ impl std::fmt::Display for ComparisonOp {
     }
 }
 
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Call(func) => write!(f, "{}", func),
+            Action::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                write!(f, "if {} {{", condition)?;
+                for action in then {
+                    write!(f, " {};", action)?;
+                }
+                write!(f, " }}")?;
+                if !otherwise.is_empty() {
+                    write!(f, " else {{")?;
+                    for action in otherwise {
+                        write!(f, " {};", action)?;
+                    }
+                    write!(f, " }}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Action {
+    pub fn as_call(&self) -> Option<&FuncCall> {
+        match self {
+            Action::Call(func) => Some(func),
+            Action::If { .. } => None,
+        }
+    }
+}
+
 impl std::fmt::Display for FuncCall {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}(", self.name)?;
@@ -149,74 +952,649 @@ impl std::fmt::Display for Value {
             Value::Number(n) => write!(f, "{}", n),
             Value::String(s) => write!(f, "\"{}\"", s),
             Value::Ident(id) => write!(f, "{}", id),
+            Value::Aggregate(aggregate) => write!(f, "{}", aggregate),
+        }
+    }
+}
+
+impl Value {
+    fn variant_index(&self) -> u8 {
+        match self {
+            Value::Boolean(_) => 0,
+            Value::Number(_) => 1,
+            Value::String(_) => 2,
+            Value::Ident(_) => 3,
+            Value::Aggregate(_) => 4,
+        }
+    }
+}
+
+pub(crate) fn normalize_number(n: &str) -> Cow<'_, str> {
+    let n = n.trim();
+    let (int, frac) = n.split_once('.').unwrap_or((n, ""));
+    if int.is_empty()
+        || !int.bytes().all(|b| b.is_ascii_digit())
+        || !frac.bytes().all(|b| b.is_ascii_digit())
+    {
+        return Cow::Borrowed(n);
+    }
+
+    let int = int.trim_start_matches('0');
+    let int = if int.is_empty() { "0" } else { int };
+    let frac = frac.trim_end_matches('0');
+
+    if frac.is_empty() {
+        Cow::Borrowed(int)
+    } else {
+        Cow::Owned(format!("{}.{}", int, frac))
+    }
+}
+
+fn cmp_numbers(a: &str, b: &str) -> Ordering {
+    let a = normalize_number(a);
+    let b = normalize_number(b);
+    let (a_int, a_frac) = a.split_once('.').unwrap_or((&a, ""));
+    let (b_int, b_frac) = b.split_once('.').unwrap_or((&b, ""));
+    let numeric = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+
+    if numeric(a_int) && numeric(b_int) {
+        a_int
+            .len()
+            .cmp(&b_int.len())
+            .then_with(|| a_int.cmp(b_int))
+            .then_with(|| a_frac.cmp(b_frac))
+    } else {
+        a.cmp(&b)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+            (Value::Number(a), Value::Number(b)) => cmp_numbers(a, b),
+            (Value::String(a), Value::String(b)) => a.cmp(b),
+            (Value::Ident(a), Value::Ident(b)) => a.cmp(b),
+            (Value::Aggregate(a), Value::Aggregate(b)) => a.cmp(b),
+            _ => self.variant_index().cmp(&other.variant_index()),
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.variant_index().hash(state);
+        match self {
+            Value::Boolean(b) => b.hash(state),
+            Value::Number(n) => normalize_number(n).hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Ident(path) => path.hash(state),
+            Value::Aggregate(aggregate) => aggregate.hash(state),
         }
     }
 }
 
 pub fn parse_triggers_to_ast(input: &str) -> Result<TriggerFile, TriggerParserError> {
-    let pairs = TriggerParser::parse(Rule::file, input)
+    parse_triggers_to_ast_with(input, &ParseOptions::default())
+}
+
+pub fn parse_triggers_to_ast_with(
+    input: &str,
+    options: &ParseOptions,
+) -> Result<TriggerFile, TriggerParserError> {
+    let file = parse_file_pair(input, &options.limits)?;
+    build_file(file, options, &Definitions::default())
+}
+
+/// Parses `input` into an AST borrowing its text from `input`. See
+/// [`borrowed`].
+pub fn parse_triggers_borrowed(
+    input: &str,
+) -> Result<borrowed::TriggerFile<'_>, TriggerParserError> {
+    parse_triggers_borrowed_with(input, &ParseOptions::default())
+}
+
+pub fn parse_triggers_borrowed_with<'src>(
+    input: &'src str,
+    options: &ParseOptions,
+) -> Result<borrowed::TriggerFile<'src>, TriggerParserError> {
+    let file = parse_file_pair(input, &options.limits)?;
+    build_borrowed_file(file, options, &Definitions::default())
+}
+
+pub(crate) fn parse_file_pair<'i>(
+    input: &'i str,
+    limits: &Limits,
+) -> Result<Pair<'i, Rule>, TriggerParserError> {
+    check_input(input, limits)?;
+    let mut pairs = TriggerParser::parse(Rule::file, input)
         .map_err(|e| TriggerParserError::PestError(e.to_string()))?;
+    Ok(pairs.next().unwrap())
+}
 
-    let mut triggers = Vec::new();
+// Checks the limits that can be checked on the text, before pest sees it.
+//
+// The grammar recurses once per parenthesis and `if` block, so pest itself
+// could run out of stack on pathological input before the AST is built and
+// depth checked. This rejects such input up front, counting brackets
+// outside strings and comments. Function calls and trigger bodies take one
+// bracket level of their own.
+fn check_input(input: &str, limits: &Limits) -> Result<(), TriggerParserError> {
+    if input.len() > limits.max_file_size {
+        return Err(TriggerParserError::FileTooLarge {
+            size: input.len(),
+            limit: limits.max_file_size,
+        });
+    }
+    let span_at = |start: usize| {
+        let (line, column) = pest::Position::new(input, start).unwrap().line_col();
+        Span {
+            start,
+            end: start + 1,
+            line,
+            column,
+        }
+    };
 
-    for pair in pairs {
-        if pair.as_rule() == Rule::file {
-            for inner_pair in pair.into_inner() {
-                if inner_pair.as_rule() == Rule::trigger {
-                    triggers.push(parse_trigger(inner_pair)?);
+    let max_depth = limits.max_depth;
+    let bytes = input.as_bytes();
+    let (mut parens, mut braces) = (0usize, 0usize);
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let start = i;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += 1;
+                }
+                if i - start - 1 > limits.max_string_len {
+                    return Err(TriggerParserError::StringTooLong {
+                        limit: limits.max_string_len,
+                        span: span_at(start),
+                    });
                 }
             }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'(' => parens += 1,
+            b')' => parens = parens.saturating_sub(1),
+            b'{' => braces += 1,
+            b'}' => braces = braces.saturating_sub(1),
+            _ => {}
+        }
+        if parens > max_depth.saturating_add(1) || braces > max_depth.saturating_add(2) {
+            return Err(TriggerParserError::NestingTooDeep {
+                limit: max_depth,
+                span: span_at(i),
+            });
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+pub(crate) fn file_imports(file: &Pair<Rule>) -> Vec<Import> {
+    file.clone()
+        .into_inner()
+        .filter(|pair| pair.as_rule() == Rule::import)
+        .map(parse_import)
+        .collect()
+}
+
+/// Named conditions and templates defined outside the file being built,
+/// e.g. in files it imports.
+#[derive(Debug, Default)]
+pub(crate) struct Definitions {
+    pub conditions: Vec<ConditionDef>,
+    pub templates: Vec<TemplateDef>,
+}
+
+/// Builds the AST of a parsed `file` pair.
+pub(crate) fn build_file(
+    file: Pair<Rule>,
+    options: &ParseOptions,
+    external: &Definitions,
+) -> Result<TriggerFile, TriggerParserError> {
+    intern_paths(&file, options.limits.max_interned_bytes)?;
+    build_borrowed_file(file, options, external).map(borrowed::TriggerFile::into_owned)
+}
+
+// Interns the segments of the field paths in `file` within `limit`, before
+// the owned AST interns them without one. Names of triggers, templates and
+// functions are not paths.
+fn intern_paths(file: &Pair<Rule>, limit: usize) -> Result<(), TriggerParserError> {
+    // New names cannot take more room than the file's text, so unless the
+    // interner is close to the limit there is nothing to check.
+    if path::interned_bytes().saturating_add(file.as_str().len()) <= limit {
+        return Ok(());
+    }
+    let mut pending = vec![file.clone()];
+    while let Some(pair) = pending.pop() {
+        let parent = pair.as_rule();
+        for child in pair.into_inner() {
+            let is_path = child.as_rule() == Rule::ident
+                && matches!(
+                    parent,
+                    Rule::atom
+                        | Rule::comparison
+                        | Rule::aggregate
+                        | Rule::aggregate_source
+                        | Rule::arg_list
+                        | Rule::value
+                );
+            if !is_path {
+                pending.push(child);
+                continue;
+            }
+            for segment in child.as_str().split('.') {
+                if Symbol::intern_within(segment, limit).is_none() {
+                    return Err(TriggerParserError::TooManyNames {
+                        limit,
+                        span: Span::from_pair(&child),
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build_borrowed_file<'i>(
+    file: Pair<'i, Rule>,
+    options: &ParseOptions,
+    external: &Definitions,
+) -> Result<borrowed::TriggerFile<'i>, TriggerParserError> {
+    let mut scope = ConditionScope {
+        external: &external.conditions,
+        declared: Vec::new(),
+        expanded: HashMap::new(),
+        stack: Vec::new(),
+        limits: options.limits,
+        nodes: 0,
+    };
+    for inner_pair in file.clone().into_inner() {
+        if inner_pair.as_rule() == Rule::condition_def {
+            scope.declare(inner_pair)?;
+        }
+    }
+
+    let mut templates: Vec<TemplateDef> = Vec::new();
+    for inner_pair in file.clone().into_inner() {
+        if inner_pair.as_rule() == Rule::template {
+            let template = template::parse_template(inner_pair, &mut scope, options)?;
+            let first = templates
+                .iter()
+                .chain(&external.templates)
+                .find(|t| t.name == template.name);
+            if let Some(first) = first {
+                return Err(TriggerParserError::DuplicateTemplate {
+                    name: template.name.clone(),
+                    first: Box::new(first.location()),
+                    second: Box::new(template.location()),
+                });
+            }
+            templates.push(template);
+        }
+    }
+
+    let mut triggers = Vec::new();
+    let mut imports = Vec::new();
+
+    for inner_pair in file.into_inner() {
+        let rule = inner_pair.as_rule();
+        if matches!(rule, Rule::trigger | Rule::instantiation)
+            && triggers.len() == options.limits.max_triggers
+        {
+            return Err(TriggerParserError::TooManyTriggers {
+                limit: options.limits.max_triggers,
+                span: Span::from_pair(&inner_pair),
+            });
+        }
+        match rule {
+            Rule::trigger => triggers.push(parse_trigger(inner_pair, &mut scope, options)?),
+            Rule::instantiation => {
+                let span = Span::from_pair(&inner_pair);
+                let trigger = template::instantiate(inner_pair, &templates, &external.templates)?;
+                scope.add_nodes(limits::trigger_nodes(&trigger), span)?;
+                triggers.push(trigger.into());
+            }
+            Rule::import => imports.push(parse_import(inner_pair)),
+            _ => {}
+        }
+    }
+
+    let conditions = scope.finish()?;
+
+    let file = borrowed::TriggerFile {
+        triggers,
+        imports,
+        conditions,
+        templates,
+    };
+    if !options.allow_duplicates {
+        file.check_unique_names()?;
+    }
+    Ok(file)
+}
+
+// Named conditions visible while parsing one file. Definitions are parsed
+// lazily on first reference, which is also where recursion is detected.
+//
+// The scope also keeps the AST within the file's limits, counting nodes as
+// they are built. Every reference to a named condition copies it, so its
+// depth and nodes are kept with the expanded definition and checked before
+// copying.
+struct ConditionScope<'i, 'e> {
+    external: &'e [ConditionDef],
+    declared: Vec<(String, Span, Pair<'i, Rule>)>,
+    expanded: HashMap<String, Expansion<'i>>,
+    stack: Vec<String>,
+    limits: Limits,
+    nodes: usize,
+}
+
+#[derive(Clone)]
+struct Expansion<'i> {
+    expr: borrowed::Expr<'i>,
+    depth: usize,
+    nodes: usize,
+}
+
+impl<'i> ConditionScope<'i, '_> {
+    fn declare(&mut self, pair: Pair<'i, Rule>) -> Result<(), TriggerParserError> {
+        let span = Span::from_pair(&pair);
+        let mut inner = pair.into_inner();
+        let name = inner.next().unwrap().as_str().to_string();
+        let expr = inner.next().unwrap();
+
+        let first = self
+            .declared
+            .iter()
+            .find(|(declared, _, _)| *declared == name)
+            .map(|(_, first, _)| Location {
+                file: None,
+                span: *first,
+            })
+            .or_else(|| {
+                self.external
+                    .iter()
+                    .find(|def| def.name == name)
+                    .map(ConditionDef::location)
+            });
+        if let Some(first) = first {
+            return Err(TriggerParserError::DuplicateCondition {
+                name,
+                first: Box::new(first),
+                second: Box::new(Location { file: None, span }),
+            });
+        }
+
+        self.declared.push((name, span, expr));
+        Ok(())
+    }
+
+    // Expands `name` if it is not already, returning its expansion.
+    fn resolve(&mut self, name: &str, span: Span) -> Result<&Expansion<'i>, TriggerParserError> {
+        if self.expanded.contains_key(name) {
+            return Ok(&self.expanded[name]);
+        }
+        if let Some(def) = self.external.iter().find(|def| def.name == name) {
+            let expansion = Expansion {
+                expr: def.condition.clone().into(),
+                depth: def.condition.depth(),
+                nodes: limits::expr_nodes(&def.condition),
+            };
+            return Ok(self.expanded.entry(name.to_string()).or_insert(expansion));
+        }
+        if let Some(start) = self.stack.iter().position(|n| n == name) {
+            let mut chain = self.stack[start..].to_vec();
+            chain.push(name.to_string());
+            return Err(TriggerParserError::RecursiveCondition { chain, span });
+        }
+
+        let Some((_, _, pair)) = self.declared.iter().find(|(n, _, _)| n == name) else {
+            return Err(TriggerParserError::UndefinedCondition {
+                name: name.to_string(),
+                span,
+            });
+        };
+        let pair = pair.clone();
+
+        self.stack.push(name.to_string());
+        let nodes = self.nodes;
+        let expr = parse_expr(pair, self, 0)?;
+        self.stack.pop();
+
+        let expansion = Expansion {
+            depth: expr.depth(),
+            nodes: self.nodes - nodes,
+            expr,
+        };
+        Ok(self.expanded.entry(name.to_string()).or_insert(expansion))
+    }
+
+    // Counts `nodes` more nodes built at `span`.
+    fn add_nodes(&mut self, nodes: usize, span: Span) -> Result<(), TriggerParserError> {
+        self.nodes += nodes;
+        if self.nodes > self.limits.max_nodes {
+            return Err(TriggerParserError::TooManyNodes {
+                limit: self.limits.max_nodes,
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_depth(&self, depth: usize, span: Span) -> Result<(), TriggerParserError> {
+        if depth > self.limits.max_depth {
+            return Err(TriggerParserError::NestingTooDeep {
+                limit: self.limits.max_depth,
+                span,
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<ConditionDef>, TriggerParserError> {
+        let declared: Vec<(String, Span)> = self
+            .declared
+            .iter()
+            .map(|(name, span, _)| (name.clone(), *span))
+            .collect();
+
+        declared
+            .into_iter()
+            .map(|(name, span)| {
+                let condition = self.resolve(&name, span)?.expr.clone().into_owned();
+                Ok(ConditionDef {
+                    name,
+                    condition,
+                    span,
+                    origin: None,
+                })
+            })
+            .collect()
+    }
+}
+
+// Bare identifiers starting with an uppercase letter and without dots refer
+// to named conditions; anything else is a field path.
+fn is_condition_name(ident: &str) -> bool {
+    ident.starts_with(|c: char| c.is_ascii_uppercase()) && !ident.contains('.')
+}
+
+pub(crate) fn check_unique_names(triggers: &[Trigger]) -> Result<(), TriggerParserError> {
+    let mut seen: HashMap<&str, &Trigger> = HashMap::new();
+    for trigger in triggers {
+        if let Some(first) = seen.get(trigger.name.as_str()) {
+            return Err(TriggerParserError::DuplicateTrigger {
+                name: trigger.name.clone(),
+                first: Box::new(first.location()),
+                second: Box::new(trigger.location()),
+            });
+        }
+        seen.insert(&trigger.name, trigger);
+    }
+    Ok(())
+}
+
+fn parse_import(pair: Pair<Rule>) -> Import {
+    let span = Span::from_pair(&pair);
+    let mut path = String::new();
+
+    for inner_pair in pair.into_inner() {
+        if inner_pair.as_rule() == Rule::string {
+            let s = inner_pair.as_str();
+            path = s[1..s.len() - 1].to_string();
         }
     }
 
-    Ok(TriggerFile { triggers })
+    Import { path, span }
 }
 
-fn parse_trigger(pair: Pair<Rule>) -> Result<Trigger, TriggerParserError> {
-    let mut name = String::new();
-    let mut event_type = String::new();
-    let mut description = String::new();
+fn parse_trigger<'i>(
+    pair: Pair<'i, Rule>,
+    scope: &mut ConditionScope<'i, '_>,
+    options: &ParseOptions,
+) -> Result<borrowed::Trigger<'i>, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let mut name = Cow::Borrowed("");
+    let mut event_type = None;
+    let mut schedule = None;
+    let mut pattern = None;
+    let mut description = None;
     let mut condition = None;
     let mut actions = Vec::new();
+    let mut else_actions = Vec::new();
+    let mut cooldown = None;
+    let mut max_fires = None;
+    let mut priority = 0;
+    let mut enabled = true;
+    let mut tags = Vec::new();
+    let mut fields: BTreeMap<String, Vec<FieldValue>> = BTreeMap::new();
+    // Where each non-repeatable field was first set.
+    let mut seen: HashMap<String, Span> = HashMap::new();
 
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
             Rule::ident => {
-                name = inner_pair.as_str().to_string();
+                name = Cow::Borrowed(inner_pair.as_str());
             }
             Rule::trigger_body => {
-                for body_pair in inner_pair.into_inner() {
-                    match body_pair.as_rule() {
+                for field in inner_pair.into_inner() {
+                    if field.as_rule() == Rule::if_block {
+                        actions.push(parse_if_block(field, scope, 0)?);
+                        continue;
+                    }
+                    let field_span = Span::from_pair(&field);
+                    let rule = field.as_rule();
+                    let mut inner = field.into_inner();
+                    let field_name = match rule {
+                        Rule::field_on => "on",
+                        Rule::field_description => "description",
+                        Rule::field_condition => "condition",
+                        Rule::field_action => "action",
+                        Rule::field_else_action => "else_action",
+                        Rule::field_cooldown => "cooldown",
+                        Rule::field_max_fires => "max_fires",
+                        Rule::field_priority => "priority",
+                        Rule::field_enabled => "enabled",
+                        Rule::field_tags => "tags",
+                        _ => inner.next().unwrap().as_str(),
+                    };
+
+                    let spec = options.fields.get(field_name);
+                    let repeated = match rule {
+                        Rule::field_action | Rule::field_else_action => true,
+                        Rule::field_custom => spec.is_some_and(|spec| spec.repeated),
+                        _ => false,
+                    };
+                    if !repeated {
+                        if let Some(first) = seen.get(field_name) {
+                            return Err(TriggerParserError::DuplicateField {
+                                field: field_name.to_string(),
+                                first: *first,
+                                second: field_span,
+                            });
+                        }
+                        seen.insert(field_name.to_string(), field_span);
+                    }
+
+                    match rule {
+                        Rule::field_cooldown => cooldown = Some(parse_cooldown(inner)?),
+                        Rule::field_max_fires => max_fires = Some(parse_rate_limit(inner)?),
                         Rule::field_on => {
-                            for on_pair in body_pair.into_inner() {
-                                if on_pair.as_rule() == Rule::event_type {
-                                    event_type = on_pair.as_str().to_string();
+                            let on = inner.next().unwrap();
+                            event_type = Some(match on.as_rule() {
+                                Rule::pattern => {
+                                    let parsed = parse_pattern(on)?;
+                                    let text = parsed.to_string();
+                                    pattern = Some(parsed);
+                                    Cow::Owned(text)
+                                }
+                                Rule::every | Rule::cron => {
+                                    let parsed = parse_schedule(on)?;
+                                    let text = parsed.to_string();
+                                    schedule = Some(parsed);
+                                    Cow::Owned(text)
                                 }
-                            }
+                                _ => Cow::Borrowed(on.as_str()),
+                            });
                         }
                         Rule::field_description => {
-                            for desc_pair in body_pair.into_inner() {
-                                if desc_pair.as_rule() == Rule::string {
-                                    let s = desc_pair.as_str();
-                                    description = s[1..s.len() - 1].to_string();
-                                }
-                            }
+                            let s = inner.as_str();
+                            description = Some(Cow::Borrowed(&s[1..s.len() - 1]));
                         }
                         Rule::field_condition => {
-                            for cond_pair in body_pair.into_inner() {
-                                if cond_pair.as_rule() == Rule::expr {
-                                    condition = Some(parse_expr(cond_pair)?);
-                                }
-                            }
+                            condition = Some(parse_expr(inner.next().unwrap(), scope, 0)?)
                         }
                         Rule::field_action => {
-                            for action_pair in body_pair.into_inner() {
-                                if action_pair.as_rule() == Rule::func_call {
-                                    actions.push(parse_func_call(action_pair)?);
-                                }
-                            }
+                            scope.add_nodes(1, field_span)?;
+                            actions
+                                .push(borrowed::Action::Call(parse_action(inner.next().unwrap())?))
+                        }
+                        Rule::field_else_action => {
+                            scope.add_nodes(1, field_span)?;
+                            else_actions
+                                .push(borrowed::Action::Call(parse_action(inner.next().unwrap())?))
+                        }
+                        Rule::field_priority => priority = parse_priority(inner.next().unwrap())?,
+                        Rule::field_enabled => enabled = inner.as_str() == "true",
+                        Rule::field_tags => {
+                            tags = inner
+                                .map(|tag| {
+                                    let s = tag.as_str();
+                                    Cow::Borrowed(&s[1..s.len() - 1])
+                                })
+                                .collect()
+                        }
+                        _ => {
+                            let value = inner.next().unwrap();
+                            let value =
+                                parse_field_value(field_name, field_span, value, spec, scope)?;
+                            fields
+                                .entry(field_name.to_string())
+                                .or_default()
+                                .push(value);
                         }
-                        _ => {}
                     }
                 }
             }
@@ -224,89 +1602,432 @@ fn parse_trigger(pair: Pair<Rule>) -> Result<Trigger, TriggerParserError> {
         }
     }
 
-    Ok(Trigger {
+    let missing = |field: &str| TriggerParserError::MissingField {
+        field: field.to_string(),
+        trigger: name.to_string(),
+        span,
+    };
+    let event_type = event_type.ok_or_else(|| missing("on"))?;
+    let description = description.ok_or_else(|| missing("description"))?;
+    if !else_actions.is_empty() && condition.is_none() {
+        return Err(missing("condition"));
+    }
+    for (field, spec) in &options.fields {
+        if spec.required && !fields.contains_key(field) {
+            return Err(missing(field));
+        }
+    }
+    if count_actions(&actions) + count_actions(&else_actions) > options.limits.max_actions {
+        return Err(TriggerParserError::TooManyActions {
+            trigger: name.to_string(),
+            limit: options.limits.max_actions,
+            span,
+        });
+    }
+
+    Ok(borrowed::Trigger {
         name,
         event_type,
+        schedule,
+        pattern,
         description,
         condition,
         actions,
+        else_actions,
+        cooldown,
+        max_fires,
+        priority,
+        enabled,
+        tags,
+        fields,
+        span,
     })
 }
 
-fn parse_expr(pair: Pair<Rule>) -> Result<Expr, TriggerParserError> {
-    let mut pairs = pair.into_inner();
-    let first = pairs.next().unwrap();
+// `every 10m` or `schedule("...")`.
+fn parse_schedule(pair: Pair<Rule>) -> Result<Schedule, TriggerParserError> {
+    let rule = pair.as_rule();
+    let inner = pair.into_inner().next().unwrap();
+    if rule == Rule::every {
+        return Ok(Schedule::Every(parse_duration("on", inner)?));
+    }
 
-    let mut left = parse_expr_atom(first)?;
+    let s = inner.as_str();
+    let cron = Cron::parse(&s[1..s.len() - 1]).map_err(|e| TriggerParserError::InvalidField {
+        field: "on".to_string(),
+        expected: format!("a cron expression ({})", e),
+        span: Span::from_pair(&inner),
+    })?;
+    Ok(Schedule::Cron(cron))
+}
 
-    while let Some(pair) = pairs.next() {
-        match pair.as_rule() {
-            Rule::and => {
-                if let Some(next_pair) = pairs.next() {
-                    let right = parse_expr(next_pair)?;
-                    left = Expr::And(Box::new(left), Box::new(right));
-                    break;
-                }
-            }
-            Rule::or => {
-                if let Some(next_pair) = pairs.next() {
-                    let right = parse_expr(next_pair)?;
-                    left = Expr::Or(Box::new(left), Box::new(right));
-                    break;
+fn parse_pattern(pair: Pair<Rule>) -> Result<EventPattern, TriggerParserError> {
+    let mut steps = Vec::new();
+    let mut within = Duration::ZERO;
+    let mut key = None;
+
+    for inner in pair.into_inner() {
+        match inner.as_rule() {
+            Rule::pattern_step => {
+                let mut count = 1;
+                let mut event_type = String::new();
+                for part in inner.into_inner() {
+                    if part.as_rule() == Rule::count {
+                        count = part
+                            .as_str()
+                            .parse()
+                            .ok()
+                            .filter(|n| *n > 0)
+                            .ok_or_else(|| TriggerParserError::InvalidField {
+                                field: "on".to_string(),
+                                expected: "a positive count".to_string(),
+                                span: Span::from_pair(&part),
+                            })?;
+                    } else {
+                        event_type = part.as_str().to_string();
+                    }
                 }
+                steps.push(PatternStep { event_type, count });
             }
-            Rule::expr => {
-                let right = parse_expr(pair)?;
-                left = Expr::And(Box::new(left), Box::new(right));
-            }
+            Rule::duration => within = parse_duration("on", inner)?,
+            Rule::rate_key => key = Some(parse_rate_key(inner)),
             _ => {}
         }
     }
 
-    Ok(left)
+    Ok(EventPattern { steps, within, key })
 }
-fn parse_expr_atom(pair: Pair<Rule>) -> Result<Expr, TriggerParserError> {
-    let mut not_count = 0;
-    let mut inner_expr = None;
 
-    for inner_pair in pair.into_inner() {
-        match inner_pair.as_rule() {
-            Rule::not => {
-                not_count += 1;
+// An `if` with its `else if` branches, at `depth`. Each `else if` is an
+// `if` nested in the `else` of the one before.
+fn parse_if_block<'i>(
+    pair: Pair<'i, Rule>,
+    scope: &mut ConditionScope<'i, '_>,
+    depth: usize,
+) -> Result<borrowed::Action<'i>, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let mut branches = Vec::new();
+    let mut last_block = None;
+    let mut inner = pair.into_inner();
+    while let Some(pair) = inner.next() {
+        match pair.as_rule() {
+            Rule::expr => branches.push((pair, inner.next().unwrap())),
+            _ => last_block = Some(pair),
+        }
+    }
+    scope.check_depth(depth + branches.len() - 1, span)?;
+    scope.add_nodes(branches.len(), span)?;
+
+    let mut otherwise = match last_block {
+        Some(block) => parse_action_block(block, scope, depth + branches.len())?,
+        None => Vec::new(),
+    };
+    for (i, (condition, then)) in branches.into_iter().enumerate().rev() {
+        let branch = borrowed::Action::If {
+            condition: parse_expr(condition, scope, depth + i + 1)?,
+            then: parse_action_block(then, scope, depth + i + 1)?,
+            otherwise,
+        };
+        if i == 0 {
+            return Ok(branch);
+        }
+        otherwise = vec![branch];
+    }
+    unreachable!("an if block has at least one branch")
+}
+
+fn parse_action_block<'i>(
+    pair: Pair<'i, Rule>,
+    scope: &mut ConditionScope<'i, '_>,
+    depth: usize,
+) -> Result<Vec<borrowed::Action<'i>>, TriggerParserError> {
+    scope.check_depth(depth, Span::from_pair(&pair))?;
+    pair.into_inner()
+        .map(|item| match item.as_rule() {
+            Rule::if_block => parse_if_block(item, scope, depth),
+            _ => {
+                scope.add_nodes(1, Span::from_pair(&item))?;
+                parse_action(item.into_inner().next().unwrap()).map(borrowed::Action::Call)
             }
-            Rule::comparison => {
-                inner_expr = Some(Expr::Comparison(parse_comparison(inner_pair)?));
+        })
+        .collect()
+}
+
+// Actions including those in `if` blocks, which count as actions themselves.
+fn count_actions(actions: &[borrowed::Action]) -> usize {
+    actions
+        .iter()
+        .map(|action| match action {
+            borrowed::Action::Call(_) => 1,
+            borrowed::Action::If {
+                then, otherwise, ..
+            } => 1 + count_actions(then) + count_actions(otherwise),
+        })
+        .sum()
+}
+
+fn parse_action(pair: Pair<'_, Rule>) -> Result<borrowed::FuncCall<'_>, TriggerParserError> {
+    if pair.as_rule() == Rule::stop {
+        return Ok(borrowed::FuncCall {
+            name: Cow::Borrowed(engine::STOP_PROPAGATION),
+            args: Vec::new(),
+        });
+    }
+    parse_func_call(pair)
+}
+
+fn parse_priority(pair: Pair<Rule>) -> Result<i32, TriggerParserError> {
+    pair.as_str()
+        .parse()
+        .map_err(|_| TriggerParserError::InvalidField {
+            field: "priority".to_string(),
+            expected: "a 32-bit integer".to_string(),
+            span: Span::from_pair(&pair),
+        })
+}
+
+fn parse_cooldown(mut pairs: Pairs<Rule>) -> Result<Cooldown, TriggerParserError> {
+    let period = parse_duration("cooldown", pairs.next().unwrap())?;
+    let key = pairs.next().map(parse_rate_key);
+    Ok(Cooldown { period, key })
+}
+
+fn parse_rate_limit(mut pairs: Pairs<Rule>) -> Result<RateLimit, TriggerParserError> {
+    let count_pair = pairs.next().unwrap();
+    let count = count_pair
+        .as_str()
+        .parse()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or_else(|| TriggerParserError::InvalidField {
+            field: "max_fires".to_string(),
+            expected: "a positive count".to_string(),
+            span: Span::from_pair(&count_pair),
+        })?;
+    let window = parse_duration("max_fires", pairs.next().unwrap())?;
+    let key = pairs.next().map(parse_rate_key);
+    Ok(RateLimit { count, window, key })
+}
+
+fn parse_rate_key(pair: Pair<Rule>) -> String {
+    pair.into_inner().next().unwrap().as_str().to_string()
+}
+
+fn parse_duration(field: &str, pair: Pair<Rule>) -> Result<Duration, TriggerParserError> {
+    let text = pair.as_str();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap();
+    let (amount, unit) = text.split_at(split);
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        _ => 86_400_000,
+    };
+    amount
+        .parse::<u64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(millis))
+        .filter(|millis| *millis > 0)
+        .map(Duration::from_millis)
+        .ok_or_else(|| TriggerParserError::InvalidField {
+            field: field.to_string(),
+            expected: "a positive duration".to_string(),
+            span: Span::from_pair(&pair),
+        })
+}
+
+/// Formats a duration the way it is written in trigger files, using the
+/// largest unit that divides it.
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    for (unit, size) in [
+        ("d", 86_400_000),
+        ("h", 3_600_000),
+        ("m", 60_000),
+        ("s", 1_000),
+    ] {
+        if millis != 0 && millis.is_multiple_of(size) {
+            return format!("{}{}", millis / size, unit);
+        }
+    }
+    format!("{}ms", millis)
+}
+
+// The value of a `name: ...` field that is not one of the built-in forms.
+// Built-in names only end up here when their value has the wrong shape.
+fn parse_field_value<'i>(
+    name: &str,
+    field_span: Span,
+    pair: Pair<'i, Rule>,
+    spec: Option<&FieldSpec>,
+    scope: &mut ConditionScope<'i, '_>,
+) -> Result<FieldValue, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let invalid = |expected: String| TriggerParserError::InvalidField {
+        field: name.to_string(),
+        expected,
+        span,
+    };
+
+    let Some(spec) = spec else {
+        return Err(match name {
+            "on" => invalid(format!(
+                "one of {}, `every <duration>` or `schedule(\"<cron>\")`",
+                EVENT_TYPES.join(", ")
+            )),
+            "description" => invalid("a string".to_string()),
+            "condition" => invalid("a condition".to_string()),
+            "action" | "else_action" => invalid("a function call".to_string()),
+            "cooldown" => {
+                invalid("a duration like 30s, optionally followed by `by <field>`".to_string())
             }
-            Rule::func_call => {
-                inner_expr = Some(Expr::FuncCall(parse_func_call(inner_pair)?));
+            "priority" => invalid("an integer".to_string()),
+            "enabled" => invalid("true or false".to_string()),
+            "tags" => invalid("a list of strings".to_string()),
+            "max_fires" => {
+                invalid("a limit like 3 per 1m, optionally followed by `by <field>`".to_string())
             }
-            Rule::ident => {
-                inner_expr = Some(Expr::Ident(inner_pair.as_str().to_string()));
+            _ => TriggerParserError::UnknownField {
+                field: name.to_string(),
+                span: field_span,
+            },
+        });
+    };
+
+    let raw = if spec.ty == FieldType::Expr && pair.as_rule() == Rule::expr {
+        Some(FieldValue::Expr(parse_expr(pair, scope, 0)?.into_owned()))
+    } else {
+        raw_field_value(pair)?
+    };
+    raw.and_then(|raw| spec.ty.accept(raw))
+        .ok_or_else(|| invalid(spec.ty.to_string()))
+}
+
+const EVENT_TYPES: &[&str] = &[
+    "player_join",
+    "player_leave",
+    "player_score_change",
+    "message_receive",
+];
+
+// A field value as written, before checking it against the field's type.
+// `None` for a compound condition, which only `FieldType::Expr` accepts.
+fn raw_field_value(pair: Pair<Rule>) -> Result<Option<FieldValue>, TriggerParserError> {
+    Ok(Some(match pair.as_rule() {
+        Rule::list => FieldValue::List(
+            pair.into_inner()
+                .map(|item| Ok(FieldValue::Value(parse_value(item)?.into_owned())))
+                .collect::<Result<_, _>>()?,
+        ),
+        Rule::expr => {
+            let mut atoms = pair.into_inner();
+            let mut parts = atoms.next().unwrap().into_inner();
+            let only = parts.next().unwrap();
+            if atoms.next().is_some() || parts.next().is_some() {
+                return Ok(None);
             }
-            Rule::expr => {
-                inner_expr = Some(Expr::Parenthesized(Box::new(parse_expr(inner_pair)?)));
+            match only.as_rule() {
+                Rule::ident => match only.as_str() {
+                    "true" | "false" => FieldValue::Value(Value::Boolean(only.as_str() == "true")),
+                    id => FieldValue::Value(Value::Ident(FieldPath::parse(id))),
+                },
+                Rule::func_call => FieldValue::FuncCall(parse_func_call(only)?.into_owned()),
+                _ => return Ok(None),
             }
-            _ => {}
         }
+        _ => FieldValue::Value(parse_value(pair)?.into_owned()),
+    }))
+}
+
+// Parses `pair` into an expression whose root is `depth` levels down.
+// Operands are grouped from the right, so the chain's operators nest one
+// level each.
+fn parse_expr<'i>(
+    pair: Pair<'i, Rule>,
+    scope: &mut ConditionScope<'i, '_>,
+    depth: usize,
+) -> Result<borrowed::Expr<'i>, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let parts: Vec<Pair<'i, Rule>> = pair.into_inner().collect();
+    let operators = parts.len() / 2;
+    scope.check_depth(depth + operators, span)?;
+    scope.add_nodes(operators, span)?;
+
+    let mut operands = Vec::with_capacity(operators + 1);
+    for (i, atom) in parts.iter().step_by(2).enumerate() {
+        let atom_depth = depth + i + usize::from(i < operators);
+        operands.push(parse_expr_atom(atom.clone(), scope, atom_depth)?);
+    }
+
+    let mut result = operands.pop().unwrap();
+    for (left, operator) in operands
+        .into_iter()
+        .zip(parts.iter().skip(1).step_by(2))
+        .rev()
+    {
+        result = match operator.as_rule() {
+            Rule::and => borrowed::Expr::And(Box::new(left), Box::new(result)),
+            _ => borrowed::Expr::Or(Box::new(left), Box::new(result)),
+        };
     }
+    Ok(result)
+}
 
-    let mut result = inner_expr.unwrap();
+fn parse_expr_atom<'i>(
+    pair: Pair<'i, Rule>,
+    scope: &mut ConditionScope<'i, '_>,
+    depth: usize,
+) -> Result<borrowed::Expr<'i>, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let mut inner = pair.into_inner();
+    let not_count = inner
+        .clone()
+        .take_while(|p| p.as_rule() == Rule::not)
+        .count();
+    // The operand under the `!`s.
+    let depth = depth + not_count;
+    scope.check_depth(depth, span)?;
+    scope.add_nodes(not_count + 1, span)?;
+
+    let inner_pair = inner.nth(not_count).unwrap();
+    let mut result = match inner_pair.as_rule() {
+        Rule::comparison => borrowed::Expr::Comparison(parse_comparison(inner_pair)?),
+        Rule::func_call => borrowed::Expr::FuncCall(parse_func_call(inner_pair)?),
+        Rule::ident if is_condition_name(inner_pair.as_str()) => {
+            let span = Span::from_pair(&inner_pair);
+            let name = inner_pair.as_str();
+            let Expansion {
+                depth: height,
+                nodes,
+                ..
+            } = *scope.resolve(name, span)?;
+            scope.check_depth(depth + 1 + height, span)?;
+            scope.add_nodes(nodes, span)?;
+            borrowed::Expr::Parenthesized(Box::new(scope.expanded[name].expr.clone()))
+        }
+        Rule::ident => borrowed::Expr::Ident(Cow::Borrowed(inner_pair.as_str())),
+        _ => borrowed::Expr::Parenthesized(Box::new(parse_expr(inner_pair, scope, depth + 1)?)),
+    };
     for _ in 0..not_count {
-        result = Expr::Not(Box::new(result));
+        result = borrowed::Expr::Not(Box::new(result));
     }
 
     Ok(result)
 }
 
-fn parse_comparison(pair: Pair<Rule>) -> Result<Comparison, TriggerParserError> {
-    let mut left = String::new();
+fn parse_comparison(pair: Pair<'_, Rule>) -> Result<borrowed::Comparison<'_>, TriggerParserError> {
+    let mut left = borrowed::Value::Ident(Cow::Borrowed(""));
     let mut operator = ComparisonOp::Eq;
-    let mut right = Value::Boolean(false);
+    let mut right = borrowed::Value::Boolean(false);
 
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
             Rule::ident => {
-                left = inner_pair.as_str().to_string();
+                left = borrowed::Value::Ident(Cow::Borrowed(inner_pair.as_str()));
+            }
+            Rule::aggregate => {
+                left = borrowed::Value::Aggregate(Box::new(parse_aggregate(inner_pair)?))
             }
             Rule::eq => operator = ComparisonOp::Eq,
             Rule::neq => operator = ComparisonOp::Neq,
@@ -321,50 +2042,116 @@ fn parse_comparison(pair: Pair<Rule>) -> Result<Comparison, TriggerParserError>
         }
     }
 
-    Ok(Comparison {
+    Ok(borrowed::Comparison {
         left,
         operator,
         right,
     })
 }
 
-fn parse_func_call(pair: Pair<Rule>) -> Result<FuncCall, TriggerParserError> {
-    let mut name = String::new();
+fn parse_func_call(pair: Pair<'_, Rule>) -> Result<borrowed::FuncCall<'_>, TriggerParserError> {
+    let mut name = "";
     let mut args = Vec::new();
 
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
             Rule::ident => {
-                name = inner_pair.as_str().to_string();
+                name = inner_pair.as_str();
             }
             Rule::arg_list => {
-                for arg_pair in inner_pair.into_inner() {
-                    if arg_pair.as_rule() == Rule::value {
-                        args.push(parse_value(arg_pair)?);
-                    } else if arg_pair.as_rule() == Rule::ident {
-                        args.push(Value::Ident(arg_pair.as_str().to_string()));
-                    }
-                }
+                args = parse_arg_list(inner_pair)?;
             }
             _ => {}
         }
     }
 
-    Ok(FuncCall { name, args })
+    Ok(borrowed::FuncCall {
+        name: Cow::Borrowed(name),
+        args,
+    })
+}
+
+fn parse_arg_list(pair: Pair<'_, Rule>) -> Result<Vec<borrowed::Value<'_>>, TriggerParserError> {
+    let mut args = Vec::new();
+
+    for arg_pair in pair.into_inner() {
+        if arg_pair.as_rule() == Rule::value {
+            args.push(parse_value(arg_pair)?);
+        } else if arg_pair.as_rule() == Rule::ident {
+            args.push(borrowed::Value::Ident(Cow::Borrowed(arg_pair.as_str())));
+        }
+    }
+
+    Ok(args)
 }
 
-fn parse_value(pair: Pair<Rule>) -> Result<Value, TriggerParserError> {
+fn parse_value(pair: Pair<'_, Rule>) -> Result<borrowed::Value<'_>, TriggerParserError> {
     let inner = pair.into_inner().next().unwrap();
 
     Ok(match inner.as_rule() {
-        Rule::boolean => Value::Boolean(inner.as_str() == "true"),
-        Rule::number => Value::Number(inner.as_str().to_string()),
+        Rule::boolean => borrowed::Value::Boolean(inner.as_str() == "true"),
+        Rule::number => borrowed::Value::Number(Cow::Borrowed(inner.as_str())),
         Rule::string => {
             let s = inner.as_str();
-            Value::String(s[1..s.len() - 1].to_string())
+            borrowed::Value::String(Cow::Borrowed(&s[1..s.len() - 1]))
         }
-        Rule::ident => Value::Ident(inner.as_str().to_string()),
-        _ => Value::Boolean(false),
+        Rule::ident => borrowed::Value::Ident(Cow::Borrowed(inner.as_str())),
+        Rule::aggregate => borrowed::Value::Aggregate(Box::new(parse_aggregate(inner)?)),
+        _ => borrowed::Value::Boolean(false),
+    })
+}
+
+fn parse_aggregate(pair: Pair<Rule>) -> Result<Aggregate, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let mut inner = pair.into_inner();
+    let function = match inner.next().unwrap().as_str() {
+        "count" => AggregateFn::Count,
+        "sum" => AggregateFn::Sum,
+        "avg" => AggregateFn::Avg,
+        "min" => AggregateFn::Min,
+        _ => AggregateFn::Max,
+    };
+    let mut source = inner.next().unwrap().into_inner();
+    let event_type = source.next().unwrap().as_str().to_string();
+    let field = source.next().map(|field| FieldPath::parse(field.as_str()));
+
+    let mut key = None;
+    let mut window = Duration::ZERO;
+    for part in inner {
+        match part.as_rule() {
+            Rule::ident => key = Some(FieldPath::parse(part.as_str())),
+            Rule::duration => {
+                window = parse_duration("window", part).map_err(|_| {
+                    TriggerParserError::InvalidAggregate {
+                        aggregate: function.to_string(),
+                        message: "the window must be positive".to_string(),
+                        span,
+                    }
+                })?
+            }
+            _ => {}
+        }
+    }
+
+    let message = match (function, &field) {
+        (AggregateFn::Count, Some(_)) => Some("count takes an event type, not a field"),
+        (AggregateFn::Count, None) | (_, Some(_)) => None,
+        (_, None) => Some("expected a field, e.g. player_score_change.delta"),
+    };
+    if let Some(message) = message {
+        return Err(TriggerParserError::InvalidAggregate {
+            aggregate: function.to_string(),
+            message: message.to_string(),
+            span,
+        });
+    }
+
+    Ok(Aggregate {
+        function,
+        event_type,
+        field,
+        key,
+        window,
     })
 }
 
@@ -0,0 +1,182 @@
+//! Caps on what a trigger file may ask of the parser and the engine.
+//!
+//! Trigger files may come from people other than the server's operators, so
+//! parsing and running them is bounded by [`Limits`]: the size of the file,
+//! its triggers and their actions, string literals, how deeply conditions
+//! nest, how many nodes the AST may have once named conditions and
+//! templates are expanded, and how much room the names of every file parsed
+//! take in the process-wide interner. Each limit exceeded is reported by its
+//! own [`TriggerParserError`](crate::TriggerParserError) variant.
+//!
+//! The engine also limits the work done for one event, in condition steps
+//! and in wall time; see [`Engine::with_limits`]. A step is one node of a
+//! condition evaluated: an `&&`, `||` or `!`, a comparison, a call or a
+//! field. Aggregates also take a step for each recorded event they look at.
+//! When the budget runs out, the event's remaining triggers are not run and
+//! [`Engine::dispatch_checked`] reports which trigger it stopped at, as an
+//! [`EngineError::BudgetExceeded`].
+//!
+//! [`Engine::with_limits`]: crate::engine::Engine::with_limits
+//! [`Engine::dispatch_checked`]: crate::engine::Engine::dispatch_checked
+//! [`EngineError::BudgetExceeded`]: crate::engine::EngineError::BudgetExceeded
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::engine::EngineError;
+use crate::visit::{Visitor, walk_action, walk_expr};
+use crate::{Action, Expr, Trigger};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes per file.
+    pub max_file_size: usize,
+    /// Triggers per file, and for projects in total, instantiated templates
+    /// included.
+    pub max_triggers: usize,
+    /// Actions per trigger, counting those inside `if` blocks and else
+    /// actions.
+    pub max_actions: usize,
+    /// Bytes in a string literal, without the quotes.
+    pub max_string_len: usize,
+    /// Condition and action nodes per file, and for projects in total, after
+    /// named conditions and templates are expanded.
+    pub max_nodes: usize,
+    /// How deeply conditions and `if` blocks may nest. Each `!`,
+    /// parenthesis, `&&` or `||` in a chain and `else if` adds a level, and
+    /// a named condition as many as its definition has. The default leaves
+    /// room to parse on a thread with the usual 2 MiB stack, even in debug
+    /// builds.
+    pub max_depth: usize,
+    /// Bytes of the distinct names in field paths interned by the process,
+    /// across every file parsed. Names already interned do not count again.
+    pub max_interned_bytes: usize,
+    /// Condition steps per event.
+    pub max_steps: u64,
+    /// Wall time per event.
+    pub max_time: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_file_size: 16 << 20,
+            max_triggers: 100_000,
+            max_actions: 1_000,
+            max_string_len: 64 << 10,
+            max_nodes: 4_000_000,
+            max_depth: 128,
+            max_interned_bytes: 64 << 20,
+            max_steps: 1_000_000,
+            max_time: Duration::from_millis(100),
+        }
+    }
+}
+
+/// The part of an event's budget that ran out, and its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overrun {
+    Steps { limit: u64 },
+    Time { limit: Duration },
+}
+
+impl std::fmt::Display for Overrun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Overrun::Steps { limit } => write!(f, "{} steps", limit),
+            Overrun::Time { limit } => write!(f, "{:?}", limit),
+        }
+    }
+}
+
+/// The steps and time left for one event. Evaluation takes steps through a
+/// shared reference, as conditions are evaluated by `&self` methods.
+pub(crate) struct Budget {
+    max_steps: u64,
+    max_time: Duration,
+    steps: Cell<u64>,
+    started: Instant,
+    overrun: Cell<Option<Overrun>>,
+}
+
+// Reading the clock costs about as much as a step, so it is read once every
+// this many steps.
+const STEPS_PER_CLOCK_READ: u64 = 64;
+
+impl Budget {
+    pub(crate) fn new(limits: &Limits) -> Self {
+        Budget {
+            max_steps: limits.max_steps,
+            max_time: limits.max_time,
+            steps: Cell::new(0),
+            started: Instant::now(),
+            overrun: Cell::new(None),
+        }
+    }
+
+    /// Takes a step. Returns false once the budget is spent.
+    pub(crate) fn step(&self) -> bool {
+        if self.overrun.get().is_some() {
+            return false;
+        }
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if steps > self.max_steps {
+            self.overrun.set(Some(Overrun::Steps {
+                limit: self.max_steps,
+            }));
+            return false;
+        }
+        !steps.is_multiple_of(STEPS_PER_CLOCK_READ) || self.check_time()
+    }
+
+    /// Returns false if the time is up.
+    pub(crate) fn check_time(&self) -> bool {
+        if self.overrun.get().is_none() && self.started.elapsed() >= self.max_time {
+            self.overrun.set(Some(Overrun::Time {
+                limit: self.max_time,
+            }));
+        }
+        self.overrun.get().is_none()
+    }
+
+    pub(crate) fn overrun(&self) -> Option<Overrun> {
+        self.overrun.get()
+    }
+
+    /// The error for running out at `trigger`.
+    pub(crate) fn error(&self, trigger: &str) -> Option<EngineError> {
+        Some(EngineError::BudgetExceeded {
+            trigger: trigger.to_string(),
+            kind: self.overrun.get()?,
+        })
+    }
+}
+
+// Condition and action nodes, as counted against `max_nodes`.
+#[derive(Default)]
+struct Nodes(usize);
+
+impl Visitor for Nodes {
+    fn visit_action(&mut self, action: &Action) {
+        self.0 += 1;
+        walk_action(self, action);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.0 += 1;
+        walk_expr(self, expr);
+    }
+}
+
+pub(crate) fn trigger_nodes(trigger: &Trigger) -> usize {
+    let mut nodes = Nodes::default();
+    nodes.visit_trigger(trigger);
+    nodes.0
+}
+
+pub(crate) fn expr_nodes(expr: &Expr) -> usize {
+    let mut nodes = Nodes::default();
+    nodes.visit_expr(expr);
+    nodes.0
+}
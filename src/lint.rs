@@ -0,0 +1,689 @@
+//! Style and correctness checks over a parsed trigger file.
+//!
+//! Every rule has a stable kebab-case name used in `triggerlang.toml` and in
+//! `// lint:allow(rule-name)` comments. A suppression comment applies to the
+//! trigger it is written in, or to the next trigger when written above one.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::{self, OverlapKind, Satisfiability, check_condition};
+use crate::visit::{Visitor, walk_expr};
+use crate::{
+    Action, ComparisonOp, Expr, FieldPath, FuncCall, Span, Trigger, TriggerFile,
+    TriggerParserError, Value,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Off,
+    Info,
+    Warning,
+    Error,
+}
+
+pub struct Rule {
+    pub name: &'static str,
+    pub default_level: Level,
+    pub description: &'static str,
+}
+
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "duplicate-name",
+        default_level: Level::Error,
+        description: "two triggers share a name",
+    },
+    Rule {
+        name: "empty-description",
+        default_level: Level::Warning,
+        description: "the description is empty",
+    },
+    Rule {
+        name: "no-actions",
+        default_level: Level::Warning,
+        description: "the trigger has no actions",
+    },
+    Rule {
+        name: "bool-comparison",
+        default_level: Level::Warning,
+        description: "a field is compared with `true` or `false`",
+    },
+    Rule {
+        name: "unknown-field",
+        default_level: Level::Warning,
+        description: "a field path is not known or looks misspelled",
+    },
+    Rule {
+        name: "unused-field",
+        default_level: Level::Info,
+        description: "a field listed in the config is never used",
+    },
+    Rule {
+        name: "deep-nesting",
+        default_level: Level::Warning,
+        description: "a condition nests boolean operators too deeply",
+    },
+    Rule {
+        name: "action-in-condition",
+        default_level: Level::Warning,
+        description: "a condition calls a function used as an action",
+    },
+    Rule {
+        name: "unsatisfiable-condition",
+        default_level: Level::Error,
+        description: "a condition can never be true",
+    },
+    Rule {
+        name: "tautological-condition",
+        default_level: Level::Warning,
+        description: "a condition is always true",
+    },
+    Rule {
+        name: "overlapping-triggers",
+        default_level: Level::Warning,
+        description: "two triggers can fire on the same event",
+    },
+    Rule {
+        name: "subsumed-trigger",
+        default_level: Level::Warning,
+        description: "a trigger only fires when another one does too",
+    },
+];
+
+const DEFAULT_MAX_DEPTH: usize = 4;
+
+/// The `[lint]` table of `triggerlang.toml`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LintConfig {
+    /// Deepest allowed nesting of `&&`, `||` and `!` in a condition.
+    pub max_depth: Option<usize>,
+    /// Known field paths. `player.*` accepts any path under `player`. When
+    /// empty, fields are only checked for likely misspellings.
+    pub fields: Vec<String>,
+    /// Functions that must not be called from conditions, in addition to
+    /// every function used in an `action:` of the file.
+    pub action_functions: Vec<String>,
+    pub rules: BTreeMap<String, Level>,
+}
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    lint: LintConfig,
+}
+
+impl LintConfig {
+    pub fn from_toml(input: &str) -> Result<Self, TriggerParserError> {
+        let file: ConfigFile =
+            toml::from_str(input).map_err(|e| TriggerParserError::ConfigError(e.to_string()))?;
+        let config = file.lint;
+
+        for name in config.rules.keys() {
+            if find_rule(name).is_none() {
+                return Err(TriggerParserError::ConfigError(format!(
+                    "unknown lint rule `{}`",
+                    name
+                )));
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn level(&self, rule: &str) -> Level {
+        match self.rules.get(rule) {
+            Some(level) => *level,
+            None => find_rule(rule).map_or(Level::Off, |r| r.default_level),
+        }
+    }
+}
+
+pub fn find_rule(name: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|rule| rule.name == name)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub level: Level,
+    pub message: String,
+    /// The trigger the diagnostic is reported on, if any.
+    pub trigger: Option<String>,
+    /// The file of that trigger, when linting a project.
+    pub file: Option<PathBuf>,
+    pub span: Option<Span>,
+    /// Other triggers involved, e.g. the second trigger of an overlap.
+    pub related: Vec<String>,
+}
+
+/// Runs every enabled rule. `source` is the text `file` was parsed from and
+/// is scanned for suppression comments.
+pub fn lint(source: &str, file: &TriggerFile, config: &LintConfig) -> Vec<Diagnostic> {
+    let triggers: Vec<(usize, &Trigger)> = file.triggers.iter().enumerate().collect();
+    run(file, config, suppressions(source, &triggers))
+}
+
+/// Lints a project loaded by [`load_project`], imports included.
+/// Suppression comments are read from each trigger's `origin` file.
+///
+/// [`load_project`]: crate::project::load_project
+pub fn lint_project(
+    file: &TriggerFile,
+    config: &LintConfig,
+) -> Result<Vec<Diagnostic>, TriggerParserError> {
+    let mut by_origin: BTreeMap<&Path, Vec<(usize, &Trigger)>> = BTreeMap::new();
+    for (index, trigger) in file.triggers.iter().enumerate() {
+        if let Some(origin) = &trigger.origin {
+            by_origin.entry(origin).or_default().push((index, trigger));
+        }
+    }
+
+    let mut allowed = HashMap::new();
+    for (origin, triggers) in by_origin {
+        let source = fs::read_to_string(origin).map_err(|e| TriggerParserError::IoError {
+            path: origin.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        allowed.extend(suppressions(&source, &triggers));
+    }
+    Ok(run(file, config, allowed))
+}
+
+fn run(
+    file: &TriggerFile,
+    config: &LintConfig,
+    allowed: HashMap<usize, BTreeSet<String>>,
+) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        config,
+        file,
+        actions: file
+            .triggers
+            .iter()
+            .flat_map(|t| t.calls().into_iter().map(|a| a.name.as_str()))
+            .chain(config.action_functions.iter().map(String::as_str))
+            .collect(),
+        diagnostics: Vec::new(),
+    };
+
+    linter.check_names();
+    for index in 0..file.triggers.len() {
+        linter.check_trigger(index);
+    }
+    linter.check_fields();
+    linter.check_overlaps();
+
+    let mut diagnostics: Vec<Diagnostic> = linter
+        .diagnostics
+        .into_iter()
+        .filter(|(d, triggers)| {
+            !triggers.iter().any(|index| {
+                allowed
+                    .get(index)
+                    .is_some_and(|rules| rules.contains(d.rule))
+            })
+        })
+        .map(|(d, _)| d)
+        .collect();
+    diagnostics.sort_by(|a, b| {
+        let key = |d: &Diagnostic| (d.span.is_none(), d.file.clone(), d.span.map(|s| s.start));
+        key(a).cmp(&key(b))
+    });
+    diagnostics
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    file: &'a TriggerFile,
+    // Functions used in an action anywhere in the file or listed in the
+    // config, which conditions must not call.
+    actions: BTreeSet<&'a str>,
+    // Each diagnostic with the triggers it is about, by index, whose
+    // suppression comments apply to it.
+    diagnostics: Vec<(Diagnostic, Vec<usize>)>,
+}
+
+impl Linter<'_> {
+    // `trigger` is an index into the file's triggers.
+    fn report(&mut self, rule: &'static str, trigger: Option<usize>, message: String) {
+        self.report_related(rule, trigger, Vec::new(), message);
+    }
+
+    fn report_related(
+        &mut self,
+        rule: &'static str,
+        trigger: Option<usize>,
+        related: Vec<usize>,
+        message: String,
+    ) {
+        let level = self.config.level(rule);
+        if level == Level::Off {
+            return;
+        }
+        let triggers = &self.file.triggers;
+        let reported = trigger.map(|index| &triggers[index]);
+        let diagnostic = Diagnostic {
+            rule,
+            level,
+            message,
+            trigger: reported.map(|t| t.name.clone()),
+            file: reported.and_then(|t| t.origin.clone()),
+            span: reported.map(|t| t.span),
+            related: related
+                .iter()
+                .map(|index| triggers[*index].name.clone())
+                .collect(),
+        };
+        self.diagnostics
+            .push((diagnostic, trigger.into_iter().chain(related).collect()));
+    }
+
+    fn check_names(&mut self) {
+        let file = self.file;
+        let mut seen: HashMap<&str, &Trigger> = HashMap::new();
+        for (index, trigger) in file.triggers.iter().enumerate() {
+            match seen.get(trigger.name.as_str()) {
+                Some(first) => self.report(
+                    "duplicate-name",
+                    Some(index),
+                    format!(
+                        "trigger `{}` is already defined at {}",
+                        trigger.name,
+                        first.location()
+                    ),
+                ),
+                None => {
+                    seen.insert(&trigger.name, trigger);
+                }
+            }
+        }
+    }
+
+    fn check_trigger(&mut self, index: usize) {
+        let file = self.file;
+        let trigger = &file.triggers[index];
+        if trigger.description.trim().is_empty() {
+            self.report(
+                "empty-description",
+                Some(index),
+                format!("trigger `{}` has an empty description", trigger.name),
+            );
+        }
+        if trigger.calls().is_empty() {
+            self.report(
+                "no-actions",
+                Some(index),
+                format!("trigger `{}` has no actions", trigger.name),
+            );
+        }
+
+        for condition in if_conditions(&trigger.actions)
+            .into_iter()
+            .chain(if_conditions(&trigger.else_actions))
+        {
+            self.check_satisfiability(
+                index,
+                condition,
+                format!("condition of an `if` in `{}`", trigger.name),
+            );
+        }
+
+        let Some(condition) = &trigger.condition else {
+            return;
+        };
+
+        let mut collector = ConditionCollector::default();
+        collector.visit_expr(condition);
+
+        for (field, value, op) in collector.bool_comparisons {
+            let suggestion = if (op == ComparisonOp::Eq) == value {
+                field.clone()
+            } else {
+                format!("!{}", field)
+            };
+            self.report(
+                "bool-comparison",
+                Some(index),
+                format!(
+                    "`{} {} {}` can be written as `{}`",
+                    field, op, value, suggestion
+                ),
+            );
+        }
+
+        let max_depth = self.config.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        let depth = nesting_depth(condition);
+        if depth > max_depth {
+            self.report(
+                "deep-nesting",
+                Some(index),
+                format!(
+                    "condition of `{}` is nested {} levels deep (max {})",
+                    trigger.name, depth, max_depth
+                ),
+            );
+        }
+
+        for func in collector.calls {
+            if self.actions.contains(func.name.as_str()) {
+                self.report(
+                    "action-in-condition",
+                    Some(index),
+                    format!(
+                        "condition of `{}` calls `{}`, which is used as an action",
+                        trigger.name, func
+                    ),
+                );
+            }
+        }
+
+        self.check_satisfiability(index, condition, format!("condition of `{}`", trigger.name));
+    }
+
+    // `what` names the condition in the message.
+    fn check_satisfiability(&mut self, trigger: usize, condition: &Expr, what: String) {
+        match check_condition(condition) {
+            result @ Satisfiability::Unsatisfiable { .. } => self.report(
+                "unsatisfiable-condition",
+                Some(trigger),
+                format!("{} is {}", what, result),
+            ),
+            result @ Satisfiability::AlwaysTrue { .. } => self.report(
+                "tautological-condition",
+                Some(trigger),
+                format!("{} is {}", what, result),
+            ),
+            Satisfiability::Satisfiable { .. } => {}
+        }
+    }
+
+    fn check_fields(&mut self) {
+        let mut uses: Vec<(usize, String)> = Vec::new();
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for (index, trigger) in self.file.triggers.iter().enumerate() {
+            let mut collector = FieldCollector::default();
+            collector.visit_trigger(trigger);
+            for field in collector.0 {
+                *counts.entry(field.clone()).or_default() += 1;
+                uses.push((index, field));
+            }
+        }
+
+        let known = &self.config.fields;
+        let mut reported = BTreeSet::new();
+        for (trigger, field) in &uses {
+            if !reported.insert((*trigger, field.clone())) {
+                continue;
+            }
+
+            if known.is_empty() {
+                // Without a schema, flag paths used once that are a typo
+                // away from a path used more often.
+                if counts[field] > 1 {
+                    continue;
+                }
+                let similar = counts
+                    .iter()
+                    .filter(|(other, count)| **count > 1 && is_typo(field, other))
+                    .max_by_key(|(_, count)| **count);
+                if let Some((other, _)) = similar {
+                    self.report(
+                        "unknown-field",
+                        Some(*trigger),
+                        format!(
+                            "field `{}` looks misspelled, did you mean `{}`?",
+                            field, other
+                        ),
+                    );
+                }
+            } else if !known.iter().any(|pattern| field_matches(pattern, field)) {
+                let suggestion = known
+                    .iter()
+                    .filter(|pattern| !pattern.ends_with(".*") && is_typo(field, pattern))
+                    .min_by_key(|pattern| edit_distance(field, pattern));
+                let message = match suggestion {
+                    Some(s) => format!("unknown field `{}`, did you mean `{}`?", field, s),
+                    None => format!("unknown field `{}`", field),
+                };
+                self.report("unknown-field", Some(*trigger), message);
+            }
+        }
+
+        for pattern in known {
+            if !counts.keys().any(|field| field_matches(pattern, field)) {
+                self.report(
+                    "unused-field",
+                    None,
+                    format!("field `{}` is never used", pattern),
+                );
+            }
+        }
+    }
+
+    fn check_overlaps(&mut self) {
+        for (first, second, overlap) in analysis::overlaps(self.file) {
+            let rule = match overlap.kind {
+                OverlapKind::Overlapping => "overlapping-triggers",
+                OverlapKind::Subsumed | OverlapKind::Equivalent => "subsumed-trigger",
+            };
+            self.report_related(rule, Some(first), vec![second], overlap.to_string());
+        }
+    }
+}
+
+#[derive(Default)]
+struct ConditionCollector {
+    bool_comparisons: Vec<(String, bool, ComparisonOp)>,
+    calls: Vec<FuncCall>,
+}
+
+impl Visitor for ConditionCollector {
+    fn visit_comparison(&mut self, comparison: &crate::Comparison) {
+        if let Value::Boolean(value) = comparison.right
+            && matches!(comparison.operator, ComparisonOp::Eq | ComparisonOp::Neq)
+            && let Some(field) = comparison.field()
+        {
+            self.bool_comparisons
+                .push((field.to_string(), value, comparison.operator));
+        }
+    }
+
+    fn visit_func_call(&mut self, func: &FuncCall) {
+        self.calls.push(func.clone());
+    }
+}
+
+#[derive(Default)]
+struct FieldCollector(Vec<String>);
+
+impl Visitor for FieldCollector {
+    fn visit_ident(&mut self, ident: &FieldPath) {
+        if !matches!(ident.as_name(), Some("true" | "false")) {
+            self.0.push(ident.to_string());
+        }
+    }
+}
+
+// The conditions of the `if` blocks in `actions`, nested ones included, in
+// source order.
+fn if_conditions(actions: &[Action]) -> Vec<&Expr> {
+    fn collect<'a>(actions: &'a [Action], conditions: &mut Vec<&'a Expr>) {
+        for action in actions {
+            if let Action::If {
+                condition,
+                then,
+                otherwise,
+            } = action
+            {
+                conditions.push(condition);
+                collect(then, conditions);
+                collect(otherwise, conditions);
+            }
+        }
+    }
+
+    let mut conditions = Vec::new();
+    collect(actions, &mut conditions);
+    conditions
+}
+
+// Chains of the same operator (`a && b && c`) count as one level;
+// parentheses do not count at all.
+fn nesting_depth(expr: &Expr) -> usize {
+    struct Depth {
+        current: usize,
+        max: usize,
+        parent: Option<&'static str>,
+    }
+
+    impl Visitor for Depth {
+        fn visit_expr(&mut self, expr: &Expr) {
+            let kind = match expr {
+                Expr::And(_, _) => Some("and"),
+                Expr::Or(_, _) => Some("or"),
+                Expr::Not(_) => Some("not"),
+                _ => None,
+            };
+            let Some(kind) = kind else {
+                walk_expr(self, expr);
+                return;
+            };
+
+            let nested = self.parent != Some(kind) || kind == "not";
+            let (saved_depth, saved_parent) = (self.current, self.parent);
+            if nested {
+                self.current += 1;
+                self.max = self.max.max(self.current);
+            }
+            self.parent = Some(kind);
+            walk_expr(self, expr);
+            self.current = saved_depth;
+            self.parent = saved_parent;
+        }
+    }
+
+    let mut depth = Depth {
+        current: 0,
+        max: 0,
+        parent: None,
+    };
+    depth.visit_expr(expr);
+    depth.max
+}
+
+fn field_matches(pattern: &str, field: &str) -> bool {
+    match pattern.strip_suffix(".*") {
+        Some(prefix) => field
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('.')),
+        None => pattern == field,
+    }
+}
+
+fn is_typo(a: &str, b: &str) -> bool {
+    a != b && edit_distance(a, b) <= 2
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+// Maps `triggers`, all parsed from `source` and given with their index in
+// the file, to the rules allowed for them by `// lint:allow(...)`.
+fn suppressions(source: &str, triggers: &[(usize, &Trigger)]) -> HashMap<usize, BTreeSet<String>> {
+    let mut allowed: HashMap<usize, BTreeSet<String>> = HashMap::new();
+
+    for (offset, rules) in allow_comments(source) {
+        let target = triggers
+            .iter()
+            .find(|(_, t)| t.span.start <= offset && offset < t.span.end)
+            .or_else(|| {
+                triggers
+                    .iter()
+                    .filter(|(_, t)| t.span.start >= offset)
+                    .min_by_key(|(_, t)| t.span.start)
+            });
+        if let Some((index, _)) = target {
+            allowed.entry(*index).or_default().extend(rules);
+        }
+    }
+
+    allowed
+}
+
+fn allow_comments(source: &str) -> Vec<(usize, Vec<String>)> {
+    let mut comments = Vec::new();
+    let mut in_string = false;
+    let bytes = source.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => in_string = !in_string,
+            b'/' if !in_string && bytes.get(i + 1) == Some(&b'/') => {
+                let end = source[i..].find('\n').map_or(source.len(), |n| i + n);
+                let text = source[i + 2..end].trim();
+                if let Some(list) = text
+                    .strip_prefix("lint:allow(")
+                    .and_then(|rest| rest.split_once(')'))
+                    .map(|(list, _)| list)
+                {
+                    let rules = list
+                        .split(',')
+                        .map(|rule| rule.trim().to_string())
+                        .filter(|rule| !rule.is_empty())
+                        .collect();
+                    comments.push((i, rules));
+                }
+                i = end;
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    comments
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Off => write!(f, "off"),
+            Level::Info => write!(f, "info"),
+            Level::Warning => write!(f, "warning"),
+            Level::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(span) = &self.span {
+            write!(f, "{}: ", span)?;
+        }
+        write!(f, "{}[{}]: {}", self.level, self.rule, self.message)
+    }
+}
@@ -1,7 +1,12 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use std::fs;
-use std::path::PathBuf;
-use triggerlang::{parse_triggers, parse_triggers_to_ast};
+use std::path::{Path, PathBuf};
+use triggerlang::engine::Engine;
+use triggerlang::lint::{Level, LintConfig, lint_project};
+use triggerlang::project::{load_filtered, load_project, load_project_with};
+use triggerlang::simplify::{simplify, to_cnf, to_dnf};
+use triggerlang::visit::{Visitor, walk_expr};
+use triggerlang::{Expr, ParseOptions, Trigger, TriggerFilter, parse_triggers_to_ast_with};
 
 #[derive(Parser)]
 #[command(name = "triggerlang")]
@@ -13,6 +18,27 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Args)]
+struct FilterArgs {
+    /// Only use triggers with one of these tags.
+    #[arg(long, value_delimiter = ',', value_name = "TAG")]
+    tags: Vec<String>,
+
+    /// Skip triggers with any of these tags.
+    #[arg(long, value_delimiter = ',', value_name = "TAG")]
+    exclude_tags: Vec<String>,
+}
+
+impl FilterArgs {
+    fn filter(self) -> TriggerFilter {
+        TriggerFilter {
+            tags: self.tags,
+            exclude_tags: self.exclude_tags,
+            include_disabled: false,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Parse {
@@ -24,16 +50,84 @@ enum Commands {
 
         #[arg(short, long)]
         verbose: bool,
+
+        #[arg(long)]
+        allow_duplicates: bool,
+
+        /// Leave out disabled triggers.
+        #[arg(long)]
+        enabled_only: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    Simplify {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(long, conflicts_with = "dnf")]
+        cnf: bool,
+
+        #[arg(long)]
+        dnf: bool,
+    },
+
+    Lint {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(short, long, value_name = "CONFIG")]
+        config: Option<PathBuf>,
+
+        #[arg(short, long, value_enum, default_value = "text")]
+        format: OutputFormat,
+    },
+
+    Explain {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[arg(short, long, value_name = "EVENT")]
+        event: Option<String>,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+
+    Tags {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
     },
 
     Credits,
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+const CONFIG_FILE: &str = "triggerlang.toml";
+
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Parse { file, ast, verbose } => {
+        Commands::Parse {
+            file,
+            ast,
+            verbose,
+            allow_duplicates,
+            enabled_only,
+            filter,
+        } => {
+            let mut filter = filter.filter();
+            filter.include_disabled = !enabled_only;
+            let mut options = ParseOptions::default();
+            options.allow_duplicates = allow_duplicates;
+
             if verbose {
                 println!("Parsing file...");
                 println!();
@@ -47,25 +141,29 @@ fn main() {
                         println!();
                     }
 
-                    match parse_triggers(&content) {
+                    match parse_triggers_to_ast_with(&content, &options) {
                         Ok(_) => {
                             println!("First syntax validation passed");
 
-                            match parse_triggers_to_ast(&content) {
+                            match load_filtered(&file, &options, &filter) {
                                 Ok(ast_tree) => {
                                     println!("Generated AST");
-                                    println!("Found {} triggers", ast_tree.triggers.len());
+                                    println!("Found {} triggers", ast_tree.triggers().len());
                                     println!();
 
                                     if ast {
                                         println!("AST tree");
                                         println!();
 
-                                        for (idx, trigger) in ast_tree.triggers.iter().enumerate() {
+                                        for (idx, trigger) in ast_tree.triggers().iter().enumerate()
+                                        {
                                             println!("Trigger #{}", idx + 1);
                                             println!("- Name: {}", trigger.name);
                                             println!("- Event: {}", trigger.event_type);
                                             println!("- Description: \"{}\"", trigger.description);
+                                            if !trigger.enabled {
+                                                println!("- Disabled");
+                                            }
                                             println!();
 
                                             if let Some(condition) = &trigger.condition {
@@ -79,16 +177,29 @@ fn main() {
                                             for action in trigger.actions.iter() {
                                                 println!(" - {}", action);
                                             }
+                                            if !trigger.else_actions.is_empty() {
+                                                println!(
+                                                    "- Else actions ({}):",
+                                                    trigger.else_actions.len()
+                                                );
+                                                for action in trigger.else_actions.iter() {
+                                                    println!(" - {}", action);
+                                                }
+                                            }
                                             println!();
                                         }
                                     } else {
                                         println!("Triggers found:");
-                                        for (idx, trigger) in ast_tree.triggers.iter().enumerate() {
+                                        for (idx, trigger) in ast_tree.triggers().iter().enumerate()
+                                        {
+                                            let disabled =
+                                                if trigger.enabled { "" } else { ", disabled" };
                                             println!(
-                                                "  {}. {} ({})",
+                                                "  {}. {} ({}{})",
                                                 idx + 1,
                                                 trigger.name,
-                                                trigger.event_type
+                                                trigger.event_type,
+                                                disabled
                                             );
                                         }
                                         println!();
@@ -116,6 +227,212 @@ fn main() {
             }
         }
 
+        Commands::Simplify { file, cnf, dnf } => {
+            let ast_tree = match load_project(&file) {
+                Ok(ast_tree) => ast_tree,
+                Err(e) => {
+                    eprintln!("Failed to load triggers");
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            for trigger in ast_tree.into_triggers() {
+                println!("Trigger {}", trigger.name);
+                match trigger.condition {
+                    Some(condition) => {
+                        println!("- Condition: {}", condition);
+                        let simplified = if cnf {
+                            to_cnf(condition)
+                        } else if dnf {
+                            to_dnf(condition)
+                        } else {
+                            simplify(condition)
+                        };
+                        println!("- Simplified: {}", simplified);
+                    }
+                    None => println!("- Condition: None"),
+                }
+                println!();
+            }
+        }
+
+        Commands::Lint {
+            file,
+            config,
+            format,
+        } => {
+            let mut options = ParseOptions::default();
+            options.allow_duplicates = true;
+            let ast_tree = match load_project_with(&file, &options) {
+                Ok(ast_tree) => ast_tree,
+                Err(e) => {
+                    eprintln!("Syntax validation failed");
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let config_path = config.or_else(|| {
+                let dir = file.parent().unwrap_or_else(|| Path::new("."));
+                [dir.join(CONFIG_FILE), PathBuf::from(CONFIG_FILE)]
+                    .into_iter()
+                    .find(|path| path.is_file())
+            });
+            let lint_config = match &config_path {
+                Some(path) => match fs::read_to_string(path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| LintConfig::from_toml(&text).map_err(|e| e.to_string()))
+                {
+                    Ok(lint_config) => lint_config,
+                    Err(e) => {
+                        eprintln!("Failed to load config: {}", path.display());
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                None => LintConfig::default(),
+            };
+
+            let diagnostics = match lint_project(&ast_tree, &lint_config) {
+                Ok(diagnostics) => diagnostics,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            match format {
+                OutputFormat::Json => match serde_json::to_string_pretty(&diagnostics) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                },
+                OutputFormat::Text => {
+                    for diagnostic in &diagnostics {
+                        let path = diagnostic.file.as_deref().unwrap_or(&file);
+                        println!("{}:{}", path.display(), diagnostic);
+                    }
+                    println!();
+                    println!("Found {} problems", diagnostics.len());
+                }
+            }
+
+            if diagnostics.iter().any(|d| d.level == Level::Error) {
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Explain {
+            file,
+            event,
+            filter,
+        } => {
+            let ast_tree = match load_filtered(&file, &ParseOptions::default(), &filter.filter()) {
+                Ok(ast_tree) => ast_tree,
+                Err(e) => {
+                    eprintln!("Failed to load triggers");
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let mut event_types: Vec<String> = Vec::new();
+            for trigger in ast_tree.triggers() {
+                for event_type in trigger.event_types() {
+                    if !event_types.iter().any(|known| known == event_type) {
+                        event_types.push(event_type.to_string());
+                    }
+                }
+            }
+            if let Some(event) = &event {
+                event_types.retain(|event_type| event_type == event);
+            }
+
+            let engine = Engine::new(ast_tree);
+            println!("Triggers run by descending priority; equal priorities run in file order.");
+            println!(
+                "When a trigger that stops propagation fires, the triggers after it do not run."
+            );
+            let describe = |i: usize, trigger: &Trigger| {
+                let schedule = match trigger.schedule {
+                    Some(_) => format!("{}, ", trigger.event_type),
+                    None => String::new(),
+                };
+                let stops = if trigger.stops_propagation() {
+                    ", stops propagation"
+                } else {
+                    ""
+                };
+                println!(
+                    "  {}. {} ({}priority {}{})",
+                    i + 1,
+                    trigger.name,
+                    schedule,
+                    trigger.priority,
+                    stops
+                );
+            };
+            for event_type in &event_types {
+                println!();
+                println!("{}:", event_type);
+                for (i, trigger) in engine.firing_order(event_type).enumerate() {
+                    describe(i, trigger);
+                }
+            }
+
+            // Scheduled triggers run on ticks rather than for events.
+            let scheduled: Vec<&Trigger> = engine.scheduled().collect();
+            if event.is_none() && !scheduled.is_empty() {
+                println!();
+                println!("scheduled, on each tick they are due:");
+                for (i, trigger) in scheduled.into_iter().enumerate() {
+                    describe(i, trigger);
+                }
+            }
+        }
+
+        Commands::Tags { file } => {
+            let ast_tree = match load_project(&file) {
+                Ok(ast_tree) => ast_tree,
+                Err(e) => {
+                    eprintln!("Failed to load triggers");
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let names = |triggers: &[&Trigger]| {
+                triggers
+                    .iter()
+                    .map(|trigger| trigger.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            for (tag, triggers) in ast_tree.tag_usage() {
+                println!("{} ({}): {}", tag, triggers.len(), names(&triggers));
+            }
+            let untagged: Vec<&Trigger> = ast_tree
+                .triggers()
+                .iter()
+                .filter(|trigger| trigger.tags.is_empty())
+                .collect();
+            if !untagged.is_empty() {
+                println!("untagged ({}): {}", untagged.len(), names(&untagged));
+            }
+            let disabled: Vec<&Trigger> = ast_tree
+                .triggers()
+                .iter()
+                .filter(|trigger| !trigger.enabled)
+                .collect();
+            if !disabled.is_empty() {
+                println!();
+                println!("disabled ({}): {}", disabled.len(), names(&disabled));
+            }
+        }
+
         Commands::Credits => {
             println!("triggerlang v1.0.0");
             println!("======");
@@ -142,39 +459,42 @@ fn main() {
     }
 }
 
-fn print_condition_tree(expr: &triggerlang::Expr, depth: usize) {
-    let indent = " ".repeat(depth);
+struct ConditionTreePrinter {
+    depth: usize,
+}
 
-    match expr {
-        triggerlang::Expr::And(left, right) => {
-            println!("{} AND", indent);
-            print_condition_tree(left, depth + 1);
-            print_condition_tree(right, depth + 1);
-        }
-        triggerlang::Expr::Or(left, right) => {
-            println!("{} OR", indent);
-            print_condition_tree(left, depth + 1);
-            print_condition_tree(right, depth + 1);
-        }
-        triggerlang::Expr::Not(inner) => {
-            println!("{} NOT", indent);
-            print_condition_tree(inner, depth + 1);
-        }
-        triggerlang::Expr::Comparison(comp) => {
-            println!(
-                "{} Comparison: {} {} {}",
-                indent, comp.left, comp.operator, comp.right
-            );
-        }
-        triggerlang::Expr::FuncCall(func) => {
-            println!("{} FuncCall: {}", indent, func);
-        }
-        triggerlang::Expr::Ident(id) => {
-            println!("{} Identifier: {}", indent, id);
-        }
-        triggerlang::Expr::Parenthesized(inner) => {
-            println!("{} Parenthesized:", indent);
-            print_condition_tree(inner, depth + 1);
+impl Visitor for ConditionTreePrinter {
+    fn visit_expr(&mut self, expr: &Expr) {
+        let indent = " ".repeat(self.depth);
+
+        match expr {
+            Expr::And(_, _) => println!("{} AND", indent),
+            Expr::Or(_, _) => println!("{} OR", indent),
+            Expr::Not(_) => println!("{} NOT", indent),
+            Expr::Parenthesized(_) => println!("{} Parenthesized:", indent),
+            Expr::Comparison(comp) => {
+                println!(
+                    "{} Comparison: {} {} {}",
+                    indent, comp.left, comp.operator, comp.right
+                );
+                return;
+            }
+            Expr::FuncCall(func) => {
+                println!("{} FuncCall: {}", indent, func);
+                return;
+            }
+            Expr::Ident(id) => {
+                println!("{} Identifier: {}", indent, id);
+                return;
+            }
         }
+
+        self.depth += 1;
+        walk_expr(self, expr);
+        self.depth -= 1;
     }
 }
+
+fn print_condition_tree(expr: &Expr, depth: usize) {
+    ConditionTreePrinter { depth }.visit_expr(expr);
+}
@@ -0,0 +1,354 @@
+//! Matching many triggers against an event at once.
+//!
+//! A [`Network`] merges the conditions of the triggers on each event type
+//! into one DAG. Equal comparisons, fields tested for `true` and `get()`
+//! calls become a single shared test, and equal subexpressions a single
+//! node, wherever they appear. Tests are the instructions of
+//! [`bytecode`](crate::bytecode) programs and run the same way. While an
+//! event is being handled, an [`Evaluation`] remembers the result of every
+//! node of its type's DAG it evaluated, so that each distinct comparison
+//! runs at most once per event however many triggers contain it.
+//!
+//! Nodes are evaluated on demand, in the order the conditions are written,
+//! so `&&` and `||` skip what they do not need just like the tree walker in
+//! [`engine::evaluate`](crate::engine::evaluate) and give the same results.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::bytecode::{Frame, Op, Pool, SlotTable};
+use crate::engine::{Event, History};
+use crate::limits::Budget;
+use crate::state::StateStore;
+use crate::{Expr, Trigger};
+
+type NodeId = u32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Node {
+    Const(bool),
+    // A comparison, call or identifier, run like a program's instruction.
+    Test(Op),
+    Not(NodeId),
+    And(Vec<NodeId>),
+    Or(Vec<NodeId>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Network {
+    // The conditions evaluated for each event type. A trigger is in the
+    // part of its event type, or of each type its pattern steps on.
+    parts: HashMap<String, Part>,
+    // For events no trigger runs on.
+    empty: Part,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Part {
+    slots: SlotTable,
+    pool: Pool,
+    nodes: Vec<Node>,
+    // The triggers that run on the type, in trigger order, with the node of
+    // their condition. Triggers without a condition have none and always
+    // match.
+    roots: Vec<(usize, Option<NodeId>)>,
+}
+
+impl Network {
+    /// Builds the network for `triggers`. Trigger indices in the results
+    /// refer to this slice.
+    pub fn new(triggers: &[Trigger]) -> Self {
+        let mut builders: HashMap<&str, Builder> = HashMap::new();
+        for (index, trigger) in triggers.iter().enumerate() {
+            let mut event_types: Vec<&str> = match &trigger.pattern {
+                Some(pattern) => pattern
+                    .steps
+                    .iter()
+                    .map(|step| step.event_type.as_str())
+                    .collect(),
+                None => vec![trigger.event_type.as_str()],
+            };
+            event_types.sort_unstable();
+            event_types.dedup();
+            for event_type in event_types {
+                let builder = builders.entry(event_type).or_default();
+                let root = trigger.condition.as_ref().map(|cond| builder.expr(cond));
+                builder.roots.push((index, root));
+            }
+        }
+        Network {
+            parts: builders
+                .into_iter()
+                .map(|(event_type, builder)| (event_type.to_string(), builder.finish()))
+                .collect(),
+            empty: Part::default(),
+        }
+    }
+
+    /// Starts evaluating conditions for `event`.
+    pub fn evaluation<'n>(&'n self, event: &'n Event) -> Evaluation<'n> {
+        let part = self.parts.get(&event.event_type).unwrap_or(&self.empty);
+        Evaluation {
+            part,
+            frame: Frame::new(&part.slots, event),
+            memo: vec![UNKNOWN; part.nodes.len()],
+            budget: None,
+        }
+    }
+
+    /// The triggers listening for the event's type whose condition holds,
+    /// in trigger order.
+    pub fn matching(&self, event: &Event, store: &dyn StateStore) -> Vec<usize> {
+        let mut evaluation = self.evaluation(event);
+        let part = evaluation.part;
+        part.roots
+            .iter()
+            .filter(|(_, root)| evaluation.root_holds(*root, store, None))
+            .map(|(index, _)| *index)
+            .collect()
+    }
+
+    /// Distinct comparisons and other tests in the network, counted once
+    /// per event type they are evaluated for.
+    pub fn test_count(&self) -> usize {
+        self.parts
+            .values()
+            .flat_map(|part| &part.nodes)
+            .filter(|node| matches!(node, Node::Test(_)))
+            .count()
+    }
+
+    /// Distinct nodes, tests included.
+    pub fn node_count(&self) -> usize {
+        self.parts.values().map(|part| part.nodes.len()).sum()
+    }
+}
+
+const UNKNOWN: u8 = 2;
+
+/// Node results for one event.
+pub struct Evaluation<'n> {
+    part: &'n Part,
+    frame: Frame<'n>,
+    memo: Vec<u8>,
+    budget: Option<&'n Budget>,
+}
+
+impl<'n> Evaluation<'n> {
+    /// Takes a step of `budget` for every node evaluated. Once it is spent,
+    /// conditions no longer hold.
+    pub(crate) fn with_budget(mut self, budget: &'n Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Whether the condition of the trigger at `index` holds. Triggers that
+    /// do not run on the event's type never hold.
+    pub fn holds(&mut self, index: usize, store: &dyn StateStore) -> bool {
+        self.holds_at(index, store, None)
+    }
+
+    pub(crate) fn holds_at(
+        &mut self,
+        index: usize,
+        store: &dyn StateStore,
+        history: Option<(&History, SystemTime)>,
+    ) -> bool {
+        let roots = &self.part.roots;
+        match roots.binary_search_by_key(&index, |(index, _)| *index) {
+            Ok(at) => self.root_holds(roots[at].1, store, history),
+            Err(_) => false,
+        }
+    }
+
+    /// Forgets the results so far, e.g. after state they may depend on
+    /// changed.
+    pub fn invalidate(&mut self) {
+        self.memo.fill(UNKNOWN);
+    }
+
+    fn root_holds(
+        &mut self,
+        root: Option<NodeId>,
+        store: &dyn StateStore,
+        history: Option<(&History, SystemTime)>,
+    ) -> bool {
+        match root {
+            Some(root) => self.eval(root, store, history),
+            None => true,
+        }
+    }
+
+    // Walks the DAG with an explicit stack, like the tree walker, so that
+    // deep conditions cannot overflow the thread's stack. `result` holds the
+    // value of the last node finished.
+    fn eval(
+        &mut self,
+        root: NodeId,
+        store: &dyn StateStore,
+        history: Option<(&History, SystemTime)>,
+    ) -> bool {
+        enum Task {
+            Eval(NodeId),
+            Not(NodeId),
+            // The index of the next operand of an `&&` or `||` node, to be
+            // evaluated unless the ones before decided the result.
+            Chain(NodeId, usize),
+        }
+
+        let part = self.part;
+        let mut result = true;
+        let mut tasks = vec![Task::Eval(root)];
+        while let Some(task) = tasks.pop() {
+            let id = match task {
+                Task::Eval(id) => {
+                    let known = self.memo[id as usize];
+                    if known != UNKNOWN {
+                        result = known == 1;
+                        continue;
+                    }
+                    if self.budget.is_some_and(|budget| !budget.step()) {
+                        result = false;
+                        continue;
+                    }
+                    match &part.nodes[id as usize] {
+                        Node::Const(b) => result = *b,
+                        Node::Test(op) => {
+                            result = part
+                                .pool
+                                .test(*op, &self.frame, store, history, self.budget)
+                        }
+                        Node::Not(inner) => {
+                            tasks.extend([Task::Not(id), Task::Eval(*inner)]);
+                            continue;
+                        }
+                        Node::And(_) | Node::Or(_) => {
+                            tasks.push(Task::Chain(id, 0));
+                            continue;
+                        }
+                    }
+                    id
+                }
+                Task::Not(id) => {
+                    result = !result;
+                    id
+                }
+                Task::Chain(id, next) => {
+                    let (children, and) = match &part.nodes[id as usize] {
+                        Node::And(children) => (children, true),
+                        Node::Or(children) => (children, false),
+                        _ => unreachable!("not an operator"),
+                    };
+                    let decided = next > 0 && result != and;
+                    if !decided {
+                        match children.get(next) {
+                            Some(child) => {
+                                tasks.extend([Task::Chain(id, next + 1), Task::Eval(*child)]);
+                                continue;
+                            }
+                            None => result = and,
+                        }
+                    }
+                    id
+                }
+            };
+            self.memo[id as usize] = u8::from(result);
+        }
+        result
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    slots: SlotTable,
+    pool: Pool,
+    nodes: Vec<Node>,
+    node_ids: HashMap<Node, NodeId>,
+    roots: Vec<(usize, Option<NodeId>)>,
+}
+
+impl Builder {
+    fn finish(self) -> Part {
+        Part {
+            slots: self.slots,
+            pool: self.pool,
+            nodes: self.nodes,
+            roots: self.roots,
+        }
+    }
+
+    // Builds the nodes bottom up from an explicit stack, so that deep
+    // conditions cannot overflow the thread's stack. `ids` holds the nodes
+    // of the operands finished so far.
+    fn expr(&mut self, expr: &Expr) -> NodeId {
+        enum Task<'e> {
+            Visit(&'e Expr),
+            Not,
+            // An `&&` (or `||`) of that many operands.
+            Chain(bool, usize),
+        }
+
+        let mut ids = Vec::new();
+        let mut tasks = vec![Task::Visit(expr)];
+        while let Some(task) = tasks.pop() {
+            let node = match task {
+                Task::Visit(Expr::Parenthesized(inner)) => {
+                    tasks.push(Task::Visit(inner));
+                    continue;
+                }
+                Task::Visit(Expr::Not(inner)) => {
+                    tasks.extend([Task::Not, Task::Visit(inner)]);
+                    continue;
+                }
+                Task::Visit(chain @ (Expr::And(..) | Expr::Or(..))) => {
+                    let and = matches!(chain, Expr::And(..));
+                    let operands = operands(chain, and);
+                    tasks.push(Task::Chain(and, operands.len()));
+                    tasks.extend(operands.into_iter().rev().map(Task::Visit));
+                    continue;
+                }
+                Task::Visit(leaf) => match self.pool.leaf(leaf, &mut self.slots) {
+                    Op::Push(b) => Node::Const(b),
+                    op => Node::Test(op),
+                },
+                Task::Not => Node::Not(ids.pop().expect("operand built")),
+                Task::Chain(and, len) => {
+                    let children = ids.split_off(ids.len() - len);
+                    if and {
+                        Node::And(children)
+                    } else {
+                        Node::Or(children)
+                    }
+                }
+            };
+            ids.push(self.intern(node));
+        }
+        ids.pop().expect("expression built")
+    }
+
+    fn intern(&mut self, node: Node) -> NodeId {
+        if let Some(id) = self.node_ids.get(&node) {
+            return *id;
+        }
+        let id = self.nodes.len() as NodeId;
+        self.nodes.push(node.clone());
+        self.node_ids.insert(node, id);
+        id
+    }
+}
+
+// The operands of a chain of `&&` (or `||`), parentheses included, so that
+// `a && (b && c)` and `(a && b) && c` share a node.
+fn operands(chain: &Expr, and: bool) -> Vec<&Expr> {
+    let mut operands = Vec::new();
+    let mut stack = vec![chain];
+    while let Some(expr) = stack.pop() {
+        match expr {
+            Expr::And(left, right) if and => stack.extend([&**right, &**left]),
+            Expr::Or(left, right) if !and => stack.extend([&**right, &**left]),
+            Expr::Parenthesized(inner) => stack.push(inner),
+            other => operands.push(other),
+        }
+    }
+    operands
+}
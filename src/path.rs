@@ -0,0 +1,253 @@
+//! Field paths such as `message.sender.is_admin`.
+//!
+//! Identifiers are parsed once, with the trigger file, into a [`FieldPath`]
+//! of dot-separated segments. Segments are interned [`Symbol`]s: every
+//! occurrence of a name shares one allocation for the life of the process,
+//! so paths are cheap to clone and segments compare by address.
+//!
+//! Only the names in trigger files are interned; event payloads keep their
+//! keys as strings, so events cannot grow the interner. As interned names
+//! are never freed, parsing a file fails once its new names would take the
+//! interner past [`Limits::max_interned_bytes`], however many files were
+//! parsed before.
+//!
+//! A path of a single segment is a bare name, such as a template parameter
+//! or `true`; [`FieldPath::as_name`] tells the two apart without looking at
+//! the text.
+//!
+//! [`Limits::max_interned_bytes`]: crate::Limits::max_interned_bytes
+//!
+//! Paths are looked up in an [`EventPayload`](crate::payload::EventPayload)
+//! segment by segment.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+
+/// An interned name. Interned names are never freed; there is one per
+/// distinct segment ever parsed, not one per occurrence.
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static str);
+
+#[derive(Default)]
+struct Interner {
+    symbols: HashSet<&'static str>,
+    // The length of every symbol, added up.
+    bytes: usize,
+}
+
+fn interner() -> MutexGuard<'static, Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Bytes taken by the names interned so far, in total.
+pub fn interned_bytes() -> usize {
+    interner().bytes
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        Symbol::intern_within(name, usize::MAX).expect("no limit")
+    }
+
+    // Interns `name` unless it is new and would take the interned names past
+    // `limit` bytes.
+    pub(crate) fn intern_within(name: &str, limit: usize) -> Option<Symbol> {
+        let mut interner = interner();
+        if let Some(symbol) = interner.symbols.get(name) {
+            return Some(Symbol(symbol));
+        }
+        let bytes = interner.bytes.checked_add(name.len())?;
+        if bytes > limit {
+            return None;
+        }
+        let symbol: &'static str = Box::leak(name.into());
+        interner.symbols.insert(symbol);
+        interner.bytes = bytes;
+        Some(Symbol(symbol))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+// Interning makes equal names the same allocation.
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+// By text, so that maps keyed by symbols can be searched with a `&str`.
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else {
+            self.0.cmp(other.0)
+        }
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl Borrow<str> for Symbol {
+    fn borrow(&self) -> &str {
+        self.0
+    }
+}
+
+impl std::fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// A dotted identifier, split into its segments.
+///
+/// Paths order like their text, since `.` sorts before every other
+/// character an identifier may contain.
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FieldPath {
+    segments: Box<[Symbol]>,
+}
+
+impl FieldPath {
+    pub fn parse(path: &str) -> FieldPath {
+        FieldPath {
+            segments: path.split('.').map(Symbol::intern).collect(),
+        }
+    }
+
+    pub fn segments(&self) -> &[Symbol] {
+        &self.segments
+    }
+
+    /// The name, if the path is a single segment.
+    pub fn as_name(&self) -> Option<&'static str> {
+        match *self.segments {
+            [name] => Some(name.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Whether the path starts with the segment `first`, e.g. `state`.
+    pub fn starts_with(&self, first: &str) -> bool {
+        self.segments
+            .first()
+            .is_some_and(|segment| *segment == first)
+    }
+
+    /// The path without its first segment, as text.
+    pub fn rest(&self) -> Option<String> {
+        match &*self.segments {
+            [_, rest @ ..] if !rest.is_empty() => Some(join(rest)),
+            _ => None,
+        }
+    }
+
+    /// Length of the path as written.
+    pub fn text_len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|s| s.as_str().len())
+            .sum::<usize>()
+            + self.segments.len().saturating_sub(1)
+    }
+}
+
+fn join(segments: &[Symbol]) -> String {
+    let mut text = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            text.push('.');
+        }
+        text.push_str(segment.as_str());
+    }
+    text
+}
+
+impl From<&str> for FieldPath {
+    fn from(path: &str) -> Self {
+        FieldPath::parse(path)
+    }
+}
+
+impl From<String> for FieldPath {
+    fn from(path: String) -> Self {
+        FieldPath::parse(&path)
+    }
+}
+
+impl PartialEq<str> for FieldPath {
+    fn eq(&self, other: &str) -> bool {
+        self.segments.len() == other.split('.').count()
+            && self
+                .segments
+                .iter()
+                .zip(other.split('.'))
+                .all(|(a, b)| *a == b)
+    }
+}
+
+impl PartialEq<&str> for FieldPath {
+    fn eq(&self, other: &&str) -> bool {
+        self == *other
+    }
+}
+
+impl std::fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(segment.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+// As the text, like the `String` paths used to be.
+impl std::fmt::Debug for FieldPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
@@ -0,0 +1,100 @@
+//! Event data as nested maps, lists and scalars.
+//!
+//! An [`EventPayload`] holds an event's fields the way game servers
+//! usually send them: `{"message": {"sender": {"is_admin": true}}}` rather
+//! than flat dotted keys. A [`FieldPath`] is looked up one segment at a
+//! time, by map key or, for lists, by index (`message.mentions.0`), and the
+//! result borrows from the payload. Keys are plain strings: they come from
+//! whoever sends the events, so they are not interned like the names in
+//! trigger files.
+
+use std::collections::BTreeMap;
+
+use crate::Value;
+use crate::path::FieldPath;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventPayload {
+    /// A boolean, number or string.
+    Value(Value),
+    Map(BTreeMap<String, EventPayload>),
+    List(Vec<EventPayload>),
+}
+
+impl Default for EventPayload {
+    fn default() -> Self {
+        EventPayload::Map(BTreeMap::new())
+    }
+}
+
+impl EventPayload {
+    /// The part of the payload at `path`.
+    pub fn get(&self, path: &FieldPath) -> Option<&EventPayload> {
+        self.descend(path.segments().iter().map(|segment| segment.as_str()))
+    }
+
+    /// Like [`get`](EventPayload::get), for a path as written.
+    pub fn get_str(&self, path: &str) -> Option<&EventPayload> {
+        self.descend(path.split('.'))
+    }
+
+    fn descend<'s>(&self, segments: impl Iterator<Item = &'s str>) -> Option<&EventPayload> {
+        let mut node = self;
+        for segment in segments {
+            node = match node {
+                EventPayload::Map(map) => map.get(segment)?,
+                EventPayload::List(items) => items.get(segment.parse::<usize>().ok()?)?,
+                EventPayload::Value(_) => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// The scalar, if the payload is one.
+    pub fn as_value(&self) -> Option<&Value> {
+        match self {
+            EventPayload::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Puts `payload` at the dotted `path`, creating maps for missing
+    /// segments and replacing scalars in the way.
+    pub fn insert(&mut self, path: &str, payload: EventPayload) {
+        let mut node = self;
+        for segment in path.split('.') {
+            let index = match &*node {
+                EventPayload::List(items) => segment
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|index| *index < items.len()),
+                _ => None,
+            };
+            node = match (index, node) {
+                (Some(index), EventPayload::List(items)) => &mut items[index],
+                (_, node) => {
+                    if !matches!(node, EventPayload::Map(_)) {
+                        *node = EventPayload::default();
+                    }
+                    let EventPayload::Map(map) = node else {
+                        unreachable!("just made a map");
+                    };
+                    map.entry(segment.to_string()).or_default()
+                }
+            };
+        }
+        *node = payload;
+    }
+}
+
+impl From<Value> for EventPayload {
+    fn from(value: Value) -> Self {
+        EventPayload::Value(value)
+    }
+}
+
+impl From<Vec<EventPayload>> for EventPayload {
+    fn from(items: Vec<EventPayload>) -> Self {
+        EventPayload::List(items)
+    }
+}
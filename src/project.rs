@@ -0,0 +1,233 @@
+//! Loading trigger files that `import` each other.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    Definitions, Location, ParseOptions, Trigger, TriggerFile, TriggerFilter, TriggerParserError,
+    build_file, check_unique_names, file_imports, limits, parse_file_pair,
+};
+
+/// Loads `root` and every file it imports, directly or transitively, into
+/// one [`TriggerFile`]. Import paths are relative to the importing file.
+/// Imported triggers are spliced in where their `import` appears, each file
+/// is loaded once, and every trigger's `origin` is set to its file. A file
+/// can use the named conditions and templates of the files it imports,
+/// directly or transitively, but not those of other files in the project.
+pub fn load_project(root: &Path) -> Result<TriggerFile, TriggerParserError> {
+    load_project_with(root, &ParseOptions::default())
+}
+
+pub fn load_project_with(
+    root: &Path,
+    options: &ParseOptions,
+) -> Result<TriggerFile, TriggerParserError> {
+    let root = root
+        .canonicalize()
+        .map_err(|e| TriggerParserError::IoError {
+            path: root.to_path_buf(),
+            message: e.to_string(),
+        })?;
+
+    let mut loader = Loader {
+        stack: Vec::new(),
+        loaded: HashSet::new(),
+        triggers: Vec::new(),
+        loaded_triggers: 0,
+        loaded_nodes: 0,
+        imports: HashMap::new(),
+        definitions: Vec::new(),
+        options,
+    };
+    loader.load(&root)?;
+
+    if !options.allow_duplicates {
+        check_unique_names(&loader.triggers)?;
+    }
+
+    let mut conditions = Vec::new();
+    let mut templates = Vec::new();
+    for (_, definitions) in loader.definitions {
+        conditions.extend(definitions.conditions);
+        templates.extend(definitions.templates);
+    }
+    Ok(TriggerFile::new(
+        loader.triggers,
+        Vec::new(),
+        conditions,
+        templates,
+    ))
+}
+
+/// Loads the project like [`load_project_with`] and keeps only the triggers
+/// matching `filter`, e.g. those enabled and tagged for this environment.
+pub fn load_filtered(
+    root: &Path,
+    options: &ParseOptions,
+    filter: &TriggerFilter,
+) -> Result<TriggerFile, TriggerParserError> {
+    Ok(load_project_with(root, options)?.filtered(filter))
+}
+
+struct Loader<'o> {
+    // Files currently being loaded, outermost first.
+    stack: Vec<PathBuf>,
+    loaded: HashSet<PathBuf>,
+    triggers: Vec<Trigger>,
+    // Triggers loaded so far, including those set aside to be spliced in.
+    loaded_triggers: usize,
+    // Their condition and action nodes, as counted against `max_nodes`.
+    loaded_nodes: usize,
+    // The files each loaded file imports directly.
+    imports: HashMap<PathBuf, Vec<PathBuf>>,
+    // The named conditions and templates of each loaded file, in the order
+    // the files finished loading.
+    definitions: Vec<(PathBuf, Definitions)>,
+    options: &'o ParseOptions,
+}
+
+impl Loader<'_> {
+    fn load(&mut self, path: &Path) -> Result<(), TriggerParserError> {
+        let io_error = |e: std::io::Error| TriggerParserError::IoError {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        };
+        let in_file = |e| TriggerParserError::InFile {
+            path: path.to_path_buf(),
+            source: Box::new(e),
+        };
+        // Checked before reading, so that huge files are not read at all.
+        let limit = self.options.limits.max_file_size;
+        let size = fs::metadata(path).map_err(io_error)?.len();
+        if size > limit as u64 {
+            return Err(in_file(TriggerParserError::FileTooLarge {
+                size: usize::try_from(size).unwrap_or(usize::MAX),
+                limit,
+            }));
+        }
+        let source = fs::read_to_string(path).map_err(io_error)?;
+        let file = parse_file_pair(&source, &self.options.limits).map_err(in_file)?;
+
+        self.stack.push(path.to_path_buf());
+        self.loaded.insert(path.to_path_buf());
+
+        // Imports are loaded before the file itself is built, so that their
+        // named conditions and templates are in scope. Their triggers are kept aside and
+        // spliced in where the import appears.
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut imported = Vec::new();
+        let mut targets = Vec::new();
+        for import in file_imports(&file) {
+            let location = Location {
+                file: Some(path.to_path_buf()),
+                span: import.span,
+            };
+            let target = dir.join(&import.path).canonicalize().map_err(|e| {
+                TriggerParserError::ImportError {
+                    location: location.clone(),
+                    path: PathBuf::from(&import.path),
+                    message: e.to_string(),
+                }
+            })?;
+
+            if let Some(start) = self.stack.iter().position(|p| *p == target) {
+                let mut chain = self.stack[start..].to_vec();
+                chain.push(target);
+                return Err(TriggerParserError::ImportCycle { location, chain });
+            }
+            if !self.loaded.contains(&target) {
+                let before = self.triggers.len();
+                self.load(&target)?;
+                imported.push((import.span.start, self.triggers.split_off(before)));
+            }
+            targets.push(target);
+        }
+        let visible = self.visible(&targets);
+        self.imports.insert(path.to_path_buf(), targets);
+
+        // Duplicates are checked once the whole project is merged.
+        let options = ParseOptions {
+            allow_duplicates: true,
+            ..self.options.clone()
+        };
+        let parsed = build_file(file, &options, &visible).map_err(in_file)?;
+
+        let mut imported = imported.into_iter().peekable();
+        for trigger in parsed.triggers {
+            while let Some((_, triggers)) = imported.next_if(|(at, _)| *at < trigger.span.start) {
+                self.triggers.extend(triggers);
+            }
+            self.push(trigger, path).map_err(in_file)?;
+        }
+        for (_, triggers) in imported {
+            self.triggers.extend(triggers);
+        }
+        let mut definitions = Definitions::default();
+        for mut condition in parsed.conditions {
+            condition.origin = Some(path.to_path_buf());
+            definitions.conditions.push(condition);
+        }
+        for mut template in parsed.templates {
+            template.body.origin = Some(path.to_path_buf());
+            definitions.templates.push(template);
+        }
+        self.definitions.push((path.to_path_buf(), definitions));
+
+        self.stack.pop();
+        Ok(())
+    }
+
+    // The definitions of `targets` and of every file they import.
+    fn visible(&self, targets: &[PathBuf]) -> Definitions {
+        let mut reachable: HashSet<&Path> = HashSet::new();
+        let mut pending: Vec<&Path> = targets.iter().map(PathBuf::as_path).collect();
+        while let Some(path) = pending.pop() {
+            if reachable.insert(path) {
+                pending.extend(
+                    self.imports
+                        .get(path)
+                        .into_iter()
+                        .flatten()
+                        .map(PathBuf::as_path),
+                );
+            }
+        }
+
+        let mut visible = Definitions::default();
+        for (path, definitions) in &self.definitions {
+            if reachable.contains(path.as_path()) {
+                visible
+                    .conditions
+                    .extend(definitions.conditions.iter().cloned());
+                visible
+                    .templates
+                    .extend(definitions.templates.iter().cloned());
+            }
+        }
+        visible
+    }
+
+    fn push(&mut self, mut trigger: Trigger, path: &Path) -> Result<(), TriggerParserError> {
+        let limit = self.options.limits.max_triggers;
+        if self.loaded_triggers == limit {
+            return Err(TriggerParserError::TooManyTriggers {
+                limit,
+                span: trigger.span,
+            });
+        }
+        let nodes = limits::trigger_nodes(&trigger);
+        let limit = self.options.limits.max_nodes;
+        if nodes > limit - self.loaded_nodes {
+            return Err(TriggerParserError::TooManyNodes {
+                limit,
+                span: trigger.span,
+            });
+        }
+        self.loaded_triggers += 1;
+        self.loaded_nodes += nodes;
+        trigger.origin = Some(path.to_path_buf());
+        self.triggers.push(trigger);
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Time-based triggers: `on: every 10m` and `on: schedule("0 */2 * * *")`.
+//!
+//! Cron expressions have the usual five fields (minute, hour, day of month,
+//! month, day of week) and are evaluated in UTC. Each field accepts `*`,
+//! numbers, ranges (`1-5`), lists (`1,15`) and steps (`*/15`, `0-30/10`).
+//! Day of week runs from 0 (Sunday) to 6; 7 is accepted for Sunday too. As
+//! in classic cron, when both day fields are restricted a time matches if
+//! either does; a field starting with `*`, such as `*/2`, is not restricted.
+//! Expressions that can never match, such as `0 0 30 2 *`, are rejected.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::format_duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// The first time the schedule is due strictly after `after`. For
+    /// `every`, intervals are counted from `start`, usually when the engine
+    /// was created.
+    pub fn next_after(&self, start: SystemTime, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Every(period) => {
+                let elapsed = after.duration_since(start).unwrap_or_default();
+                let periods = elapsed.as_nanos() / period.as_nanos() + 1;
+                let nanos = periods.checked_mul(period.as_nanos())?;
+                let offset = Duration::new(
+                    u64::try_from(nanos / 1_000_000_000).ok()?,
+                    (nanos % 1_000_000_000) as u32,
+                );
+                start.checked_add(offset)
+            }
+            Schedule::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Every(period) => write!(f, "every {}", format_duration(*period)),
+            Schedule::Cron(cron) => write!(f, "schedule(\"{}\")", cron),
+        }
+    }
+}
+
+/// A parsed five-field cron expression. Each field is a bit set of the
+/// values it matches.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+// Name, lowest and highest value of each field.
+const FIELDS: [(&str, u32, u32); 5] = [
+    ("minute", 0, 59),
+    ("hour", 0, 23),
+    ("day of month", 1, 31),
+    ("month", 1, 12),
+    ("day of week", 0, 7),
+];
+
+impl Cron {
+    pub fn parse(expr: &str) -> Result<Cron, String> {
+        let parts: Vec<&str> = expr.split_whitespace().collect();
+        if parts.len() != 5 {
+            return Err(format!("expected 5 fields, found {}", parts.len()));
+        }
+
+        let mut sets = [0u64; 5];
+        for ((part, set), (name, low, high)) in parts.iter().zip(&mut sets).zip(FIELDS) {
+            *set = parse_field(part, low, high).map_err(|e| format!("{}: {}", name, e))?;
+        }
+        // Sunday can be written as 0 or 7.
+        if sets[4] & (1 << 7) != 0 {
+            sets[4] |= 1;
+        }
+
+        let cron = Cron {
+            source: parts.join(" "),
+            minutes: sets[0],
+            hours: sets[1],
+            days: sets[2],
+            months: sets[3],
+            weekdays: sets[4],
+            days_restricted: !parts[2].starts_with('*'),
+            weekdays_restricted: !parts[4].starts_with('*'),
+        };
+        // Every month has every weekday, so only a day of month on its own
+        // can rule out all the months.
+        let days_alone = cron.days_restricted && !cron.weekdays_restricted;
+        let possible = (1..=12).any(|month| {
+            has(cron.months, month) && (1..=days_in_month(month)).any(|day| has(cron.days, day))
+        });
+        if days_alone && !possible {
+            return Err("day of month: none of the days occurs in the given months".to_string());
+        }
+        Ok(cron)
+    }
+
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let t = DateTime::from_system_time(time);
+        self.matches_day(&t) && has(self.hours, t.hour) && has(self.minutes, t.minute)
+    }
+
+    fn matches_day(&self, t: &DateTime) -> bool {
+        let day = has(self.days, t.day);
+        let weekday = has(self.weekdays, t.weekday);
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.months, t.month) && day_matches
+    }
+
+    /// The first matching minute strictly after `after`, looking at most
+    /// five years ahead.
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let secs = after.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let mut minute = secs / 60 + 1;
+        let limit = minute + 5 * 366 * 24 * 60;
+
+        while minute < limit {
+            let time = UNIX_EPOCH + Duration::from_secs(minute * 60);
+            let t = DateTime::from_system_time(time);
+            if !self.matches_day(&t) {
+                minute += 24 * 60 - u64::from(t.hour * 60 + t.minute);
+            } else if !has(self.hours, t.hour) {
+                minute += 60 - u64::from(t.minute);
+            } else if !has(self.minutes, t.minute) {
+                minute += 1;
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for Cron {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+// The most days `month` has, in leap years for February.
+fn days_in_month(month: u32) -> u32 {
+    match month {
+        2 => 29,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse_field(field: &str, low: u32, high: u32) -> Result<u64, String> {
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, parse_number(step)?),
+            None => (item, 1),
+        };
+        if step == 0 {
+            return Err("step must be positive".to_string());
+        }
+        let (start, end) = match range {
+            "*" => (low, high),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse_number(start)?, parse_number(end)?),
+                None => {
+                    let value = parse_number(range)?;
+                    // `5/10` means from 5 to the end in steps of 10.
+                    (value, if item.contains('/') { high } else { value })
+                }
+            },
+        };
+        if start < low || end > high || start > end {
+            return Err(format!("'{}' is outside {}-{}", item, low, high));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    s.parse().map_err(|_| format!("'{}' is not a number", s))
+}
+
+// A UTC calendar time, to the minute.
+struct DateTime {
+    month: u32,
+    day: u32,
+    weekday: u32,
+    hour: u32,
+    minute: u32,
+}
+
+impl DateTime {
+    fn from_system_time(time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let days = secs / 86_400;
+        let secs_of_day = secs % 86_400;
+
+        // Civil-from-days, after Howard Hinnant's algorithm. The year is not
+        // needed, so neither is the era.
+        let z = days + 719_468;
+        let day_of_era = z % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+        DateTime {
+            month: month as u32,
+            day: day as u32,
+            // 1970-01-01 was a Thursday.
+            weekday: ((days + 4) % 7) as u32,
+            hour: (secs_of_day / 3_600) as u32,
+            minute: (secs_of_day % 3_600 / 60) as u32,
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Boolean simplification and normal forms for trigger conditions.
+//!
+//! The literals `true` and `false` are parsed as [`Expr::Ident`], so that is
+//! also how constant results are represented here.
+
+use crate::{Comparison, ComparisonOp, Expr, FieldPath, Value};
+
+/// Rewrites `expr` into an equivalent, smaller condition:
+///
+/// - parentheses are dropped and negations are pushed down to the leaves
+///   (double negations cancel, `!(a && b)` becomes `!a || !b`);
+/// - `x == true` becomes `x`;
+/// - nested `&&`/`||` chains are flattened, duplicates removed and constant
+///   operands folded away.
+///
+/// Equivalent means under the engine, where a comparison with a missing
+/// field never holds and `!=` holds between values of different types. So
+/// negated comparisons keep their `!` (`!(x > 1)` also holds without `x`,
+/// `x <= 1` does not), and `x == false` is not `!x`.
+pub fn simplify(expr: Expr) -> Expr {
+    flatten(nnf(expr, false))
+}
+
+/// Conjunctive normal form: an AND of ORs of literals.
+///
+/// Distribution is exponential in the worst case, so this is meant for the
+/// short conditions found in trigger files.
+pub fn to_cnf(expr: Expr) -> Expr {
+    let clauses = distribute(simplify(expr), true);
+    let clauses = clauses
+        .into_iter()
+        .map(|clause| join(clause, false))
+        .collect();
+    flatten(join(clauses, true))
+}
+
+/// Disjunctive normal form: an OR of ANDs of literals.
+pub fn to_dnf(expr: Expr) -> Expr {
+    let terms = dnf_terms(expr);
+    let terms = terms.into_iter().map(|term| join(term, true)).collect();
+    flatten(join(terms, false))
+}
+
+/// The terms of the disjunctive normal form of `expr`, each a list of
+/// literals that must all hold.
+pub(crate) fn dnf_terms(expr: Expr) -> Vec<Vec<Expr>> {
+    distribute(simplify(expr), false)
+}
+
+pub(crate) fn constant(value: bool) -> Expr {
+    Expr::Ident(FieldPath::parse(if value { "true" } else { "false" }))
+}
+
+pub(crate) fn as_constant(expr: &Expr) -> Option<bool> {
+    match expr {
+        Expr::Ident(id) if id == "true" => Some(true),
+        Expr::Ident(id) if id == "false" => Some(false),
+        _ => None,
+    }
+}
+
+pub(crate) fn negate_op(op: &ComparisonOp) -> ComparisonOp {
+    match op {
+        ComparisonOp::Eq => ComparisonOp::Neq,
+        ComparisonOp::Neq => ComparisonOp::Eq,
+        ComparisonOp::Gt => ComparisonOp::Lte,
+        ComparisonOp::Lte => ComparisonOp::Gt,
+        ComparisonOp::Lt => ComparisonOp::Gte,
+        ComparisonOp::Gte => ComparisonOp::Lt,
+    }
+}
+
+fn literal(expr: Expr, negated: bool) -> Expr {
+    if negated {
+        Expr::Not(Box::new(expr))
+    } else {
+        expr
+    }
+}
+
+fn nnf(expr: Expr, negated: bool) -> Expr {
+    match expr {
+        Expr::Parenthesized(inner) => nnf(*inner, negated),
+        Expr::Not(inner) => nnf(*inner, !negated),
+        Expr::And(left, right) if negated => {
+            Expr::Or(Box::new(nnf(*left, true)), Box::new(nnf(*right, true)))
+        }
+        Expr::Or(left, right) if negated => {
+            Expr::And(Box::new(nnf(*left, true)), Box::new(nnf(*right, true)))
+        }
+        Expr::And(left, right) => {
+            Expr::And(Box::new(nnf(*left, false)), Box::new(nnf(*right, false)))
+        }
+        Expr::Or(left, right) => {
+            Expr::Or(Box::new(nnf(*left, false)), Box::new(nnf(*right, false)))
+        }
+        Expr::Comparison(comp) => nnf_comparison(comp, negated),
+        Expr::Ident(id) => match id.as_name() {
+            Some("true") => constant(!negated),
+            Some("false") => constant(negated),
+            _ => literal(Expr::Ident(id), negated),
+        },
+        Expr::FuncCall(func) => literal(Expr::FuncCall(func), negated),
+    }
+}
+
+// Only `x == true` has a shorter equivalent: both hold exactly when `x` is
+// `true`. A field named `true` or `false` is not the constant, though.
+fn nnf_comparison(comp: Comparison, negated: bool) -> Expr {
+    match (&comp.left, &comp.operator, &comp.right) {
+        (Value::Ident(field), ComparisonOp::Eq, Value::Boolean(true))
+            if !matches!(field.as_name(), Some("true" | "false")) =>
+        {
+            literal(Expr::Ident(field.clone()), negated)
+        }
+        _ => literal(Expr::Comparison(comp), negated),
+    }
+}
+
+fn flatten(expr: Expr) -> Expr {
+    match expr {
+        Expr::And(_, _) => {
+            let mut operands = Vec::new();
+            collect(expr, true, &mut operands);
+            fold_operands(operands, true)
+        }
+        Expr::Or(_, _) => {
+            let mut operands = Vec::new();
+            collect(expr, false, &mut operands);
+            fold_operands(operands, false)
+        }
+        other => other,
+    }
+}
+
+fn collect(expr: Expr, conjunction: bool, out: &mut Vec<Expr>) {
+    match expr {
+        Expr::And(left, right) if conjunction => {
+            collect(*left, conjunction, out);
+            collect(*right, conjunction, out);
+        }
+        Expr::Or(left, right) if !conjunction => {
+            collect(*left, conjunction, out);
+            collect(*right, conjunction, out);
+        }
+        other => out.push(flatten(other)),
+    }
+}
+
+// `conjunction` selects AND (identity `true`, absorbing `false`) or OR (the
+// reverse). Operands may themselves be chains of the same kind after
+// flattening their children, so they are spliced in.
+fn fold_operands(operands: Vec<Expr>, conjunction: bool) -> Expr {
+    let mut kept: Vec<Expr> = Vec::new();
+
+    for operand in operands {
+        match as_constant(&operand) {
+            Some(value) if value == conjunction => continue,
+            Some(_) => return constant(!conjunction),
+            None => {}
+        }
+
+        let mut spliced = Vec::new();
+        match operand {
+            Expr::And(_, _) if conjunction => collect(operand, true, &mut spliced),
+            Expr::Or(_, _) if !conjunction => collect(operand, false, &mut spliced),
+            other => spliced.push(other),
+        }
+        for expr in spliced {
+            if !kept.contains(&expr) {
+                kept.push(expr);
+            }
+        }
+    }
+
+    join(kept, conjunction)
+}
+
+fn join(operands: Vec<Expr>, conjunction: bool) -> Expr {
+    let mut operands = operands.into_iter().rev();
+    let Some(mut result) = operands.next() else {
+        return constant(conjunction);
+    };
+
+    for operand in operands {
+        result = if conjunction {
+            Expr::And(Box::new(operand), Box::new(result))
+        } else {
+            Expr::Or(Box::new(operand), Box::new(result))
+        };
+    }
+
+    result
+}
+
+// Returns the clauses (for CNF, `conjunction == true`) or terms (for DNF) of
+// an expression already in negation normal form.
+fn distribute(expr: Expr, conjunction: bool) -> Vec<Vec<Expr>> {
+    match expr {
+        Expr::And(left, right) if conjunction => {
+            let mut groups = distribute(*left, conjunction);
+            groups.extend(distribute(*right, conjunction));
+            groups
+        }
+        Expr::Or(left, right) if !conjunction => {
+            let mut groups = distribute(*left, conjunction);
+            groups.extend(distribute(*right, conjunction));
+            groups
+        }
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            let left = distribute(*left, conjunction);
+            let right = distribute(*right, conjunction);
+            let mut groups = Vec::with_capacity(left.len() * right.len());
+            for l in &left {
+                for r in &right {
+                    groups.push(l.iter().chain(r).cloned().collect());
+                }
+            }
+            groups
+        }
+        other => vec![vec![other]],
+    }
+}
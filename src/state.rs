@@ -0,0 +1,271 @@
+//! State kept by the engine between events, and across restarts.
+//!
+//! Triggers change state with the built-in actions [`SET_FLAG`],
+//! [`CLEAR_FLAG`] and [`INCR`], and read it back in conditions. A name
+//! starting with `state.` is global; any other name belongs to the player
+//! of the event (its `player.id`), or is global if the event has no player.
+//! A `player.` prefix on a name is optional.
+//!
+//! In conditions, `state.<name>` reads a global value. `player.<name>`
+//! reads the event's field when it has one, and the player's stored value
+//! otherwise, so `!player.has_received_reward` sees a flag set earlier with
+//! `set_flag("has_received_reward")`. `get("<name>")` holds when the value
+//! named like in actions is `true`. Conditions, including those of `if`
+//! blocks, see the state from before the trigger fired.
+//!
+//! Stores see flat keys: `state.<name>` for global values and
+//! `player.<id>.<name>` for per-player ones, with any `%` and `.` in the id
+//! written as `%25` and `%2E` so that the id cannot run into the name.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::engine::{EngineError, Event};
+use crate::{FieldPath, FuncCall, Value};
+
+/// Sets a flag to `true`: `set_flag("has_received_reward")`.
+pub const SET_FLAG: &str = "set_flag";
+/// Sets a flag to `false`: `clear_flag("muted")`.
+pub const CLEAR_FLAG: &str = "clear_flag";
+/// Adds to a number, starting from 0: `incr("state.joins")` or
+/// `incr("warnings", 2)`.
+pub const INCR: &str = "incr";
+/// Reads a flag in a condition: `get("state.event_running")`.
+pub const GET: &str = "get";
+
+/// The event field identifying the player that per-player state belongs to.
+pub const PLAYER_ID: &str = "player.id";
+
+pub trait StateStore {
+    fn get(&self, key: &str) -> Option<Value>;
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), EngineError>;
+
+    /// Makes the values set so far durable. The engine calls this once per
+    /// event or tick, after the event's state actions.
+    fn flush(&mut self) -> Result<(), EngineError> {
+        Ok(())
+    }
+}
+
+/// State that lives as long as the engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStateStore {
+    values: BTreeMap<String, Value>,
+}
+
+impl MemoryStateStore {
+    pub fn new() -> Self {
+        MemoryStateStore::default()
+    }
+
+    pub fn values(&self) -> &BTreeMap<String, Value> {
+        &self.values
+    }
+}
+
+impl StateStore for MemoryStateStore {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.values.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), EngineError> {
+        self.values.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// State kept in a JSON file, an object from key to value. Changes are
+/// written on [`flush`](StateStore::flush), and on drop, to a temporary
+/// file that then replaces the old one, so a crash leaves either the old or
+/// the new state.
+#[derive(Debug)]
+pub struct FileStateStore {
+    path: PathBuf,
+    memory: MemoryStateStore,
+    // Whether there are changes not yet written.
+    dirty: bool,
+}
+
+impl FileStateStore {
+    /// Opens the store at `path`, loading it if the file exists.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        let path = path.as_ref().to_path_buf();
+        let error = |message: String| EngineError::StateError {
+            path: path.clone(),
+            message,
+        };
+
+        let mut memory = MemoryStateStore::new();
+        if path.exists() {
+            let text = std::fs::read_to_string(&path).map_err(|e| error(e.to_string()))?;
+            let json: BTreeMap<String, serde_json::Value> =
+                serde_json::from_str(&text).map_err(|e| error(e.to_string()))?;
+            for (key, value) in json {
+                let value = from_json(&value)
+                    .ok_or_else(|| error(format!("unsupported value for '{}'", key)))?;
+                memory.values.insert(key, value);
+            }
+        }
+
+        Ok(FileStateStore {
+            path,
+            memory,
+            dirty: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn save(&self) -> Result<(), EngineError> {
+        let error = |message: String| EngineError::StateError {
+            path: self.path.clone(),
+            message,
+        };
+        let json: serde_json::Map<String, serde_json::Value> = self
+            .memory
+            .values
+            .iter()
+            .map(|(key, value)| (key.clone(), to_json(value)))
+            .collect();
+        let text = serde_json::to_string_pretty(&json).map_err(|e| error(e.to_string()))?;
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        std::fs::write(&temp, text)
+            .and_then(|_| std::fs::rename(&temp, &self.path))
+            .map_err(|e| error(e.to_string()))
+    }
+}
+
+impl StateStore for FileStateStore {
+    fn get(&self, key: &str) -> Option<Value> {
+        self.memory.get(key)
+    }
+
+    fn set(&mut self, key: &str, value: Value) -> Result<(), EngineError> {
+        self.memory.set(key, value)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), EngineError> {
+        if self.dirty {
+            self.save()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileStateStore {
+    fn drop(&mut self) {
+        // Errors cannot be reported here; call `flush` to see them.
+        let _ = self.flush();
+    }
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Boolean(b) => serde_json::Value::Bool(*b),
+        Value::Number(n) => n
+            .parse::<serde_json::Number>()
+            .map(serde_json::Value::Number)
+            .unwrap_or_else(|_| serde_json::Value::String(n.clone())),
+        Value::String(s) => serde_json::Value::String(s.clone()),
+        Value::Ident(path) => serde_json::Value::String(path.to_string()),
+        Value::Aggregate(aggregate) => serde_json::Value::String(aggregate.to_string()),
+    }
+}
+
+fn from_json(value: &serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::Bool(b) => Some(Value::Boolean(*b)),
+        serde_json::Value::Number(n) => Some(Value::Number(n.to_string())),
+        serde_json::Value::String(s) => Some(Value::String(s.clone())),
+        _ => None,
+    }
+}
+
+/// The store key of a state name as written in an action or `get`, for
+/// `event`.
+pub fn state_key(name: &str, event: &Event) -> String {
+    if let Some(global) = name.strip_prefix("state.") {
+        return format!("state.{}", global);
+    }
+    let name = name.strip_prefix("player.").unwrap_or(name);
+    match event.get(PLAYER_ID) {
+        Some(id) => player_key(id, name),
+        None => format!("state.{}", name),
+    }
+}
+
+/// The stored value a field path of a condition refers to, if it names
+/// state: `state.<name>`, or `player.<name>` for the event's player.
+pub fn lookup(path: &FieldPath, event: &Event, store: &dyn StateStore) -> Option<Value> {
+    if path.starts_with("state") && path.segments().len() > 1 {
+        return store.get(&path.to_string());
+    }
+    if !path.starts_with("player") {
+        return None;
+    }
+    let name = path.rest()?;
+    let id = event.get(PLAYER_ID)?;
+    store.get(&player_key(id, &name))
+}
+
+// The key of a player's value. Strings are used without their quotes.
+fn player_key(id: &Value, name: &str) -> String {
+    let id = match id {
+        Value::String(s) => s.clone(),
+        id => id.to_string(),
+    };
+    let id = id.replace('%', "%25").replace('.', "%2E");
+    format!("player.{}.{}", id, name)
+}
+
+/// Whether `action` is one of the built-in state actions.
+pub fn is_state_action(action: &FuncCall) -> bool {
+    matches!(action.name.as_str(), SET_FLAG | CLEAR_FLAG | INCR)
+}
+
+/// Applies a built-in state action for `event`.
+pub fn apply(
+    action: &FuncCall,
+    event: &Event,
+    store: &mut dyn StateStore,
+) -> Result<(), EngineError> {
+    let invalid = || EngineError::InvalidAction {
+        action: action.to_string(),
+        expected: match action.name.as_str() {
+            INCR => "a state name and optionally a number",
+            _ => "a state name",
+        }
+        .to_string(),
+    };
+    let Some(Value::String(name)) = action.args.first() else {
+        return Err(invalid());
+    };
+    let key = state_key(name, event);
+
+    match action.name.as_str() {
+        SET_FLAG | CLEAR_FLAG if action.args.len() == 1 => {
+            store.set(&key, Value::Boolean(action.name == SET_FLAG))
+        }
+        INCR if action.args.len() <= 2 => {
+            let by = match action.args.get(1) {
+                None => 1.0,
+                Some(Value::Number(n)) => n.parse().map_err(|_| invalid())?,
+                Some(_) => return Err(invalid()),
+            };
+            let current = match store.get(&key) {
+                Some(Value::Number(n)) => n.parse().unwrap_or(0.0),
+                _ => 0.0,
+            };
+            store.set(&key, Value::try_from(current + by)?)
+        }
+        _ => Err(invalid()),
+    }
+}
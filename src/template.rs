@@ -0,0 +1,218 @@
+//! Trigger templates and their instantiation.
+
+use std::collections::HashMap;
+
+use pest::iterators::Pair;
+
+use crate::borrowed;
+use crate::visit::{Fold, fold_expr, fold_trigger};
+use crate::{
+    Comparison, ConditionScope, Expr, FieldPath, ParseOptions, Rule, Span, TemplateDef, Trigger,
+    TriggerParserError, Value, parse_arg_list, parse_trigger,
+};
+
+pub(crate) fn parse_template<'i>(
+    pair: Pair<'i, Rule>,
+    scope: &mut ConditionScope<'i, '_>,
+    options: &ParseOptions,
+) -> Result<TemplateDef, TriggerParserError> {
+    let params = pair
+        .clone()
+        .into_inner()
+        .filter(|inner| inner.as_rule() == Rule::param_list)
+        .flat_map(|list| list.into_inner())
+        .map(|param| param.as_str().to_string())
+        .collect();
+    let body = parse_trigger(pair, scope, options)?.into_owned();
+
+    Ok(TemplateDef {
+        name: body.name.clone(),
+        params,
+        body,
+    })
+}
+
+/// Expands `trigger Name = Template(args);` into an ordinary trigger located
+/// at the instantiation.
+pub(crate) fn instantiate(
+    pair: Pair<Rule>,
+    local: &[TemplateDef],
+    external: &[TemplateDef],
+) -> Result<Trigger, TriggerParserError> {
+    let span = Span::from_pair(&pair);
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+    let template_pair = inner.next().unwrap();
+    let args = match inner.next() {
+        Some(list) => parse_arg_list(list)?
+            .into_iter()
+            .map(borrowed::Value::into_owned)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let template_name = template_pair.as_str();
+    let Some(template) = local
+        .iter()
+        .chain(external)
+        .find(|t| t.name == template_name)
+    else {
+        return Err(TriggerParserError::UndefinedTemplate {
+            name: template_name.to_string(),
+            span: Span::from_pair(&template_pair),
+        });
+    };
+
+    let error = |message: String| TriggerParserError::TemplateError {
+        message,
+        span,
+        template: Box::new(template.location()),
+    };
+
+    if args.len() != template.params.len() {
+        return Err(error(format!(
+            "template '{}' takes {} arguments but {} were given",
+            template.name,
+            template.params.len(),
+            args.len()
+        )));
+    }
+
+    let mut substitution = Substitution {
+        bindings: template
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(&args)
+            .collect(),
+        error: None,
+    };
+    let trigger = substitution.fold_trigger(template.body.clone());
+    if let Some(message) = substitution.error {
+        return Err(error(message));
+    }
+
+    Ok(Trigger {
+        name,
+        span,
+        origin: None,
+        ..trigger
+    })
+}
+
+// Replaces template parameters with the instantiation's arguments. A
+// parameter used as a field path, a `by` key or a bare condition must be
+// bound to an identifier (or a boolean, for conditions). Tags must be string
+// literals, so parameters in them are rejected when the template is parsed.
+struct Substitution<'a> {
+    bindings: HashMap<&'a str, &'a Value>,
+    error: Option<String>,
+}
+
+impl<'a> Substitution<'a> {
+    // Parameters are bare names; a dotted path never refers to one.
+    fn binding(&self, id: &FieldPath) -> Option<&'a Value> {
+        self.bindings.get(id.as_name()?).copied()
+    }
+
+    // The field and key of an aggregate are paths, so a parameter there
+    // must be bound to an identifier.
+    fn fold_field(&mut self, field: FieldPath) -> FieldPath {
+        match self.binding(&field) {
+            Some(Value::Ident(bound)) => bound.clone(),
+            Some(other) => {
+                self.error = Some(format!(
+                    "parameter '{}' is used as a field but bound to {}",
+                    field, other
+                ));
+                field
+            }
+            None => field,
+        }
+    }
+
+    // The `by` keys of cooldowns, rate limits and patterns are field paths
+    // kept as text.
+    fn fold_key(&mut self, key: String) -> String {
+        match self.bindings.get(key.as_str()) {
+            Some(Value::Ident(bound)) => bound.to_string(),
+            Some(other) => {
+                self.error = Some(format!(
+                    "parameter '{}' is used as a key but bound to {}",
+                    key, other
+                ));
+                key
+            }
+            None => key,
+        }
+    }
+}
+
+impl Fold for Substitution<'_> {
+    fn fold_trigger(&mut self, mut trigger: Trigger) -> Trigger {
+        if let Some(cooldown) = &mut trigger.cooldown {
+            cooldown.key = cooldown.key.take().map(|key| self.fold_key(key));
+        }
+        if let Some(limit) = &mut trigger.max_fires {
+            limit.key = limit.key.take().map(|key| self.fold_key(key));
+        }
+        if let Some(pattern) = &mut trigger.pattern {
+            pattern.key = pattern.key.take().map(|key| self.fold_key(key));
+        }
+        fold_trigger(self, trigger)
+    }
+
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Ident(id) => match self.binding(&id) {
+                Some(Value::Boolean(b)) => Expr::Ident(b.to_string().into()),
+                Some(Value::Ident(bound)) => Expr::Ident(bound.clone()),
+                Some(other) => {
+                    self.error = Some(format!(
+                        "parameter '{}' is used as a condition but bound to {}",
+                        id, other
+                    ));
+                    Expr::Ident(id)
+                }
+                None => Expr::Ident(id),
+            },
+            other => fold_expr(self, other),
+        }
+    }
+
+    fn fold_comparison(&mut self, comparison: Comparison) -> Comparison {
+        let bound = comparison.field().and_then(|field| self.binding(field));
+        let left = match bound {
+            Some(Value::Ident(bound)) => Value::Ident(bound.clone()),
+            Some(other) => {
+                self.error = Some(format!(
+                    "parameter '{}' is used as a field but bound to {}",
+                    comparison.left, other
+                ));
+                comparison.left
+            }
+            None => self.fold_value(comparison.left),
+        };
+
+        Comparison {
+            left,
+            operator: comparison.operator,
+            right: self.fold_value(comparison.right),
+        }
+    }
+
+    fn fold_value(&mut self, value: Value) -> Value {
+        match value {
+            Value::Ident(id) => match self.binding(&id) {
+                Some(bound) => bound.clone(),
+                None => Value::Ident(id),
+            },
+            Value::Aggregate(mut aggregate) => {
+                aggregate.field = aggregate.field.map(|field| self.fold_field(field));
+                aggregate.key = aggregate.key.map(|key| self.fold_field(key));
+                Value::Aggregate(aggregate)
+            }
+            other => other,
+        }
+    }
+}
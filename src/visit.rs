@@ -0,0 +1,320 @@
+//! Traversal helpers for the trigger AST.
+//!
+//! [`Visitor`] walks the tree by shared reference, [`VisitorMut`] edits it in
+//! place and [`Fold`] rebuilds it by value. Every method has a default that
+//! recurses through the matching `walk_*`/`fold_*` function, so an analysis
+//! only overrides the nodes it cares about.
+
+use crate::{Action, Comparison, Expr, FieldPath, FuncCall, Trigger, TriggerFile, Value};
+
+pub trait Visitor {
+    fn visit_trigger_file(&mut self, file: &TriggerFile) {
+        walk_trigger_file(self, file);
+    }
+
+    fn visit_trigger(&mut self, trigger: &Trigger) {
+        walk_trigger(self, trigger);
+    }
+
+    fn visit_action(&mut self, action: &Action) {
+        walk_action(self, action);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+
+    fn visit_comparison(&mut self, comparison: &Comparison) {
+        walk_comparison(self, comparison);
+    }
+
+    fn visit_func_call(&mut self, func: &FuncCall) {
+        walk_func_call(self, func);
+    }
+
+    fn visit_value(&mut self, value: &Value) {
+        walk_value(self, value);
+    }
+
+    /// Called for every field path: bare identifiers in conditions, the left
+    /// side of comparisons and identifier values.
+    fn visit_ident(&mut self, _ident: &FieldPath) {}
+}
+
+pub fn walk_trigger_file<V: Visitor + ?Sized>(visitor: &mut V, file: &TriggerFile) {
+    for trigger in &file.triggers {
+        visitor.visit_trigger(trigger);
+    }
+}
+
+pub fn walk_trigger<V: Visitor + ?Sized>(visitor: &mut V, trigger: &Trigger) {
+    if let Some(condition) = &trigger.condition {
+        visitor.visit_expr(condition);
+    }
+    for action in trigger.actions.iter().chain(&trigger.else_actions) {
+        visitor.visit_action(action);
+    }
+}
+
+pub fn walk_action<V: Visitor + ?Sized>(visitor: &mut V, action: &Action) {
+    match action {
+        Action::Call(func) => visitor.visit_func_call(func),
+        Action::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expr(condition);
+            for action in then.iter().chain(otherwise) {
+                visitor.visit_action(action);
+            }
+        }
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match expr {
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+        Expr::Not(inner) | Expr::Parenthesized(inner) => visitor.visit_expr(inner),
+        Expr::Comparison(comp) => visitor.visit_comparison(comp),
+        Expr::FuncCall(func) => visitor.visit_func_call(func),
+        Expr::Ident(id) => visitor.visit_ident(id),
+    }
+}
+
+pub fn walk_comparison<V: Visitor + ?Sized>(visitor: &mut V, comparison: &Comparison) {
+    visitor.visit_value(&comparison.left);
+    visitor.visit_value(&comparison.right);
+}
+
+pub fn walk_func_call<V: Visitor + ?Sized>(visitor: &mut V, func: &FuncCall) {
+    for arg in &func.args {
+        visitor.visit_value(arg);
+    }
+}
+
+pub fn walk_value<V: Visitor + ?Sized>(visitor: &mut V, value: &Value) {
+    match value {
+        Value::Ident(id) => visitor.visit_ident(id),
+        // The key is a field of the current event; the aggregated field
+        // belongs to other events.
+        Value::Aggregate(aggregate) => {
+            if let Some(key) = &aggregate.key {
+                visitor.visit_ident(key);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_trigger_file_mut(&mut self, file: &mut TriggerFile) {
+        walk_trigger_file_mut(self, file);
+    }
+
+    fn visit_trigger_mut(&mut self, trigger: &mut Trigger) {
+        walk_trigger_mut(self, trigger);
+    }
+
+    fn visit_action_mut(&mut self, action: &mut Action) {
+        walk_action_mut(self, action);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+
+    fn visit_comparison_mut(&mut self, comparison: &mut Comparison) {
+        walk_comparison_mut(self, comparison);
+    }
+
+    fn visit_func_call_mut(&mut self, func: &mut FuncCall) {
+        walk_func_call_mut(self, func);
+    }
+
+    fn visit_value_mut(&mut self, value: &mut Value) {
+        walk_value_mut(self, value);
+    }
+
+    fn visit_ident_mut(&mut self, _ident: &mut FieldPath) {}
+}
+
+pub fn walk_trigger_file_mut<V: VisitorMut + ?Sized>(visitor: &mut V, file: &mut TriggerFile) {
+    for trigger in &mut file.triggers {
+        visitor.visit_trigger_mut(trigger);
+    }
+    file.reindex();
+}
+
+pub fn walk_trigger_mut<V: VisitorMut + ?Sized>(visitor: &mut V, trigger: &mut Trigger) {
+    if let Some(condition) = &mut trigger.condition {
+        visitor.visit_expr_mut(condition);
+    }
+    for action in trigger.actions.iter_mut().chain(&mut trigger.else_actions) {
+        visitor.visit_action_mut(action);
+    }
+}
+
+pub fn walk_action_mut<V: VisitorMut + ?Sized>(visitor: &mut V, action: &mut Action) {
+    match action {
+        Action::Call(func) => visitor.visit_func_call_mut(func),
+        Action::If {
+            condition,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expr_mut(condition);
+            for action in then.iter_mut().chain(otherwise) {
+                visitor.visit_action_mut(action);
+            }
+        }
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::And(left, right) | Expr::Or(left, right) => {
+            visitor.visit_expr_mut(left);
+            visitor.visit_expr_mut(right);
+        }
+        Expr::Not(inner) | Expr::Parenthesized(inner) => visitor.visit_expr_mut(inner),
+        Expr::Comparison(comp) => visitor.visit_comparison_mut(comp),
+        Expr::FuncCall(func) => visitor.visit_func_call_mut(func),
+        Expr::Ident(id) => visitor.visit_ident_mut(id),
+    }
+}
+
+pub fn walk_comparison_mut<V: VisitorMut + ?Sized>(visitor: &mut V, comparison: &mut Comparison) {
+    visitor.visit_value_mut(&mut comparison.left);
+    visitor.visit_value_mut(&mut comparison.right);
+}
+
+pub fn walk_func_call_mut<V: VisitorMut + ?Sized>(visitor: &mut V, func: &mut FuncCall) {
+    for arg in &mut func.args {
+        visitor.visit_value_mut(arg);
+    }
+}
+
+pub fn walk_value_mut<V: VisitorMut + ?Sized>(visitor: &mut V, value: &mut Value) {
+    match value {
+        Value::Ident(id) => visitor.visit_ident_mut(id),
+        Value::Aggregate(aggregate) => {
+            if let Some(key) = &mut aggregate.key {
+                visitor.visit_ident_mut(key);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rebuilds the tree by value. Useful for rewrites that change the shape of
+/// an expression, e.g. replacing a node with one of a different variant.
+pub trait Fold {
+    fn fold_trigger_file(&mut self, file: TriggerFile) -> TriggerFile {
+        fold_trigger_file(self, file)
+    }
+
+    fn fold_trigger(&mut self, trigger: Trigger) -> Trigger {
+        fold_trigger(self, trigger)
+    }
+
+    fn fold_action(&mut self, action: Action) -> Action {
+        fold_action(self, action)
+    }
+
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        fold_expr(self, expr)
+    }
+
+    fn fold_comparison(&mut self, comparison: Comparison) -> Comparison {
+        fold_comparison(self, comparison)
+    }
+
+    fn fold_func_call(&mut self, func: FuncCall) -> FuncCall {
+        fold_func_call(self, func)
+    }
+
+    fn fold_value(&mut self, value: Value) -> Value {
+        value
+    }
+}
+
+pub fn fold_trigger_file<F: Fold + ?Sized>(folder: &mut F, file: TriggerFile) -> TriggerFile {
+    let triggers = file
+        .triggers
+        .into_iter()
+        .map(|trigger| folder.fold_trigger(trigger))
+        .collect();
+    TriggerFile::new(triggers, file.imports, file.conditions, file.templates)
+}
+
+pub fn fold_trigger<F: Fold + ?Sized>(folder: &mut F, trigger: Trigger) -> Trigger {
+    Trigger {
+        condition: trigger.condition.map(|cond| folder.fold_expr(cond)),
+        actions: fold_actions(folder, trigger.actions),
+        else_actions: fold_actions(folder, trigger.else_actions),
+        ..trigger
+    }
+}
+
+fn fold_actions<F: Fold + ?Sized>(folder: &mut F, actions: Vec<Action>) -> Vec<Action> {
+    actions
+        .into_iter()
+        .map(|action| folder.fold_action(action))
+        .collect()
+}
+
+pub fn fold_action<F: Fold + ?Sized>(folder: &mut F, action: Action) -> Action {
+    match action {
+        Action::Call(func) => Action::Call(folder.fold_func_call(func)),
+        Action::If {
+            condition,
+            then,
+            otherwise,
+        } => Action::If {
+            condition: folder.fold_expr(condition),
+            then: fold_actions(folder, then),
+            otherwise: fold_actions(folder, otherwise),
+        },
+    }
+}
+
+pub fn fold_expr<F: Fold + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    match expr {
+        Expr::And(left, right) => Expr::And(
+            Box::new(folder.fold_expr(*left)),
+            Box::new(folder.fold_expr(*right)),
+        ),
+        Expr::Or(left, right) => Expr::Or(
+            Box::new(folder.fold_expr(*left)),
+            Box::new(folder.fold_expr(*right)),
+        ),
+        Expr::Not(inner) => Expr::Not(Box::new(folder.fold_expr(*inner))),
+        Expr::Parenthesized(inner) => Expr::Parenthesized(Box::new(folder.fold_expr(*inner))),
+        Expr::Comparison(comp) => Expr::Comparison(folder.fold_comparison(comp)),
+        Expr::FuncCall(func) => Expr::FuncCall(folder.fold_func_call(func)),
+        Expr::Ident(id) => Expr::Ident(id),
+    }
+}
+
+pub fn fold_comparison<F: Fold + ?Sized>(folder: &mut F, comparison: Comparison) -> Comparison {
+    Comparison {
+        left: folder.fold_value(comparison.left),
+        operator: comparison.operator,
+        right: folder.fold_value(comparison.right),
+    }
+}
+
+pub fn fold_func_call<F: Fold + ?Sized>(folder: &mut F, func: FuncCall) -> FuncCall {
+    FuncCall {
+        args: func
+            .args
+            .into_iter()
+            .map(|arg| folder.fold_value(arg))
+            .collect(),
+        ..func
+    }
+}
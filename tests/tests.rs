@@ -1,4 +1,6 @@
-use triggerlang::{parse_triggers, parse_triggers_to_ast};
+use triggerlang::{
+    ParseOptions, parse_triggers, parse_triggers_to_ast, parse_triggers_to_ast_with,
+};
 
 #[test]
 fn test_simple_trigger() {
@@ -148,8 +150,8 @@ fn test_ast_generation() {
     let ast = parse_triggers_to_ast(input);
     assert!(ast.is_ok());
     let ast = ast.unwrap();
-    assert_eq!(ast.triggers.len(), 1);
-    assert_eq!(ast.triggers[0].name, "TestTrigger");
+    assert_eq!(ast.triggers().len(), 1);
+    assert_eq!(ast.triggers()[0].name, "TestTrigger");
 }
 
 #[test]
@@ -190,3 +192,3071 @@ fn test_less_or_equal_operator() {
     "#;
     assert!(parse_triggers(input).is_ok());
 }
+
+#[test]
+fn test_visitor_collects_fields() {
+    use triggerlang::visit::Visitor;
+
+    struct FieldCollector(Vec<String>);
+
+    impl Visitor for FieldCollector {
+        fn visit_ident(&mut self, ident: &triggerlang::FieldPath) {
+            self.0.push(ident.to_string());
+        }
+    }
+
+    let input = r#"
+        trigger Fields {
+            on: message_receive
+            description: "fields"
+            condition: (message.sender.is_admin == true || message.text == other.text) && !user.is_banned
+            action: log_message(message.text)
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let mut collector = FieldCollector(Vec::new());
+    collector.visit_trigger_file(&ast);
+
+    assert_eq!(
+        collector.0,
+        vec![
+            "message.sender.is_admin",
+            "message.text",
+            "other.text",
+            "user.is_banned",
+            "message.text",
+        ]
+    );
+}
+
+#[test]
+fn test_visitor_mut_renames_functions() {
+    use triggerlang::visit::{VisitorMut, walk_func_call_mut};
+
+    struct Rename;
+
+    impl VisitorMut for Rename {
+        fn visit_func_call_mut(&mut self, func: &mut triggerlang::FuncCall) {
+            if func.name == "send_message" {
+                func.name = "whisper".to_string();
+            }
+            walk_func_call_mut(self, func);
+        }
+    }
+
+    let input = r#"
+        trigger Renamed {
+            on: player_join
+            description: "rename"
+            condition: send_message("probe")
+            action: send_message("Welcome!")
+            action: log_event("join")
+        };
+    "#;
+    let mut ast = parse_triggers_to_ast(input).unwrap();
+    Rename.visit_trigger_file_mut(&mut ast);
+
+    let trigger = &ast.triggers()[0];
+    assert_eq!(
+        trigger.condition.as_ref().unwrap().to_string(),
+        "whisper(\"probe\")"
+    );
+    assert_eq!(trigger.actions[0].as_call().unwrap().name, "whisper");
+    assert_eq!(trigger.actions[1].as_call().unwrap().name, "log_event");
+}
+
+#[test]
+fn test_fold_strips_parentheses() {
+    use triggerlang::Expr;
+    use triggerlang::visit::{Fold, fold_expr};
+
+    struct StripParens;
+
+    impl Fold for StripParens {
+        fn fold_expr(&mut self, expr: Expr) -> Expr {
+            match expr {
+                Expr::Parenthesized(inner) => self.fold_expr(*inner),
+                other => fold_expr(self, other),
+            }
+        }
+    }
+
+    let input = r#"
+        trigger Parens {
+            on: player_join
+            description: "parens"
+            condition: ((a == 1)) && !(b)
+            action: test()
+        };
+    "#;
+    let ast = StripParens.fold_trigger_file(parse_triggers_to_ast(input).unwrap());
+
+    assert_eq!(
+        ast.triggers()[0].condition.as_ref().unwrap().to_string(),
+        "(a == 1 AND (NOT b))"
+    );
+}
+
+#[test]
+fn test_structural_equality_normalizes_numbers() {
+    use triggerlang::Value;
+
+    assert_eq!(Value::Number("1".into()), Value::Number("1.0".into()));
+    assert_eq!(Value::Number("007.50".into()), Value::Number("7.5".into()));
+    assert_ne!(Value::Number("1".into()), Value::String("1".into()));
+    assert!(Value::Number("9".into()) < Value::Number("10".into()));
+    assert!(Value::Number("0.5".into()) < Value::Number("0.75".into()));
+
+    let a = parse_triggers_to_ast(
+        r#"trigger A { on: player_join description: "a" condition: x >= 10 action: test(1) };"#,
+    )
+    .unwrap();
+    let b = parse_triggers_to_ast(
+        r#"trigger A {
+            description: "a"
+            on: player_join
+            condition: x >= 10.00
+            action: test(1.0)
+        };"#,
+    )
+    .unwrap();
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_triggers_deduplicate_and_sort() {
+    use std::collections::{BTreeSet, HashSet};
+
+    let input = r#"
+        trigger B { on: player_join description: "b" action: test() };
+        trigger A { on: player_join description: "a" action: test() };
+        trigger B { on: player_join description: "b" action: test() };
+    "#;
+    let mut options = ParseOptions::default();
+    options.allow_duplicates = true;
+    let ast = parse_triggers_to_ast_with(input, &options).unwrap();
+
+    let unique: HashSet<_> = ast.triggers().iter().collect();
+    assert_eq!(unique.len(), 2);
+
+    let names: Vec<_> = ast
+        .triggers()
+        .iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|t| t.name.as_str())
+        .collect();
+    assert_eq!(names, vec!["A", "B"]);
+}
+
+#[test]
+fn test_content_hash_ignores_formatting() {
+    let a = parse_triggers_to_ast(
+        r#"trigger A { on: player_join description: "a" condition: x == 1 && y action: test() };"#,
+    )
+    .unwrap();
+    let b = parse_triggers_to_ast(
+        r#"
+        trigger A {
+            on: player_join
+            description: "a"
+            condition: (x == 1.0) && (y)
+            action: test()
+        };"#,
+    )
+    .unwrap();
+    let c = parse_triggers_to_ast(
+        r#"trigger A { on: player_join description: "a" condition: x == 2 && y action: test() };"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        a.triggers()[0].content_hash(),
+        b.triggers()[0].content_hash()
+    );
+    assert_ne!(
+        a.triggers()[0].content_hash(),
+        c.triggers()[0].content_hash()
+    );
+}
+
+fn first_condition(input: &str) -> triggerlang::Expr {
+    parse_triggers_to_ast(input).unwrap().triggers()[0]
+        .condition
+        .clone()
+        .unwrap()
+}
+
+fn condition_input(condition: &str) -> String {
+    format!(
+        "trigger T {{ on: player_join description: \"t\" condition: {} action: test() }};",
+        condition
+    )
+}
+
+#[test]
+fn test_simplify_removes_redundancy() {
+    use triggerlang::simplify::simplify;
+
+    let cases = [
+        ("!!x", "x"),
+        ("((a))", "a"),
+        ("x == true", "x"),
+        ("x == false && y != false", "(x == false AND y != false)"),
+        ("a && b && a", "(a AND b)"),
+        ("(a && b) && (c && a)", "(a AND (b AND c))"),
+        ("!(a || b)", "((NOT a) AND (NOT b))"),
+        ("!(score > 10)", "(NOT score > 10)"),
+        ("a && true", "a"),
+        ("a || true", "true"),
+        ("a && !true", "false"),
+        ("true == false || b", "(true == false OR b)"),
+    ];
+
+    for (input, expected) in cases {
+        let expr = first_condition(&condition_input(input));
+        assert_eq!(simplify(expr).to_string(), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_simplify_keeps_meaning_for_missing_fields() {
+    use triggerlang::engine::{Event, evaluate};
+    use triggerlang::simplify::{simplify, to_cnf, to_dnf};
+
+    let events = [
+        Event::new("player_join"),
+        Event::new("player_join").with("x", true),
+        Event::new("player_join").with("x", false),
+        Event::new("player_join").with("x", 5),
+        Event::new("player_join").with("x", "text"),
+    ];
+    let conditions = [
+        "!(x > 1)",
+        "!(x <= 1) || x > 1",
+        "x == false",
+        "!(x == false)",
+        "x != true",
+        "x != false",
+        "!(x == true)",
+        "x == true && !(x != true)",
+        "!(x > 1 && x < 10)",
+        "true == true",
+    ];
+
+    for condition in conditions {
+        let expr = first_condition(&condition_input(condition));
+        for rewritten in [
+            simplify(expr.clone()),
+            to_cnf(expr.clone()),
+            to_dnf(expr.clone()),
+        ] {
+            for event in &events {
+                assert_eq!(
+                    evaluate(&rewritten, event),
+                    evaluate(&expr, event),
+                    "{} rewritten as {} for {:?}",
+                    condition,
+                    rewritten,
+                    event
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_normal_forms() {
+    use triggerlang::simplify::{to_cnf, to_dnf};
+
+    let expr = first_condition(&condition_input("(a || b) && c"));
+    assert_eq!(to_dnf(expr).to_string(), "((a AND c) OR (b AND c))");
+
+    let expr = first_condition(&condition_input("a || (b && !c)"));
+    assert_eq!(to_cnf(expr).to_string(), "((a OR b) AND (a OR (NOT c)))");
+
+    let expr = first_condition(&condition_input("!(a && b) || a"));
+    assert_eq!(to_dnf(expr).to_string(), "((NOT a) OR ((NOT b) OR a))");
+}
+
+#[test]
+fn test_detects_unsatisfiable_conditions() {
+    use triggerlang::analysis::{Satisfiability, check_condition};
+
+    let expr = first_condition(&condition_input(
+        "player.score > 100 && player.is_new && player.score < 50",
+    ));
+    match check_condition(&expr) {
+        Satisfiability::Unsatisfiable { conflicts } => {
+            assert_eq!(conflicts.len(), 1);
+            assert_eq!(
+                conflicts[0].to_string(),
+                "player.score > 100 AND player.score < 50"
+            );
+        }
+        other => panic!("expected unsatisfiable, got {:?}", other),
+    }
+
+    for input in [
+        "x && !x",
+        "a >= 1 && a <= 1 && a != 1",
+        "name == \"a\" && name == \"b\"",
+        "(a > 5 || b) && a < 3 && !b",
+        "send_message(\"x\") && !send_message(\"x\")",
+        "p == q && p != q",
+    ] {
+        let expr = first_condition(&condition_input(input));
+        assert!(
+            matches!(check_condition(&expr), Satisfiability::Unsatisfiable { .. }),
+            "input: {}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_detects_tautologies() {
+    use triggerlang::analysis::{Satisfiability, check_condition};
+
+    for input in [
+        "x || !x",
+        "score > 10 || score <= 10 || !(score > 10)",
+        "true",
+        "a || !(a && b)",
+    ] {
+        let expr = first_condition(&condition_input(input));
+        assert!(
+            matches!(check_condition(&expr), Satisfiability::AlwaysTrue { .. }),
+            "input: {}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_analysis_allows_missing_fields() {
+    use triggerlang::analysis::{OverlapKind, Satisfiability, check_condition, find_overlaps};
+
+    // Neither comparison holds when `x` is missing.
+    let expr = first_condition(&condition_input("x > 100 || x <= 100"));
+    assert!(matches!(
+        check_condition(&expr),
+        Satisfiability::Satisfiable { .. }
+    ));
+
+    for (input, example) in [
+        ("!(x > 100) && !(x <= 100)", "x = true"),
+        ("!(x > 100) && !(x <= 100) && !(x != 0)", ""),
+        ("x != true && x != false", "x = 0"),
+        ("!(name == \"a\") && !(name != \"a\")", ""),
+        ("p != q && !(p == q)", "p == q = false"),
+    ] {
+        let expr = first_condition(&condition_input(input));
+        match check_condition(&expr) {
+            Satisfiability::Satisfiable { example: found } => {
+                assert_eq!(found.to_string(), example, "input: {}", input)
+            }
+            other => panic!("expected satisfiable for {}, got {:?}", input, other),
+        }
+    }
+
+    let expr = first_condition(&condition_input("x > 100 && !(x > 50)"));
+    assert!(matches!(
+        check_condition(&expr),
+        Satisfiability::Unsatisfiable { .. }
+    ));
+
+    // `!(x <= 1)` also fires when `x` is missing, so it is not the same as `x > 1`.
+    let input = r#"
+        trigger Above { on: player_join description: "a" condition: x > 1 action: test() };
+        trigger NotBelow { on: player_join description: "n" condition: !(x <= 1) action: test() };
+    "#;
+    let overlaps = find_overlaps(&parse_triggers_to_ast(input).unwrap());
+    assert_eq!(overlaps.len(), 1);
+    assert_eq!(overlaps[0].first, "Above");
+    assert_eq!(overlaps[0].kind, OverlapKind::Subsumed);
+}
+
+#[test]
+fn test_satisfiable_condition_has_example() {
+    use triggerlang::analysis::{Satisfiability, check_condition};
+
+    let expr = first_condition(&condition_input(
+        "player.score >= 1000 && player.score != 1000 && player.name != \"\" && !player.has_received_reward",
+    ));
+    match check_condition(&expr) {
+        Satisfiability::Satisfiable { example } => assert_eq!(
+            example.to_string(),
+            "player.has_received_reward = false, player.name = \"value1\", player.score = 1001"
+        ),
+        other => panic!("expected satisfiable, got {:?}", other),
+    }
+
+    let expr = first_condition(&condition_input("level > 1 && level < 2"));
+    match check_condition(&expr) {
+        Satisfiability::Satisfiable { example } => {
+            assert_eq!(example.to_string(), "level = 1.5")
+        }
+        other => panic!("expected satisfiable, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_find_overlaps_between_triggers() {
+    use triggerlang::analysis::{OverlapKind, find_overlaps};
+
+    let input = r#"
+        trigger Welcome {
+            on: player_join
+            description: "welcome"
+            condition: player.is_new == true
+            action: send_message("Welcome")
+        };
+        trigger Ban {
+            on: player_join
+            description: "ban"
+            condition: player.is_banned == true
+            action: kick_player("Banned")
+        };
+        trigger NewBan {
+            on: player_join
+            description: "new and banned"
+            condition: player.is_banned && player.is_new
+            action: log_event("new ban")
+        };
+        trigger Veteran {
+            on: player_join
+            description: "veteran"
+            condition: !player.is_new && player.is_banned == false
+            action: send_message("Welcome back")
+        };
+        trigger Leave {
+            on: player_leave
+            description: "leave"
+            condition: player.is_new
+            action: send_message("Bye")
+        };
+        trigger Retired {
+            on: player_join
+            description: "never runs"
+            enabled: false
+            action: send_message("Hi")
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let overlaps = find_overlaps(&ast);
+
+    let summary: Vec<_> = overlaps
+        .iter()
+        .map(|o| (o.first.as_str(), o.second.as_str(), o.kind.clone()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("Welcome", "Ban", OverlapKind::Overlapping),
+            ("NewBan", "Welcome", OverlapKind::Subsumed),
+            ("NewBan", "Ban", OverlapKind::Subsumed),
+        ]
+    );
+    assert_eq!(
+        overlaps[0].to_string(),
+        "triggers `Welcome` and `Ban` can both fire on player_join, e.g. player.is_banned = true, player.is_new = true"
+    );
+}
+
+#[test]
+fn test_unconditional_trigger_subsumes_others() {
+    use triggerlang::analysis::{OverlapKind, find_overlaps};
+
+    let input = r#"
+        trigger Always { on: player_join description: "a" action: test() };
+        trigger Sometimes { on: player_join description: "s" condition: x > 1 action: test() };
+        trigger Never { on: player_join description: "n" condition: x > 1 && x < 0 action: test() };
+        trigger Same { on: player_join description: "s" condition: !(x <= 1) && x >= 0 action: test() };
+    "#;
+    let overlaps = find_overlaps(&parse_triggers_to_ast(input).unwrap());
+
+    assert_eq!(overlaps.len(), 3);
+    assert_eq!(overlaps[0].first, "Sometimes");
+    assert_eq!(overlaps[0].second, "Always");
+    assert_eq!(overlaps[0].kind, OverlapKind::Subsumed);
+    assert_eq!(overlaps[2].kind, OverlapKind::Equivalent);
+}
+
+#[test]
+fn test_else_actions_fire_on_every_event() {
+    use triggerlang::analysis::{OverlapKind, find_overlaps};
+
+    let input = r#"
+        trigger Welcome { on: player_join description: "w" condition: player.is_new action: a() else_action: b() };
+        trigger Ban { on: player_join description: "b" condition: player.is_banned action: kick() };
+        trigger Never { on: player_join description: "n" condition: x > 1 && x < 0 action: a() else_action: c() };
+    "#;
+    let overlaps = find_overlaps(&parse_triggers_to_ast(input).unwrap());
+
+    let summary: Vec<_> = overlaps
+        .iter()
+        .map(|o| (o.first.as_str(), o.second.as_str(), o.kind.clone()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("Ban", "Welcome", OverlapKind::Subsumed),
+            ("Welcome", "Never", OverlapKind::Equivalent),
+            ("Ban", "Never", OverlapKind::Subsumed),
+        ]
+    );
+}
+
+#[test]
+fn test_lint_reports_rules() {
+    use triggerlang::lint::{LintConfig, lint};
+
+    let input = r#"
+        trigger A {
+            on: player_join
+            description: ""
+            condition: player.is_new == true && send_message("x")
+        };
+        trigger B {
+            on: player_leave
+            description: "b"
+            condition: player.score > 10 && player.score < 5
+            action: send_message("bye")
+        };
+        trigger A {
+            on: message_receive
+            description: "a"
+            condition: !(!(a || b) && c)
+            action: log(player.scroe, player.score, player.score)
+        };
+    "#;
+    let mut options = ParseOptions::default();
+    options.allow_duplicates = true;
+    let ast = parse_triggers_to_ast_with(input, &options).unwrap();
+    let diagnostics = lint(input, &ast, &LintConfig::default());
+
+    let rules: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.trigger.as_deref().unwrap(), d.rule))
+        .collect();
+    assert_eq!(
+        rules,
+        vec![
+            ("A", "empty-description"),
+            ("A", "no-actions"),
+            ("A", "bool-comparison"),
+            ("A", "action-in-condition"),
+            ("B", "unsatisfiable-condition"),
+            ("A", "duplicate-name"),
+            ("A", "unknown-field"),
+        ]
+    );
+    assert_eq!(
+        diagnostics[2].message,
+        "`player.is_new == true` can be written as `player.is_new`"
+    );
+    assert_eq!(
+        diagnostics[6].message,
+        "field `player.scroe` looks misspelled, did you mean `player.score`?"
+    );
+}
+
+#[test]
+fn test_lint_checks_else_actions_and_if_blocks() {
+    use triggerlang::lint::{LintConfig, lint};
+
+    let input = r#"
+        trigger Returning {
+            on: player_join
+            description: "r"
+            condition: player.is_new
+            else_action: send_message("Welcome back!")
+        };
+        trigger Reward {
+            on: player_leave
+            description: "w"
+            condition: player.is_new
+            action: send_message("Bye")
+            if player.score > 100 && player.score < 10 {
+                action: give_reward("never")
+            } else if player.is_vip || !player.is_vip {
+                action: give_reward("always")
+            }
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let diagnostics = lint(input, &ast, &LintConfig::default());
+
+    let found: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.trigger.as_deref().unwrap(), d.rule))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("Reward", "unsatisfiable-condition"),
+            ("Reward", "tautological-condition"),
+        ]
+    );
+    assert!(
+        diagnostics[0]
+            .message
+            .starts_with("condition of an `if` in `Reward` is never true")
+    );
+}
+
+#[test]
+fn test_lint_config_and_suppressions() {
+    use triggerlang::lint::{Level, LintConfig, lint};
+
+    let config = LintConfig::from_toml(
+        r#"
+        [lint]
+        max_depth = 1
+        fields = ["player.*", "message.text"]
+
+        [lint.rules]
+        bool-comparison = "off"
+        no-actions = "error"
+        "#,
+    )
+    .unwrap();
+
+    let input = r#"
+        // lint:allow(deep-nesting)
+        trigger A {
+            on: player_join
+            description: "a"
+            condition: (player.is_new == true || player.x) && !player.y
+        };
+        trigger B {
+            on: message_receive
+            description: "b" // lint:allow(unknown-field)
+            condition: messgae.text == "hi"
+            action: test()
+        };
+        trigger C {
+            on: player_leave
+            description: "c"
+            condition: (player.a || player.b) && mesage.text == "hi"
+            action: test()
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let diagnostics = lint(input, &ast, &config);
+
+    let rules: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.trigger.as_deref(), d.rule, d.level))
+        .collect();
+    assert_eq!(
+        rules,
+        vec![
+            (Some("A"), "no-actions", Level::Error),
+            (Some("C"), "deep-nesting", Level::Warning),
+            (Some("C"), "unknown-field", Level::Warning),
+            (None, "unused-field", Level::Info),
+        ]
+    );
+    assert_eq!(
+        diagnostics[2].message,
+        "unknown field `mesage.text`, did you mean `message.text`?"
+    );
+
+    assert!(LintConfig::from_toml("[lint.rules]\nno-such-rule = \"off\"").is_err());
+}
+
+#[test]
+fn test_suppressions_apply_to_one_trigger_of_a_name() {
+    use triggerlang::lint::{LintConfig, lint};
+
+    let input = r#"
+        // lint:allow(empty-description)
+        trigger A { on: player_join description: "" action: a() };
+        trigger A { on: player_leave description: "" action: b() };
+        // lint:allow(duplicate-name)
+        trigger B { on: message_receive description: "b" action: c() };
+        trigger B { on: player_score_change description: "b" action: d() };
+    "#;
+    let mut options = ParseOptions::default();
+    options.allow_duplicates = true;
+    let ast = parse_triggers_to_ast_with(input, &options).unwrap();
+    let diagnostics = lint(input, &ast, &LintConfig::default());
+
+    let found: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.trigger.as_deref().unwrap(), d.rule, d.span.unwrap().line))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("A", "duplicate-name", 4),
+            ("A", "empty-description", 4),
+            ("B", "duplicate-name", 7),
+        ]
+    );
+}
+
+#[test]
+fn test_lint_project_follows_imports() {
+    use triggerlang::lint::{LintConfig, lint_project};
+    use triggerlang::project::load_project;
+
+    let dir = project_dir(
+        "lint",
+        &[
+            (
+                "main.tl",
+                r#"
+                import "rules.tl";
+                trigger Hello { on: message_receive description: "h" action: test() };
+                "#,
+            ),
+            (
+                "rules.tl",
+                r#"
+                trigger Quiet { on: player_join description: "" action: test() };
+                // lint:allow(empty-description)
+                trigger Allowed { on: player_leave description: "" action: test() };
+                "#,
+            ),
+        ],
+    );
+
+    let project = load_project(&dir.join("main.tl")).unwrap();
+    let diagnostics = lint_project(&project, &LintConfig::default()).unwrap();
+    let found: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.trigger.as_deref(), d.rule, d.file.clone()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![(
+            Some("Quiet"),
+            "empty-description",
+            Some(dir.join("rules.tl").canonicalize().unwrap())
+        )]
+    );
+}
+
+#[test]
+fn test_duplicate_trigger_names_fail() {
+    use triggerlang::TriggerParserError;
+
+    let input = "trigger BanCheck { on: player_join description: \"a\" action: test() };\n\
+                 trigger Other { on: player_join description: \"o\" action: test() };\n\
+                 trigger BanCheck { on: player_leave description: \"b\" action: test() };";
+
+    match parse_triggers_to_ast(input) {
+        Err(TriggerParserError::DuplicateTrigger {
+            name,
+            first,
+            second,
+        }) => {
+            assert_eq!(name, "BanCheck");
+            assert_eq!((first.span.line, first.span.column), (1, 1));
+            assert_eq!((second.span.line, second.span.column), (3, 1));
+        }
+        other => panic!("expected duplicate error, got {:?}", other),
+    }
+    assert!(parse_triggers(input).is_err());
+
+    let mut options = ParseOptions::default();
+    options.allow_duplicates = true;
+    let ast = parse_triggers_to_ast_with(input, &options).unwrap();
+    assert_eq!(ast.triggers().len(), 3);
+    assert_eq!(ast.get("BanCheck").unwrap().event_type, "player_join");
+}
+
+#[test]
+fn test_trigger_file_lookup_by_name() {
+    use triggerlang::TriggerParserError;
+
+    let input = r#"
+        trigger Zeta { on: player_join description: "z" action: test() };
+        trigger Alpha { on: player_leave description: "a" action: test() };
+        trigger Mid { on: player_join description: "m" action: test() };
+    "#;
+    let mut ast = parse_triggers_to_ast(input).unwrap();
+
+    assert_eq!(ast.get("Alpha").unwrap().event_type, "player_leave");
+    assert!(ast.get("Missing").is_none());
+
+    let names: Vec<_> = ast.iter_by_name().map(|(name, _)| name).collect();
+    assert_eq!(names, vec!["Alpha", "Mid", "Zeta"]);
+
+    // Changes go through methods, which keep names unique and the index
+    // in step.
+    let alpha = ast.remove("Alpha").unwrap();
+    assert!(ast.get("Alpha").is_none());
+    assert_eq!(ast.get("Mid").unwrap().name, "Mid");
+    let mid = ast.get("Mid").unwrap().clone();
+    assert!(matches!(
+        ast.push(mid),
+        Err(TriggerParserError::DuplicateTrigger { .. })
+    ));
+    ast.push(alpha).unwrap();
+    let order: Vec<_> = ast.triggers().iter().map(|t| t.name.as_str()).collect();
+    assert_eq!(order, vec!["Zeta", "Mid", "Alpha"]);
+    assert_eq!(ast.get("Alpha").unwrap().event_type, "player_leave");
+}
+
+fn project_dir(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("triggerlang-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    for (path, content) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn test_load_project_merges_imports() {
+    use triggerlang::project::load_project;
+
+    let dir = project_dir(
+        "merge",
+        &[
+            (
+                "main.tl",
+                r#"
+                import "modes/moderation.tl";
+                trigger Welcome { on: player_join description: "w" action: test() };
+                include "common.tl";
+                "#,
+            ),
+            (
+                "modes/moderation.tl",
+                r#"
+                import "../common.tl";
+                trigger BanCheck { on: player_join description: "b" action: test() };
+                "#,
+            ),
+            (
+                "common.tl",
+                r#"trigger Goodbye { on: player_leave description: "g" action: test() };"#,
+            ),
+        ],
+    );
+
+    let project = load_project(&dir.join("main.tl")).unwrap();
+    let loaded: Vec<_> = project
+        .triggers()
+        .iter()
+        .map(|t| {
+            let origin = t.origin.as_ref().unwrap();
+            (
+                t.name.as_str(),
+                origin.file_name().unwrap().to_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        loaded,
+        vec![
+            ("Goodbye", "common.tl"),
+            ("BanCheck", "moderation.tl"),
+            ("Welcome", "main.tl"),
+        ]
+    );
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_load_project_errors() {
+    use triggerlang::TriggerParserError;
+    use triggerlang::project::load_project;
+
+    let dir = project_dir(
+        "errors",
+        &[
+            (
+                "a.tl",
+                r#"import "b.tl"; trigger A { on: player_join description: "a" action: test() };"#,
+            ),
+            ("b.tl", "\n\nimport \"a.tl\";"),
+            ("missing.tl", r#"import "nowhere.tl";"#),
+            (
+                "dup.tl",
+                r#"import "dup2.tl"; trigger D { on: player_join description: "d" action: test() };"#,
+            ),
+            (
+                "dup2.tl",
+                r#"trigger D { on: player_join description: "d" action: test() };"#,
+            ),
+            ("broken.tl", r#"import "bad.tl";"#),
+            ("bad.tl", "trigger {"),
+        ],
+    );
+
+    match load_project(&dir.join("a.tl")) {
+        Err(TriggerParserError::ImportCycle { location, chain }) => {
+            assert!(location.file.unwrap().ends_with("b.tl"));
+            assert_eq!(location.span.line, 3);
+            let names: Vec<_> = chain
+                .iter()
+                .map(|p| p.file_name().unwrap().to_owned())
+                .collect();
+            assert_eq!(names, vec!["a.tl", "b.tl", "a.tl"]);
+        }
+        other => panic!("expected import cycle, got {:?}", other),
+    }
+
+    assert!(matches!(
+        load_project(&dir.join("missing.tl")),
+        Err(TriggerParserError::ImportError { .. })
+    ));
+
+    match load_project(&dir.join("dup.tl")) {
+        Err(e @ TriggerParserError::DuplicateTrigger { .. }) => {
+            let message = e.to_string();
+            assert!(message.contains("dup.tl:1:19"), "{}", message);
+            assert!(message.contains("dup2.tl:1:1"), "{}", message);
+        }
+        other => panic!("expected duplicate trigger, got {:?}", other),
+    }
+
+    match load_project(&dir.join("broken.tl")) {
+        Err(TriggerParserError::InFile { path, .. }) => assert!(path.ends_with("bad.tl")),
+        other => panic!("expected parse error in bad.tl, got {:?}", other),
+    }
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_imports_are_recorded() {
+    let input = r#"
+        import "moderation.tl";
+        trigger A { on: player_join description: "a" action: test() };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    assert_eq!(ast.imports.len(), 1);
+    assert_eq!(ast.imports[0].path, "moderation.tl");
+    assert_eq!(ast.imports[0].span.line, 2);
+}
+
+#[test]
+fn test_named_conditions_expand() {
+    let input = r#"
+        condition IsStaff = message.sender.is_admin == true || message.sender.is_moderator == true;
+        condition ActiveStaff = IsStaff && !message.sender.is_afk;
+
+        trigger AdminMessage {
+            on: message_receive
+            description: "staff"
+            condition: ActiveStaff && !message.is_empty
+            action: broadcast_message("Admin announcement")
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+
+    assert_eq!(ast.conditions.len(), 2);
+    assert_eq!(ast.conditions[1].name, "ActiveStaff");
+    assert_eq!(
+        ast.triggers()[0].condition.as_ref().unwrap().to_string(),
+        "(((((message.sender.is_admin == true OR message.sender.is_moderator == true)) AND (NOT message.sender.is_afk))) AND (NOT message.is_empty))"
+    );
+}
+
+#[test]
+fn test_named_condition_errors() {
+    use triggerlang::TriggerParserError;
+
+    let undefined = r#"
+        trigger T {
+            on: player_join
+            description: "t"
+            condition: player.is_new && IsStaff
+        };
+    "#;
+    match parse_triggers_to_ast(undefined) {
+        Err(TriggerParserError::UndefinedCondition { name, span }) => {
+            assert_eq!(name, "IsStaff");
+            assert_eq!((span.line, span.column), (5, 41));
+        }
+        other => panic!("expected undefined condition, got {:?}", other),
+    }
+
+    let recursive = r#"
+        condition A = x || B;
+        condition B = !A;
+        trigger T { on: player_join description: "t" condition: A };
+    "#;
+    match parse_triggers_to_ast(recursive) {
+        Err(TriggerParserError::RecursiveCondition { chain, span }) => {
+            assert_eq!(chain, vec!["A", "B", "A"]);
+            assert_eq!((span.line, span.column), (3, 24));
+        }
+        other => panic!("expected recursive condition, got {:?}", other),
+    }
+
+    let unused_recursive = "condition Loop = Loop;";
+    assert!(matches!(
+        parse_triggers_to_ast(unused_recursive),
+        Err(TriggerParserError::RecursiveCondition { .. })
+    ));
+
+    let duplicate = "condition A = x;\ncondition A = y;";
+    assert!(matches!(
+        parse_triggers_to_ast(duplicate),
+        Err(TriggerParserError::DuplicateCondition { .. })
+    ));
+}
+
+#[test]
+fn test_named_conditions_across_imports() {
+    use triggerlang::TriggerParserError;
+    use triggerlang::project::load_project;
+
+    let dir = project_dir(
+        "conditions",
+        &[
+            (
+                "main.tl",
+                r#"
+                import "staff.tl";
+                import "relayed.tl";
+                trigger Admin { on: message_receive description: "a" condition: IsStaff action: test() };
+                "#,
+            ),
+            ("staff.tl", "condition IsStaff = message.sender.is_admin;"),
+            // Does not import staff.tl, so `IsStaff` is undefined here even
+            // when staff.tl is loaded first.
+            ("scoped.tl", "import \"staff.tl\"; import \"other.tl\";"),
+            (
+                "other.tl",
+                r#"trigger Other { on: message_receive description: "o" condition: IsStaff action: test() };"#,
+            ),
+            ("relay.tl", r#"import "staff.tl";"#),
+            (
+                "relayed.tl",
+                r#"
+                import "relay.tl";
+                trigger Relayed { on: message_receive description: "r" condition: IsStaff action: test() };
+                "#,
+            ),
+        ],
+    );
+
+    let project = load_project(&dir.join("main.tl")).unwrap();
+    let conditions: Vec<_> = project
+        .triggers()
+        .iter()
+        .map(|t| (t.name.as_str(), t.condition.as_ref().unwrap().to_string()))
+        .collect();
+    assert_eq!(
+        conditions,
+        vec![
+            ("Relayed", "(message.sender.is_admin)".to_string()),
+            ("Admin", "(message.sender.is_admin)".to_string()),
+        ]
+    );
+    assert!(
+        project.conditions[0]
+            .origin
+            .as_ref()
+            .unwrap()
+            .ends_with("staff.tl")
+    );
+
+    match load_project(&dir.join("scoped.tl")) {
+        Err(TriggerParserError::InFile { path, source }) => {
+            assert!(path.ends_with("other.tl"));
+            assert!(matches!(
+                *source,
+                TriggerParserError::UndefinedCondition { ref name, .. } if name == "IsStaff"
+            ));
+        }
+        other => panic!("expected undefined condition, got {:?}", other),
+    }
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_template_instantiation() {
+    let input = r#"
+        template ScoreReward(threshold, reward) {
+            on: player_score_change
+            description: "Reward players who reach high scores"
+            condition: player.score >= threshold && !player.has_received_reward
+            action: give_reward(reward)
+            action: set_flag("has_received_reward")
+        };
+
+        trigger Gold = ScoreReward(1000, "gold_medal");
+        trigger Silver = ScoreReward(500, "silver_medal");
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+
+    assert_eq!(ast.templates.len(), 1);
+    assert_eq!(ast.templates[0].params, vec!["threshold", "reward"]);
+    assert_eq!(ast.triggers().len(), 2);
+
+    let gold = ast.get("Gold").unwrap();
+    assert_eq!(gold.event_type, "player_score_change");
+    assert_eq!(gold.span.line, 10);
+    assert_eq!(
+        gold.condition.as_ref().unwrap().to_string(),
+        "(player.score >= 1000 AND (NOT player.has_received_reward))"
+    );
+    assert_eq!(gold.actions[0].to_string(), "give_reward(\"gold_medal\")");
+    assert_eq!(
+        ast.get("Silver").unwrap().actions[0].to_string(),
+        "give_reward(\"silver_medal\")"
+    );
+}
+
+#[test]
+fn test_template_field_and_flag_parameters() {
+    let input = r#"
+        template FlagCheck(field, flag, limit) {
+            on: player_join
+            description: "check"
+            condition: field > limit && flag
+            action: test()
+        };
+        trigger Check = FlagCheck(player.level, true, 3);
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    assert_eq!(
+        ast.triggers()[0].condition.as_ref().unwrap().to_string(),
+        "(player.level > 3 AND true)"
+    );
+
+    let flood = r#"
+        template Flood(key, limit) {
+            on: message_receive
+            description: "flood"
+            condition: count(message_receive, key, 1m) > limit
+            action: test()
+        };
+    "#;
+    let ast =
+        parse_triggers_to_ast(&format!("{}\ntrigger F = Flood(player.id, 10);", flood)).unwrap();
+    assert_eq!(
+        ast.triggers()[0].condition.as_ref().unwrap().to_string(),
+        "count(message_receive, player.id, 1m) > 10"
+    );
+    assert!(matches!(
+        parse_triggers_to_ast(&format!("{}\ntrigger F = Flood(3, 10);", flood)),
+        Err(triggerlang::TriggerParserError::TemplateError { .. })
+    ));
+}
+
+#[test]
+fn test_template_key_parameters() {
+    let limited = r#"
+        template Limited(key) {
+            on: player_join then 3 message_receive within 10s by key
+            description: "limited"
+            action: test()
+            cooldown: 10s by key
+            max_fires: 2 per 1m by key
+        };
+    "#;
+    let ast =
+        parse_triggers_to_ast(&format!("{}\ntrigger L = Limited(player.id);", limited)).unwrap();
+    let trigger = &ast.triggers()[0];
+    assert_eq!(
+        trigger.cooldown.as_ref().unwrap().key.as_deref(),
+        Some("player.id")
+    );
+    assert_eq!(
+        trigger.max_fires.as_ref().unwrap().key.as_deref(),
+        Some("player.id")
+    );
+    assert_eq!(
+        trigger.pattern.as_ref().unwrap().key.as_deref(),
+        Some("player.id")
+    );
+
+    match parse_triggers_to_ast(&format!("{}\ntrigger L = Limited(\"id\");", limited)) {
+        Err(e @ triggerlang::TriggerParserError::TemplateError { .. }) => {
+            assert_eq!(
+                e.to_string(),
+                "10:1: parameter 'key' is used as a key but bound to \"id\" (template defined at 2:9)"
+            );
+        }
+        other => panic!("expected template error, got {:?}", other),
+    }
+
+    // Tags must be string literals, so a parameter in them is rejected.
+    let tagged = r#"
+        template Tagged(tag) {
+            on: player_join
+            description: "tagged"
+            action: test()
+            tags: [tag]
+        };
+    "#;
+    match parse_triggers_to_ast(tagged) {
+        Err(triggerlang::TriggerParserError::InvalidField { field, span, .. }) => {
+            assert_eq!(field, "tags");
+            assert_eq!((span.line, span.column), (6, 19));
+        }
+        other => panic!("expected invalid tags, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_template_errors() {
+    use triggerlang::TriggerParserError;
+
+    let template = r#"
+        template Reward(threshold) {
+            on: player_score_change
+            description: "reward"
+            condition: threshold
+            action: test()
+        };
+    "#;
+
+    match parse_triggers_to_ast(&format!("{}\ntrigger A = Reward(1, 2);", template)) {
+        Err(e @ TriggerParserError::TemplateError { .. }) => {
+            assert_eq!(
+                e.to_string(),
+                "9:1: template 'Reward' takes 1 arguments but 2 were given (template defined at 2:9)"
+            );
+        }
+        other => panic!("expected template error, got {:?}", other),
+    }
+
+    assert!(matches!(
+        parse_triggers_to_ast(&format!("{}\ntrigger A = Reward(\"x\");", template)),
+        Err(TriggerParserError::TemplateError { .. })
+    ));
+
+    match parse_triggers_to_ast("trigger A = Missing();") {
+        Err(TriggerParserError::UndefinedTemplate { name, span }) => {
+            assert_eq!(name, "Missing");
+            assert_eq!(span.column, 13);
+        }
+        other => panic!("expected undefined template, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_fields_in_any_order() {
+    let input = r#"
+        trigger Reordered {
+            action: first()
+            condition: player.score > 10
+            description: "any order"
+            action: second()
+            on: player_score_change
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let trigger = &ast.triggers()[0];
+    assert_eq!(trigger.event_type, "player_score_change");
+    assert_eq!(trigger.description, "any order");
+    assert!(trigger.condition.is_some());
+    let actions: Vec<_> = trigger.calls().iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(actions, ["first", "second"]);
+}
+
+#[test]
+fn test_field_errors() {
+    use triggerlang::TriggerParserError;
+
+    match parse_triggers_to_ast(r#"trigger T { description: "d" action: test() };"#) {
+        Err(e @ TriggerParserError::MissingField { .. }) => {
+            assert_eq!(
+                e.to_string(),
+                "Trigger 'T' at 1:1 is missing required field 'on'"
+            );
+        }
+        other => panic!("expected missing field, got {:?}", other),
+    }
+
+    let input = "trigger T {\n  on: player_join\n  description: \"d\"\n  on: player_leave\n};";
+    match parse_triggers_to_ast(input) {
+        Err(TriggerParserError::DuplicateField {
+            field,
+            first,
+            second,
+        }) => {
+            assert_eq!(field, "on");
+            assert_eq!((first.line, second.line), (2, 4));
+        }
+        other => panic!("expected duplicate field, got {:?}", other),
+    }
+
+    let input = r#"trigger T { on: player_join description: "d" acton: test() };"#;
+    match parse_triggers_to_ast(input) {
+        Err(TriggerParserError::UnknownField { field, span }) => {
+            assert_eq!(field, "acton");
+            assert_eq!(span.column, 46);
+        }
+        other => panic!("expected unknown field, got {:?}", other),
+    }
+
+    match parse_triggers_to_ast(r#"trigger T { on: player_jump description: "d" };"#) {
+        Err(e @ TriggerParserError::InvalidField { .. }) => assert_eq!(
+            e.to_string(),
+            "Invalid value for field 'on' at 1:17: expected one of player_join, \
+             player_leave, player_score_change, message_receive, `every <duration>` \
+             or `schedule(\"<cron>\")`"
+        ),
+        other => panic!("expected invalid field, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_registered_fields() {
+    use triggerlang::TriggerParserError;
+    use triggerlang::fields::{FieldSpec, FieldType, FieldValue};
+
+    let mut options = ParseOptions::default();
+    options
+        .register_field("owner", FieldSpec::new(FieldType::String).required())
+        .unwrap()
+        .register_field(
+            "channels",
+            FieldSpec::new(FieldType::List(Box::new(FieldType::Ident))),
+        )
+        .unwrap()
+        .register_field("unless", FieldSpec::new(FieldType::Expr).repeated())
+        .unwrap();
+    assert!(matches!(
+        options.register_field("condition", FieldSpec::new(FieldType::Expr)),
+        Err(TriggerParserError::ReservedField { field }) if field == "condition"
+    ));
+
+    let input = r#"
+        condition Quiet = server.quiet_hours == true;
+        trigger T {
+            channels: [global, staff]
+            on: message_receive
+            unless: Quiet
+            owner: "moderation"
+            description: "d"
+            unless: message.is_empty
+        };
+    "#;
+    let ast = parse_triggers_to_ast_with(input, &options).unwrap();
+    let trigger = &ast.triggers()[0];
+    assert_eq!(
+        trigger.field("owner").unwrap().to_string(),
+        "\"moderation\""
+    );
+    assert_eq!(
+        trigger.field("channels").unwrap().to_string(),
+        "[global, staff]"
+    );
+    let unless: Vec<String> = trigger.fields["unless"]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(unless, ["(server.quiet_hours == true)", "message.is_empty"]);
+    assert!(matches!(trigger.field("unless"), Some(FieldValue::Expr(_))));
+
+    // Registered fields take part in equality.
+    let other = input.replace("\"moderation\"", "\"events\"");
+    assert_ne!(parse_triggers_to_ast_with(&other, &options).unwrap(), ast);
+
+    match parse_triggers_to_ast_with(
+        r#"trigger T { on: player_join description: "d" };"#,
+        &options,
+    ) {
+        Err(TriggerParserError::MissingField { field, .. }) => assert_eq!(field, "owner"),
+        other => panic!("expected missing field, got {:?}", other),
+    }
+
+    let input = r#"trigger T { on: player_join description: "d" owner: 42 };"#;
+    match parse_triggers_to_ast_with(input, &options) {
+        Err(e @ TriggerParserError::InvalidField { .. }) => assert_eq!(
+            e.to_string(),
+            "Invalid value for field 'owner' at 1:53: expected a string"
+        ),
+        other => panic!("expected invalid field, got {:?}", other),
+    }
+
+    let input = r#"trigger T { on: player_join description: "d" owner: "a" owner: "b" };"#;
+    assert!(matches!(
+        parse_triggers_to_ast_with(input, &options),
+        Err(TriggerParserError::DuplicateField { .. })
+    ));
+}
+
+#[test]
+fn test_cooldown_and_max_fires_fields() {
+    use std::time::Duration;
+    use triggerlang::TriggerParserError;
+
+    let input = r#"
+        trigger Limited {
+            on: message_receive
+            description: "d"
+            cooldown: 30s by player.id
+            max_fires: 3 per 1m
+            action: test()
+        };
+    "#;
+    let file = parse_triggers_to_ast(input).unwrap();
+    let trigger = &file.triggers()[0];
+    let cooldown = trigger.cooldown.as_ref().unwrap();
+    assert_eq!(cooldown.period, Duration::from_secs(30));
+    assert_eq!(cooldown.key.as_deref(), Some("player.id"));
+    let max_fires = trigger.max_fires.as_ref().unwrap();
+    assert_eq!(
+        (max_fires.count, max_fires.window),
+        (3, Duration::from_secs(60))
+    );
+    assert_eq!(max_fires.key, None);
+    assert_eq!(cooldown.to_string(), "30s by player.id");
+    assert_eq!(max_fires.to_string(), "3 per 1m");
+
+    for field in ["cooldown: 30", "max_fires: 0 per 1m", "cooldown: 0s"] {
+        let input = format!(
+            "trigger T {{ on: player_join description: \"d\" {} }};",
+            field
+        );
+        assert!(
+            matches!(
+                parse_triggers_to_ast(&input),
+                Err(TriggerParserError::InvalidField { .. })
+            ),
+            "{}",
+            field
+        );
+    }
+}
+
+#[test]
+fn test_engine_enforces_cooldown_per_key() {
+    use std::time::Duration;
+    use triggerlang::engine::{Engine, Event, ManualClock};
+
+    let input = r#"
+        trigger Greet {
+            on: message_receive
+            description: "d"
+            condition: message.is_empty == false
+            cooldown: 30s by player.id
+            action: reply()
+        };
+    "#;
+    let clock = ManualClock::default();
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone());
+    let message = |player: i64| {
+        Event::new("message_receive")
+            .with("player.id", player)
+            .with("message.is_empty", false)
+    };
+
+    assert_eq!(engine.dispatch(&message(1)).len(), 1);
+    assert_eq!(engine.dispatch(&message(2)).len(), 1);
+    clock.advance(Duration::from_secs(10));
+    assert!(engine.dispatch(&message(1)).is_empty());
+    assert!(engine.dispatch(&Event::new("player_join")).is_empty());
+    clock.advance(Duration::from_secs(20));
+    let fired = engine.dispatch(&message(1));
+    assert_eq!(fired[0].trigger, "Greet");
+    assert_eq!(fired[0].actions[0].name, "reply");
+
+    // Keys past their cooldown are dropped as many players come and go, but
+    // not those still cooling down.
+    for player in 100..3100 {
+        assert_eq!(engine.dispatch(&message(player)).len(), 1);
+    }
+    assert!(engine.dispatch(&message(1)).is_empty());
+    assert!(engine.dispatch(&message(100)).is_empty());
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(engine.dispatch(&message(1)).len(), 1);
+}
+
+#[test]
+fn test_engine_enforces_max_fires() {
+    use std::time::Duration;
+    use triggerlang::engine::{Engine, Event, ManualClock};
+
+    let input = r#"
+        trigger Spam {
+            on: message_receive
+            description: "d"
+            max_fires: 3 per 1m
+            action: reply()
+        };
+    "#;
+    let clock = ManualClock::default();
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone());
+    let event = Event::new("message_receive");
+
+    for _ in 0..3 {
+        assert_eq!(engine.dispatch(&event).len(), 1);
+        clock.advance(Duration::from_secs(10));
+    }
+    assert!(engine.dispatch(&event).is_empty());
+    // The first firing leaves the window 60s after it happened.
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(engine.dispatch(&event).len(), 1);
+    assert!(engine.dispatch(&event).is_empty());
+}
+
+#[test]
+fn test_engine_priority_and_stop_propagation() {
+    use triggerlang::engine::{Engine, Event, STOP_PROPAGATION};
+
+    let input = r#"
+        trigger Welcome { on: player_join description: "d" action: welcome() };
+        trigger Audit { on: player_join description: "d" priority: -1 action: audit() };
+        trigger Greet { on: player_join description: "d" action: greet() };
+        trigger Returning {
+            on: player_leave then player_join within 1m by player.id
+            description: "d"
+            priority: 5
+            action: greet_back()
+        };
+        trigger Announce { on: every 10m description: "d" action: announce() };
+        trigger BanCheck {
+            on: player_join
+            description: "d"
+            priority: 10
+            condition: player.is_banned == true
+            action: kick()
+            action: stop
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let ban_check = ast.get("BanCheck").unwrap();
+    assert_eq!(ban_check.priority, 10);
+    assert_eq!(
+        ban_check.actions[1].as_call().unwrap().name,
+        STOP_PROPAGATION
+    );
+    assert!(ban_check.stops_propagation());
+
+    let mut engine = Engine::new(ast);
+    let order: Vec<_> = engine
+        .firing_order("player_join")
+        .map(|t| t.name.as_str())
+        .collect();
+    assert_eq!(
+        order,
+        ["BanCheck", "Returning", "Welcome", "Greet", "Audit"]
+    );
+    let order = |event_type| {
+        engine
+            .firing_order(event_type)
+            .map(|t| t.name.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(order("player_leave"), ["Returning"]);
+    assert!(order("every 10m").is_empty());
+    let scheduled: Vec<_> = engine.scheduled().map(|t| t.name.as_str()).collect();
+    assert_eq!(scheduled, ["Announce"]);
+
+    let fired = engine.dispatch(&Event::new("player_join").with("player.is_banned", false));
+    let names: Vec<_> = fired.iter().map(|f| f.trigger.as_str()).collect();
+    assert_eq!(names, ["Welcome", "Greet", "Audit"]);
+
+    let fired = engine.dispatch(&Event::new("player_join").with("player.is_banned", true));
+    assert_eq!(fired.len(), 1);
+    assert!(fired[0].stopped);
+    let actions: Vec<_> = fired[0].actions.iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(actions, ["kick"]);
+}
+
+#[test]
+fn test_else_actions_and_if_blocks() {
+    use triggerlang::engine::{Engine, Event};
+    use triggerlang::{Action, TriggerParserError};
+
+    let input = r#"
+        trigger Welcome {
+            on: player_join
+            description: "Welcome new and returning players"
+            condition: player.is_new == true
+            action: send_message("Welcome!")
+            if player.score > 100 {
+                action: give_reward("veteran_pack")
+            } else if player.score > 10 {
+                action: give_reward("starter_pack")
+            } else {
+                action: log_event("no reward")
+            }
+            else_action: send_message("Welcome back!")
+        };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let trigger = &ast.triggers()[0];
+    assert_eq!(trigger.actions.len(), 2);
+    assert_eq!(trigger.else_actions.len(), 1);
+    match &trigger.actions[1] {
+        Action::If { otherwise, .. } => assert!(matches!(otherwise[..], [Action::If { .. }])),
+        other => panic!("expected if block, got {:?}", other),
+    }
+    assert_eq!(
+        trigger.actions[1].to_string(),
+        "if player.score > 100 { give_reward(\"veteran_pack\"); } else { \
+         if player.score > 10 { give_reward(\"starter_pack\"); } else { \
+         log_event(\"no reward\"); }; }"
+    );
+
+    let mut engine = Engine::new(ast);
+    let run = |engine: &mut Engine, is_new: bool, score: i64| -> Vec<String> {
+        let event = Event::new("player_join")
+            .with("player.is_new", is_new)
+            .with("player.score", score);
+        engine.dispatch(&event)[0]
+            .actions
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    };
+    assert_eq!(
+        run(&mut engine, true, 50),
+        [
+            "send_message(\"Welcome!\")",
+            "give_reward(\"starter_pack\")"
+        ]
+    );
+    assert_eq!(
+        run(&mut engine, true, 5),
+        ["send_message(\"Welcome!\")", "log_event(\"no reward\")"]
+    );
+    assert_eq!(
+        run(&mut engine, false, 500),
+        ["send_message(\"Welcome back!\")"]
+    );
+
+    let input = r#"trigger T { on: player_join description: "d" else_action: test() };"#;
+    match parse_triggers_to_ast(input) {
+        Err(TriggerParserError::MissingField { field, .. }) => assert_eq!(field, "condition"),
+        other => panic!("expected missing condition, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_enabled_flag_tags_and_filters() {
+    use triggerlang::TriggerFilter;
+    use triggerlang::engine::{Engine, Event};
+
+    let input = r#"
+        trigger Duel { on: player_join description: "d" tags: ["pvp"] action: a() };
+        trigger Xmas { on: player_join description: "d" tags: ["holiday", "pvp"] action: b() };
+        trigger Old { on: player_join description: "d" enabled: false action: c() };
+        trigger Plain { on: player_join description: "d" action: d() };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    assert!(!ast.get("Old").unwrap().enabled);
+    assert_eq!(ast.get("Xmas").unwrap().tags, ["holiday", "pvp"]);
+
+    let usage: Vec<(&str, Vec<&str>)> = ast
+        .tag_usage()
+        .into_iter()
+        .map(|(tag, triggers)| (tag, triggers.iter().map(|t| t.name.as_str()).collect()))
+        .collect();
+    assert_eq!(
+        usage,
+        [("holiday", vec!["Xmas"]), ("pvp", vec!["Duel", "Xmas"])]
+    );
+
+    let names = |filter: &TriggerFilter| -> Vec<String> {
+        ast.clone()
+            .filtered(filter)
+            .into_triggers()
+            .into_iter()
+            .map(|t| t.name)
+            .collect()
+    };
+    assert_eq!(names(&TriggerFilter::default()), ["Duel", "Xmas", "Plain"]);
+    let pvp = TriggerFilter {
+        tags: vec!["pvp".to_string()],
+        exclude_tags: vec!["holiday".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(names(&pvp), ["Duel"]);
+    let all = TriggerFilter {
+        include_disabled: true,
+        ..Default::default()
+    };
+    assert_eq!(names(&all).len(), 4);
+
+    // The engine never runs disabled triggers.
+    let mut engine = Engine::new(ast.filtered(&all));
+    let fired: Vec<_> = engine
+        .dispatch(&Event::new("player_join"))
+        .into_iter()
+        .map(|f| f.trigger)
+        .collect();
+    assert_eq!(fired, ["Duel", "Xmas", "Plain"]);
+}
+
+#[test]
+fn test_scheduled_triggers_parse() {
+    use std::time::{Duration, SystemTime};
+    use triggerlang::TriggerParserError;
+    use triggerlang::schedule::{Cron, Schedule};
+
+    let input = r#"
+        trigger Announce { on: every 600s description: "d" action: announce() };
+        trigger Restart { on: schedule("0  */2 * * *") description: "d" action: warn() };
+    "#;
+    let ast = parse_triggers_to_ast(input).unwrap();
+    let announce = ast.get("Announce").unwrap();
+    assert_eq!(
+        announce.schedule,
+        Some(Schedule::Every(Duration::from_secs(600)))
+    );
+    assert_eq!(announce.event_type, "every 10m");
+    assert_eq!(
+        ast.get("Restart").unwrap().event_type,
+        "schedule(\"0 */2 * * *\")"
+    );
+
+    let input = r#"trigger T { on: schedule("61 * * * *") description: "d" };"#;
+    match parse_triggers_to_ast(input) {
+        Err(e @ TriggerParserError::InvalidField { .. }) => assert_eq!(
+            e.to_string(),
+            "Invalid value for field 'on' at 1:26: expected a cron expression \
+             (minute: '61' is outside 0-59)"
+        ),
+        other => panic!("expected invalid cron, got {:?}", other),
+    }
+
+    // Weekdays at 9:30 UTC; 2024-01-01 was a Monday.
+    let cron = Cron::parse("30 9 * * 1-5").unwrap();
+    let at = |secs: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+    assert!(cron.matches(at(1_704_101_400)));
+    assert!(!cron.matches(at(1_704_101_400 - 86_400)));
+    assert_eq!(
+        cron.next_after(at(1_704_101_400 - 86_400)),
+        Some(at(1_704_101_400))
+    );
+
+    // A field starting with `*` is not restricted: this is the odd days of
+    // the month that are also Mondays, not either of them.
+    let cron = Cron::parse("0 0 */2 * 1").unwrap();
+    let january = |day: u64| at(1_704_067_200 + (day - 1) * 86_400);
+    assert!(cron.matches(january(1)));
+    assert!(!cron.matches(january(8)));
+    assert!(!cron.matches(january(3)));
+
+    for impossible in ["0 0 30 2 *", "0 0 31 4,6 *", "0 0 30-31 2 */2"] {
+        assert!(Cron::parse(impossible).is_err(), "{}", impossible);
+    }
+    assert!(Cron::parse("0 0 29 2 *").is_ok());
+    assert!(Cron::parse("0 0 30 2 1").is_ok());
+
+    // Periods are counted in 128 bits, so fine intervals still work long
+    // after the start.
+    let every = Schedule::Every(Duration::from_millis(1));
+    let start = at(0);
+    let later = at(100 * 86_400);
+    assert_eq!(
+        every.next_after(start, later),
+        Some(later + Duration::from_millis(1))
+    );
+}
+
+#[test]
+fn test_scheduler_advances_virtual_time() {
+    use std::time::{Duration, SystemTime};
+    use triggerlang::engine::{Engine, Event, ManualClock};
+
+    let input = r#"
+        trigger Announce { on: every 10m description: "d" action: announce() };
+        trigger Restart {
+            on: schedule("0 */2 * * *")
+            description: "d"
+            priority: 1
+            action: warn_restart()
+        };
+        trigger Join { on: player_join description: "d" action: greet() };
+    "#;
+    // 2024-01-01 00:30 UTC.
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_069_000);
+    let clock = ManualClock::new(start);
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone());
+    let tick = |engine: &mut Engine| -> Vec<String> {
+        engine.tick().into_iter().map(|f| f.trigger).collect()
+    };
+    let minutes = |n: u64| Duration::from_secs(n * 60);
+
+    clock.advance(minutes(5));
+    assert!(tick(&mut engine).is_empty());
+    clock.advance(minutes(5));
+    assert_eq!(tick(&mut engine), ["Announce"]);
+    assert!(tick(&mut engine).is_empty());
+
+    // 02:00: both are due; missed intervals of Announce only fire once.
+    clock.advance(minutes(80));
+    assert_eq!(tick(&mut engine), ["Restart", "Announce"]);
+    assert_eq!(engine.next_due("Announce"), Some(start + minutes(100)));
+    assert_eq!(engine.next_due("Restart"), Some(start + minutes(210)));
+    assert_eq!(engine.next_due("Join"), None);
+
+    // Scheduled triggers do not react to events, and events do not tick.
+    assert_eq!(engine.dispatch(&Event::new("every 10m")).len(), 0);
+    assert_eq!(engine.dispatch(&Event::new("player_join")).len(), 1);
+}
+
+#[test]
+fn test_event_patterns_parse() {
+    let input = r#"
+        trigger Spam {
+            on: player_join then 5 message_receive within 10s by player.id
+            description: "Burst of messages right after joining"
+            action: mute_player(player.id)
+        };
+        trigger Hopping { on: 3 player_leave within 1m by player.ip description: "d" action: flag() };
+    "#;
+    let file = parse_triggers_to_ast(input).unwrap();
+
+    let spam = file.triggers()[0].pattern.as_ref().unwrap();
+    assert_eq!(spam.steps.len(), 2);
+    assert_eq!(spam.steps[0].event_type, "player_join");
+    assert_eq!(spam.steps[0].count, 1);
+    assert_eq!(spam.steps[1].event_type, "message_receive");
+    assert_eq!(spam.steps[1].count, 5);
+    assert_eq!(spam.within, std::time::Duration::from_secs(10));
+    assert_eq!(spam.key.as_deref(), Some("player.id"));
+    assert_eq!(
+        file.triggers()[0].event_type,
+        "player_join then 5 message_receive within 10s by player.id"
+    );
+    assert_eq!(
+        file.triggers()[1].event_type,
+        "3 player_leave within 1m by player.ip"
+    );
+
+    let err =
+        parse_triggers_to_ast(r#"trigger T { on: 0 player_leave within 1m description: "d" };"#)
+            .unwrap_err();
+    assert!(
+        err.to_string().contains("Invalid value for field 'on'"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_engine_matches_event_patterns() {
+    use std::time::{Duration, SystemTime};
+    use triggerlang::engine::{Engine, Event, ManualClock};
+
+    let input = r#"
+        trigger Spam {
+            on: player_join then 3 message_receive within 10s by player.id
+            description: "d"
+            condition: !player.is_admin
+            action: mute_player(player.id)
+        };
+        trigger Hopping { on: 3 player_leave within 1m by player.ip description: "d" action: flag() };
+    "#;
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone());
+    let fired = |engine: &mut Engine, event: Event| -> Vec<String> {
+        engine
+            .dispatch(&event)
+            .into_iter()
+            .map(|f| f.trigger)
+            .collect()
+    };
+    let join = |id: i64| Event::new("player_join").with("player.id", id);
+    let message = |id: i64| Event::new("message_receive").with("player.id", id);
+
+    // Messages before the join do not count, nor do other players' messages.
+    assert!(fired(&mut engine, message(1)).is_empty());
+    assert!(fired(&mut engine, join(1)).is_empty());
+    assert!(fired(&mut engine, message(1)).is_empty());
+    assert!(fired(&mut engine, message(2)).is_empty());
+    assert!(fired(&mut engine, message(1)).is_empty());
+    assert_eq!(fired(&mut engine, message(1)), ["Spam"]);
+    // The match is consumed.
+    assert!(fired(&mut engine, message(1)).is_empty());
+
+    // The condition filters the counted events.
+    fired(&mut engine, join(3).with("player.is_admin", true));
+    for _ in 0..3 {
+        assert!(fired(&mut engine, message(3).with("player.is_admin", true)).is_empty());
+    }
+
+    // Matches expire once older than the window.
+    fired(&mut engine, join(4));
+    fired(&mut engine, message(4));
+    fired(&mut engine, message(4));
+    clock.advance(Duration::from_secs(11));
+    assert!(fired(&mut engine, message(4)).is_empty());
+
+    // A later start can still complete when an earlier one expired.
+    let leave = || Event::new("player_leave").with("player.ip", "10.0.0.1");
+    fired(&mut engine, leave());
+    clock.advance(Duration::from_secs(40));
+    fired(&mut engine, leave());
+    clock.advance(Duration::from_secs(30));
+    assert!(fired(&mut engine, leave()).is_empty());
+    assert_eq!(fired(&mut engine, leave()), ["Hopping"]);
+    // Events without the key are ignored.
+    for _ in 0..3 {
+        assert!(fired(&mut engine, Event::new("player_leave")).is_empty());
+    }
+}
+
+#[test]
+fn test_event_pattern_memory_is_bounded() {
+    use triggerlang::engine::{Engine, Event};
+
+    let input = r#"
+        trigger Hopping { on: 3 player_leave within 1h by player.ip description: "d" action: flag() };
+    "#;
+    let mut engine = Engine::new(parse_triggers_to_ast(input).unwrap()).with_max_pattern_keys(100);
+    let leave = |ip: i64| Event::new("player_leave").with("player.ip", ip);
+
+    // Repeated events of one key keep one partial match per progress.
+    for _ in 0..2 {
+        engine.dispatch(&leave(0));
+    }
+    assert_eq!(engine.pending_matches(), 2);
+
+    for ip in 1..1_000 {
+        engine.dispatch(&leave(ip));
+    }
+    assert!(engine.pending_matches() <= 100 * 2);
+    // The most recent keys are still tracked.
+    engine.dispatch(&leave(999));
+    assert_eq!(engine.dispatch(&leave(999)).len(), 1);
+}
+
+#[test]
+fn test_engine_state_actions_and_conditions() {
+    use triggerlang::engine::{Engine, Event};
+    use triggerlang::state::MemoryStateStore;
+    use triggerlang::{Value, state::StateStore};
+
+    let input = r#"
+        trigger HighScoreReward {
+            on: player_score_change
+            description: "d"
+            condition: player.score >= 1000 && !player.has_received_reward
+            action: give_reward("gold_medal")
+            action: set_flag("has_received_reward")
+            action: incr("state.rewards_given")
+        };
+        trigger CountJoins {
+            on: player_join
+            description: "d"
+            action: incr("joins")
+            action: incr("state.joins", 2)
+            if state.joins >= 4 { action: announce("busy") }
+        };
+        trigger EventOpen {
+            on: message_receive
+            description: "d"
+            condition: get("state.event_open")
+            action: clear_flag("state.event_open")
+            action: set_flag("bogus", 1)
+        };
+    "#;
+    let mut engine = Engine::new(parse_triggers_to_ast(input).unwrap());
+    let score = |id: i64| {
+        Event::new("player_score_change")
+            .with("player.id", id)
+            .with("player.score", 1500)
+    };
+
+    assert_eq!(engine.dispatch(&score(1)).len(), 1);
+    assert!(engine.dispatch(&score(1)).is_empty());
+    assert_eq!(engine.dispatch(&score(2))[0].actions.len(), 1);
+    assert_eq!(
+        engine.state().get("player.1.has_received_reward"),
+        Some(Value::Boolean(true))
+    );
+    assert_eq!(
+        engine.state().get("state.rewards_given"),
+        Some(Value::Number("2".to_string()))
+    );
+
+    let join = || Event::new("player_join").with("player.id", "alice");
+    assert!(engine.dispatch(&join())[0].actions.is_empty());
+    assert!(engine.dispatch(&join())[0].actions.is_empty());
+    assert_eq!(engine.dispatch(&join())[0].actions[0].name, "announce");
+    assert_eq!(
+        engine.state().get("player.alice.joins"),
+        Some(Value::Number("3".to_string()))
+    );
+
+    let message = Event::new("message_receive");
+    assert!(engine.dispatch(&message).is_empty());
+    let mut store = MemoryStateStore::new();
+    store.set("state.event_open", Value::Boolean(true)).unwrap();
+    let mut engine = Engine::new(parse_triggers_to_ast(input).unwrap()).with_state(store);
+    let firing = &engine.dispatch(&message)[0];
+    assert_eq!(
+        firing.state_errors,
+        ["Invalid action 'set_flag(\"bogus\", 1)': expected a state name"]
+    );
+    assert!(engine.dispatch(&message).is_empty());
+}
+
+#[test]
+fn test_file_state_store_survives_restarts() {
+    use triggerlang::Value;
+    use triggerlang::engine::{Engine, Event};
+    use triggerlang::state::{FileStateStore, StateStore};
+
+    let dir = project_dir("state", &[]);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("state.json");
+    let input = r#"
+        trigger Once {
+            on: player_join
+            description: "d"
+            condition: !player.welcomed
+            action: welcome()
+            action: set_flag("welcomed")
+        };
+    "#;
+    let join = Event::new("player_join").with("player.id", 7);
+
+    let mut engine = Engine::new(parse_triggers_to_ast(input).unwrap())
+        .with_state(FileStateStore::open(&path).unwrap());
+    assert_eq!(engine.dispatch(&join).len(), 1);
+    assert!(engine.dispatch(&join).is_empty());
+
+    let mut engine = Engine::new(parse_triggers_to_ast(input).unwrap())
+        .with_state(FileStateStore::open(&path).unwrap());
+    assert!(engine.dispatch(&join).is_empty());
+    assert_eq!(
+        engine
+            .dispatch(&Event::new("player_join").with("player.id", 8))
+            .len(),
+        1
+    );
+
+    // Changes are written once per event, and ids cannot run into names.
+    let dotted = Event::new("player_join").with("player.id", "a.b");
+    assert_eq!(engine.dispatch(&dotted).len(), 1);
+    let saved = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        saved.contains("\"player.a%2Eb.welcomed\": true"),
+        "{}",
+        saved
+    );
+    assert!(engine.dispatch(&dotted).is_empty());
+    drop(engine);
+
+    let mut store = FileStateStore::open(&path).unwrap();
+    store.set("state.pending", Value::Boolean(true)).unwrap();
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("pending"));
+    store.flush().unwrap();
+    assert!(std::fs::read_to_string(&path).unwrap().contains("pending"));
+    drop(store);
+
+    std::fs::write(&path, "[1, 2]").unwrap();
+    let err = FileStateStore::open(&path).unwrap_err();
+    assert!(err.to_string().starts_with("State store "), "{}", err);
+    assert!(matches!(
+        err,
+        triggerlang::engine::EngineError::StateError { .. }
+    ));
+}
+
+#[test]
+fn test_aggregates_parse() {
+    use triggerlang::{Aggregate, AggregateFn, Expr, Value};
+
+    let input = r#"
+        trigger Flood {
+            on: message_receive
+            description: "d"
+            condition: count(message_receive, player.id, 1m) > 10 && player.score < sum(player_score_change.delta, 5m)
+            action: mute_player(player.id)
+        };
+    "#;
+    let file = parse_triggers_to_ast(input).unwrap();
+    let condition = file.triggers()[0].condition.as_ref().unwrap();
+    assert_eq!(
+        condition.to_string(),
+        "(count(message_receive, player.id, 1m) > 10 AND player.score < sum(player_score_change.delta, 5m))"
+    );
+    let Expr::And(left, _) = condition else {
+        panic!("expected AND, got {}", condition);
+    };
+    let Expr::Comparison(comp) = left.as_ref() else {
+        panic!("expected a comparison, got {}", left);
+    };
+    assert_eq!(
+        comp.left,
+        Value::Aggregate(Box::new(Aggregate {
+            function: AggregateFn::Count,
+            event_type: "message_receive".to_string(),
+            field: None,
+            key: Some("player.id".into()),
+            window: std::time::Duration::from_secs(60),
+        }))
+    );
+    assert_eq!(comp.field(), None);
+
+    for (condition, message) in [
+        (
+            "sum(player_score_change, 5m) > 1",
+            "Invalid sum() at 1:57: expected a field, e.g. player_score_change.delta",
+        ),
+        (
+            "count(message_receive.text, 5m) > 1",
+            "Invalid count() at 1:57: count takes an event type, not a field",
+        ),
+        (
+            "count(message_receive, 0s) > 1",
+            "Invalid count() at 1:57: the window must be positive",
+        ),
+    ] {
+        let input = format!(
+            r#"trigger T {{ on: player_join description: "d" condition: {} }};"#,
+            condition
+        );
+        let err = parse_triggers_to_ast(&input).unwrap_err();
+        assert_eq!(err.to_string(), message);
+    }
+}
+
+#[test]
+fn test_engine_evaluates_aggregates() {
+    use std::time::{Duration, SystemTime};
+    use triggerlang::engine::{Engine, Event, ManualClock};
+
+    let input = r#"
+        trigger Flood {
+            on: message_receive
+            description: "d"
+            condition: count(message_receive, player.id, 1m) > 3
+            action: mute_player(player.id)
+        };
+        trigger Climber {
+            on: player_score_change
+            description: "d"
+            condition: sum(player_score_change.delta, player.id, 5m) >= 100
+                && max(player_score_change.delta, 5m) >= 30
+            action: flag_player(player.id)
+        };
+    "#;
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone());
+    let message = |id: i64| Event::new("message_receive").with("player.id", id);
+    let score = |id: i64, delta: i64| {
+        Event::new("player_score_change")
+            .with("player.id", id)
+            .with("delta", delta)
+    };
+
+    for _ in 0..3 {
+        assert!(engine.dispatch(&message(1)).is_empty());
+        clock.advance(Duration::from_secs(10));
+    }
+    assert!(engine.dispatch(&message(2)).is_empty());
+    assert_eq!(engine.dispatch(&message(1)).len(), 1);
+    // The first two messages drop out of the window.
+    clock.advance(Duration::from_secs(41));
+    assert!(engine.dispatch(&message(1)).is_empty());
+
+    assert!(engine.dispatch(&score(1, 40)).is_empty());
+    assert!(engine.dispatch(&score(2, 90)).is_empty());
+    assert_eq!(engine.dispatch(&score(1, 60)).len(), 1);
+    clock.advance(Duration::from_secs(5 * 60 + 1));
+    assert!(engine.dispatch(&score(1, 50)).is_empty());
+}
+
+#[test]
+fn test_event_history_is_capped() {
+    use std::time::{Duration, SystemTime};
+    use triggerlang::engine::{Engine, Event, ManualClock};
+
+    let input = r#"
+        trigger Flood {
+            on: message_receive
+            description: "d"
+            condition: count(message_receive, 1h) >= 50
+            action: slow_mode()
+        };
+    "#;
+    let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone())
+        .with_history_limits(Duration::from_secs(60), 20);
+
+    // Events of types no aggregate uses are not recorded.
+    engine.dispatch(&Event::new("player_join"));
+    assert_eq!(engine.recorded_events(), 0);
+
+    for _ in 0..100 {
+        assert!(engine.dispatch(&Event::new("message_receive")).is_empty());
+    }
+    assert_eq!(engine.recorded_events(), 20);
+
+    // Retention is shorter than the window.
+    clock.advance(Duration::from_secs(61));
+    engine.dispatch(&Event::new("message_receive"));
+    assert_eq!(engine.recorded_events(), 1);
+
+    // Keyed counts stay right while the cap evicts other keys' events.
+    let input = r#"
+        trigger Flood {
+            on: message_receive
+            description: "d"
+            condition: count(message_receive, player.id, 1h) >= 5
+            action: mute_player(player.id)
+        };
+    "#;
+    let mut engine = Engine::with_clock(parse_triggers_to_ast(input).unwrap(), clock.clone())
+        .with_history_limits(Duration::from_secs(3600), 12);
+    let message = |id: i64| Event::new("message_receive").with("player.id", id);
+    for round in 0..4 {
+        for id in 0..3 {
+            assert!(engine.dispatch(&message(id)).is_empty(), "round {}", round);
+        }
+    }
+    assert_eq!(engine.dispatch(&message(1)).len(), 1);
+    // The cap of 12 leaves player 0 with three of its four messages.
+    assert!(engine.dispatch(&message(0)).is_empty());
+    assert_eq!(engine.dispatch(&message(0)).len(), 1);
+}
+
+#[test]
+fn test_bytecode_shape() {
+    use triggerlang::ComparisonOp;
+    use triggerlang::bytecode::{Op, Operand, SlotTable, compile};
+
+    let file = parse_triggers_to_ast(
+        r#"
+        trigger A {
+            on: player_join
+            description: "d"
+            condition: player.score > 10 && (player.is_new || player.score < 10)
+        };
+        trigger B {
+            on: player_join
+            description: "d"
+            condition: !player.is_new
+        };
+    "#,
+    )
+    .unwrap();
+    let mut slots = SlotTable::new();
+    let a = compile(file.triggers()[0].condition.as_ref().unwrap(), &mut slots);
+    let b = compile(file.triggers()[1].condition.as_ref().unwrap(), &mut slots);
+
+    assert_eq!(
+        a.code(),
+        [
+            Op::Compare {
+                left: Operand::Slot(0),
+                op: ComparisonOp::Gt,
+                right: Operand::Const(0),
+            },
+            Op::JumpIfFalse(5),
+            Op::Truthy(1),
+            Op::JumpIfTrue(5),
+            Op::Compare {
+                left: Operand::Slot(0),
+                op: ComparisonOp::Lt,
+                right: Operand::Const(0),
+            },
+        ]
+    );
+    assert_eq!(a.constants().len(), 1);
+    assert_eq!(b.code(), [Op::Truthy(1), Op::Not]);
+    assert_eq!(slots.len(), 2);
+    assert_eq!(slots.path(1), "player.is_new");
+}
+
+#[test]
+fn test_bytecode_matches_tree_walker() {
+    use triggerlang::bytecode::{Frame, SlotTable, compile};
+    use triggerlang::engine::{Event, evaluate_with_state};
+    use triggerlang::state::{MemoryStateStore, StateStore};
+
+    let conditions = [
+        "player.score >= 100 && !player.is_banned",
+        "player.is_new || player.score < 0 || player.name == \"admin\"",
+        "!(player.score > 10 && player.score < 20) || player.name != other.name",
+        "true && (false || player.is_new)",
+        "player.level == player.score",
+        "unknown.field != 3",
+        "get(\"state.open\") && !player.welcomed",
+        "send_message(\"x\") || !(!(player.is_new))",
+    ];
+    let mut store = MemoryStateStore::new();
+    store.set("state.open", true.into()).unwrap();
+    store.set("player.1.welcomed", true.into()).unwrap();
+    let events = [
+        Event::new("player_join"),
+        Event::new("player_join")
+            .with("player.id", 1)
+            .with("player.score", 150)
+            .with("player.is_new", true),
+        Event::new("player_join")
+            .with("player.id", 2)
+            .with("player.score", 15)
+            .with("player.level", 15)
+            .with("player.name", "admin")
+            .with("other.name", "admin")
+            .with("player.is_banned", true),
+        Event::new("player_join")
+            .with("player.score", -5)
+            .with("player.is_new", false)
+            .with("player.name", "bob")
+            .with("unknown.field", "3"),
+    ];
+
+    let mut slots = SlotTable::new();
+    let programs: Vec<_> = conditions
+        .iter()
+        .map(|condition| {
+            let input = format!(
+                r#"trigger T {{ on: player_join description: "d" condition: {} }};"#,
+                condition
+            );
+            let file = parse_triggers_to_ast(&input).unwrap();
+            let expr = file.triggers()[0].condition.clone().unwrap();
+            let program = compile(&expr, &mut slots);
+            (expr, program)
+        })
+        .collect();
+
+    for event in &events {
+        let frame = Frame::new(&slots, event);
+        for (expr, program) in &programs {
+            assert_eq!(
+                program.run(&frame, &store),
+                evaluate_with_state(expr, event, &store),
+                "{} on {:?}",
+                expr,
+                event
+            );
+        }
+    }
+}
+
+#[test]
+fn test_network_shares_comparisons() {
+    use triggerlang::engine::{Event, evaluate};
+    use triggerlang::network::Network;
+    use triggerlang::state::MemoryStateStore;
+
+    let input = r#"
+        trigger A { on: message_receive description: "d" condition: !player.is_banned && message.sender.is_admin == true };
+        trigger B { on: message_receive description: "d" condition: (!player.is_banned) && (message.sender.is_admin == true || player.score > 10) };
+        trigger C { on: message_receive description: "d" condition: player.score > 10.0 && !player.is_banned };
+        trigger D { on: player_join description: "d" condition: !player.is_banned };
+        trigger E { on: message_receive description: "d" };
+    "#;
+    let file = parse_triggers_to_ast(input).unwrap();
+    let network = Network::new(file.triggers());
+    // player.is_banned, is_admin == true and score > 10 (written two ways)
+    // on message_receive, and player.is_banned again on player_join.
+    assert_eq!(network.test_count(), 4);
+
+    let store = MemoryStateStore::new();
+    let events = [
+        Event::new("message_receive"),
+        Event::new("message_receive")
+            .with("player.is_banned", false)
+            .with("message.sender.is_admin", true),
+        Event::new("message_receive")
+            .with("player.score", 11)
+            .with("message.sender.is_admin", false),
+        Event::new("player_join").with("player.is_banned", true),
+    ];
+    for event in &events {
+        let expected: Vec<usize> = file
+            .triggers()
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.event_type == event.event_type)
+            .filter(|(_, t)| t.condition.as_ref().is_none_or(|c| evaluate(c, event)))
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(network.matching(event, &store), expected, "{:?}", event);
+    }
+    assert_eq!(network.matching(&events[1], &store), [0, 1, 4]);
+    // Conditions are only evaluated for their own event type.
+    assert!(!network.evaluation(&events[3]).holds(4, &store));
+    assert!(
+        network
+            .matching(&Event::new("server_start"), &store)
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_engine_sees_state_changed_by_earlier_triggers() {
+    use triggerlang::engine::{Engine, Event};
+
+    let input = r#"
+        trigger First { on: player_join description: "d" priority: 1 condition: !player.greeted action: set_flag("greeted") };
+        trigger Second { on: player_join description: "d" condition: !player.greeted action: greet() };
+        trigger Third { on: player_join description: "d" condition: player.greeted action: welcome_back() };
+    "#;
+    let mut engine = Engine::new(parse_triggers_to_ast(input).unwrap());
+    let join = Event::new("player_join").with("player.id", 1);
+    let fired: Vec<_> = engine
+        .dispatch(&join)
+        .into_iter()
+        .map(|f| f.trigger)
+        .collect();
+    assert_eq!(fired, ["First", "Third"]);
+}
+
+#[test]
+fn test_field_paths_are_parsed_into_segments() {
+    use triggerlang::path::Symbol;
+    use triggerlang::{Expr, FieldPath};
+
+    let file = parse_triggers_to_ast(
+        r#"trigger T { on: message_receive description: "d" condition: message.sender.is_admin && is_weekend };"#,
+    )
+    .unwrap();
+    let Some(Expr::And(left, right)) = &file.triggers()[0].condition else {
+        panic!("expected AND");
+    };
+    let (Expr::Ident(path), Expr::Ident(name)) = (left.as_ref(), right.as_ref()) else {
+        panic!("expected identifiers");
+    };
+    assert_eq!(path.segments(), ["message", "sender", "is_admin"]);
+    assert_eq!(path.as_name(), None);
+    assert_eq!(name.as_name(), Some("is_weekend"));
+    assert_eq!(path.to_string(), "message.sender.is_admin");
+    assert_eq!(*path, FieldPath::parse("message.sender.is_admin"));
+    assert_eq!(*path, "message.sender.is_admin");
+    assert_ne!(*path, "message.sender");
+
+    // Segments are interned, and paths order like their text.
+    assert!(std::ptr::eq(
+        Symbol::intern("sender").as_str(),
+        path.segments()[1].as_str()
+    ));
+    let mut paths = ["a_b", "a.b", "a", "ab.c"].map(FieldPath::parse);
+    paths.sort();
+    assert_eq!(paths.map(|p| p.to_string()), ["a", "a.b", "a_b", "ab.c"]);
+}
+
+#[test]
+fn test_event_payload_lookup() {
+    use triggerlang::engine::{Event, evaluate};
+    use triggerlang::payload::EventPayload;
+    use triggerlang::{FieldPath, Value};
+
+    let event = Event::new("message_receive")
+        .with("message.sender.is_admin", true)
+        .with("message.text", "hi")
+        .with("player.id", 7);
+    let EventPayload::Map(message) = event.payload.get(&"message".into()).unwrap() else {
+        panic!("expected a map");
+    };
+    assert_eq!(message.len(), 2);
+    assert_eq!(
+        event.get("message.sender.is_admin"),
+        Some(&Value::Boolean(true))
+    );
+    assert_eq!(
+        event.get_path(&FieldPath::parse("message.text")),
+        Some(&Value::String("hi".to_string()))
+    );
+    // Maps are not values, and scalars have no fields.
+    assert_eq!(event.get("message.sender"), None);
+    assert_eq!(event.get("message.text.length"), None);
+
+    let mut payload = EventPayload::default();
+    payload.insert(
+        "message.mentions",
+        EventPayload::List(vec![Value::from("alice").into(), Value::from("bob").into()]),
+    );
+    payload.insert("message.mentions.1", Value::from("carol").into());
+    let event = Event::from_payload("message_receive", payload);
+    assert_eq!(event.get("message.mentions.1"), Some(&Value::from("carol")));
+    assert_eq!(event.get("message.mentions.2"), None);
+
+    assert_eq!(
+        Value::try_from(2.5).unwrap(),
+        Value::Number("2.5".to_string())
+    );
+    assert!(Value::try_from(f64::NAN).is_err());
+    assert!(Value::try_from(f64::NEG_INFINITY).is_err());
+
+    let condition = |source: &str| {
+        let input = format!(
+            r#"trigger T {{ on: message_receive description: "d" condition: {} }};"#,
+            source
+        );
+        parse_triggers_to_ast(&input).unwrap().triggers()[0]
+            .condition
+            .clone()
+            .unwrap()
+    };
+    assert!(evaluate(
+        &condition(r#"message.mentions.0 == "alice""#),
+        &event
+    ));
+    assert!(!evaluate(
+        &condition(r#"message.mentions.1 == "bob""#),
+        &event
+    ));
+}
+
+#[test]
+fn test_borrowed_ast_points_into_source() {
+    use std::borrow::Cow;
+    use triggerlang::borrowed;
+    use triggerlang::parse_triggers_borrowed;
+
+    let input = r#"
+        condition Spam = message.length > 200;
+        template Warn(field) {
+            on: message_receive
+            description: "warn"
+            condition: field == true
+            action: warn_player(player.id)
+        };
+        trigger Long {
+            on: message_receive
+            description: "Long messages"
+            condition: Spam && !player.is_admin
+            action: warn_player(player.id, "too long")
+            tags: ["chat"]
+        };
+        trigger WarnCaps = Warn(message.all_caps);
+    "#;
+    let file = parse_triggers_borrowed(input).unwrap();
+    let within = |text: &str| input.as_bytes().as_ptr_range().contains(&text.as_ptr());
+
+    let long = &file.triggers[0];
+    assert!(matches!(long.name, Cow::Borrowed("Long")));
+    assert!(within(&long.description) && within(&long.tags[0]));
+    let Some(borrowed::Expr::And(spam, not_admin)) = &long.condition else {
+        panic!("expected AND, got {:?}", long.condition);
+    };
+    // Named conditions are expanded in place, still borrowing.
+    let borrowed::Expr::Parenthesized(spam) = spam.as_ref() else {
+        panic!("expected the expanded condition, got {:?}", spam);
+    };
+    let borrowed::Expr::Comparison(comparison) = spam.as_ref() else {
+        panic!("expected a comparison, got {:?}", spam);
+    };
+    let borrowed::Value::Ident(field) = &comparison.left else {
+        panic!("expected a field, got {:?}", comparison.left);
+    };
+    assert!(matches!(field, Cow::Borrowed("message.length")) && within(field));
+    assert!(matches!(
+        not_admin.as_ref(),
+        borrowed::Expr::Not(inner) if matches!(inner.as_ref(), borrowed::Expr::Ident(id) if within(id))
+    ));
+    let borrowed::Action::Call(warn) = &long.actions[0] else {
+        panic!("expected a call");
+    };
+    assert!(within(&warn.name));
+    assert!(matches!(&warn.args[1], borrowed::Value::String(s) if within(s)));
+
+    // Instantiated templates are built, not borrowed.
+    let caps = &file.triggers[1];
+    assert!(matches!(caps.name, Cow::Owned(_)));
+
+    let owned = file.into_owned();
+    assert_eq!(owned, parse_triggers_to_ast(input).unwrap());
+    assert_eq!(
+        owned.triggers()[1].condition.as_ref().unwrap().to_string(),
+        "message.all_caps == true"
+    );
+}
+
+#[test]
+fn test_pathological_nesting_is_rejected() {
+    use triggerlang::TriggerParserError;
+
+    let too_deep = |input: &str| {
+        matches!(
+            parse_triggers_to_ast(input),
+            Err(TriggerParserError::NestingTooDeep { limit: 128, .. })
+        )
+    };
+    let n = 10_000;
+
+    assert!(too_deep(&"(".repeat(n)));
+    assert!(too_deep(&condition_input(&format!(
+        "{}x{}",
+        "(".repeat(n),
+        ")".repeat(n)
+    ))));
+    assert!(too_deep(&condition_input(&format!("{}x", "!".repeat(n)))));
+    assert!(too_deep(&condition_input(&vec!["x"; n].join(" && "))));
+    assert!(too_deep(&condition_input(&vec!["x"; n].join(" || "))));
+    assert!(too_deep(&format!(
+        "trigger T {{ on: player_join description: \"d\" {}}};",
+        "if x { ".repeat(n)
+    )));
+    assert!(too_deep(&format!(
+        "trigger T {{ on: player_join description: \"d\" if x {{ }} {}}};",
+        "else if x { } ".repeat(n)
+    )));
+
+    // Named conditions add their depth to every reference.
+    let nested = (0..200)
+        .map(|i| format!("condition C{} = !C{};", i + 1, i))
+        .collect::<String>();
+    let input = format!("condition C0 = x; {}", nested);
+    match parse_triggers_to_ast(&input) {
+        Err(TriggerParserError::NestingTooDeep { limit, span }) => {
+            assert_eq!(limit, 128);
+            assert!(span.line == 1 && span.column > 1);
+        }
+        other => panic!("expected nesting too deep, got {:?}", other),
+    }
+
+    // Brackets in strings and comments do not count.
+    let quoted = format!(
+        "// {}\ntrigger T {{ on: player_join description: \"{}\" action: say(\"{}\") }};",
+        "(".repeat(n),
+        "{".repeat(n),
+        "(".repeat(n)
+    );
+    assert!(parse_triggers_to_ast(&quoted).is_ok());
+
+    let mut options = ParseOptions::default();
+    options.limits.max_depth = 3;
+    assert!(parse_triggers_to_ast_with(&condition_input("((x && y))"), &options).is_ok());
+    assert!(matches!(
+        parse_triggers_to_ast_with(&condition_input("(((x && y)))"), &options),
+        Err(TriggerParserError::NestingTooDeep { limit: 3, .. })
+    ));
+}
+
+#[test]
+fn test_conditions_at_the_depth_limit() {
+    use triggerlang::Limits;
+    use triggerlang::engine::{Event, evaluate};
+
+    let limit = Limits::default().max_depth;
+    let event = Event::new("player_join").with("x", true);
+    let parses = |condition: &str| {
+        let input = condition_input(condition);
+        let file = parse_triggers_to_ast(&input).ok()?;
+        file.triggers()[0].condition.clone()
+    };
+
+    let parens = |n| format!("{}x{}", "(".repeat(n), ")".repeat(n));
+    let deepest = parses(&parens(limit)).unwrap();
+    assert_eq!(deepest.depth(), limit);
+    assert!(evaluate(&deepest, &event));
+    assert_eq!(deepest.to_string(), parens(limit));
+    assert!(parses(&parens(limit + 1)).is_none());
+
+    let nots = parses(&format!("{}x", "!".repeat(limit))).unwrap();
+    assert!(evaluate(&nots, &event));
+    assert!(parses(&format!("{}x", "!".repeat(limit + 1))).is_none());
+
+    let chain = |n| vec!["x"; n].join(" && ");
+    assert!(evaluate(&parses(&chain(limit + 1)).unwrap(), &event));
+    assert!(parses(&chain(limit + 2)).is_none());
+
+    let ifs = |n: usize| {
+        format!(
+            "trigger T {{ on: player_join description: \"d\" {}action: a() {}}};",
+            "if x { ".repeat(n),
+            "} ".repeat(n)
+        )
+    };
+    assert!(parse_triggers_to_ast(&ifs(limit)).is_ok());
+    assert!(parse_triggers_to_ast(&ifs(limit + 1)).is_err());
+
+    // `else if` chains nest in the AST as they used to.
+    let file = parse_triggers_to_ast(&format!(
+        "trigger T {{ on: player_join description: \"d\" if a {{ action: one() }} {}else {{ action: last() }} }};",
+        "else if b { action: two() } ".repeat(limit - 1)
+    ))
+    .unwrap();
+    let mut action = &file.triggers()[0].actions[0];
+    let mut branches = 1;
+    while let triggerlang::Action::If { otherwise, .. } = action {
+        match otherwise.as_slice() {
+            [next @ triggerlang::Action::If { .. }] => {
+                action = next;
+                branches += 1;
+            }
+            [last] => {
+                assert_eq!(last.to_string(), "last()");
+                break;
+            }
+            other => panic!("unexpected else block {:?}", other),
+        }
+    }
+    assert_eq!(branches, limit);
+}
+
+#[test]
+fn test_fuzzed_input_does_not_panic() {
+    const TOKENS: &[&str] = &[
+        "(",
+        ")",
+        "!",
+        "&&",
+        "||",
+        "x",
+        "y.z",
+        "==",
+        "1",
+        "\"s\"",
+        "f(",
+        "{",
+        "}",
+        "if",
+        "else",
+        "trigger T {",
+        "};",
+        "condition:",
+        "on: player_join",
+        "condition A =",
+        ";",
+        "A",
+        "//",
+        "\n",
+        "count(player_join, 1m)",
+        "template",
+        "\"",
+    ];
+
+    // A fixed linear congruential generator, so that failures reproduce.
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = |bound: usize| {
+        seed = seed
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (seed >> 33) as usize % bound
+    };
+
+    for round in 0..500 {
+        let len = 1 + next(if round % 10 == 0 { 5_000 } else { 100 });
+        let mut input = String::new();
+        // Some rounds keep to one token, which is where runaway nesting hides.
+        let only = (round % 4 == 0).then(|| next(TOKENS.len()));
+        for _ in 0..len {
+            input.push_str(TOKENS[only.unwrap_or_else(|| next(TOKENS.len()))]);
+            input.push(' ');
+        }
+        let _ = parse_triggers_to_ast(&input);
+        let _ = parse_triggers_to_ast(&condition_input(&input));
+    }
+}
+
+#[test]
+fn test_deep_expressions_are_handled_iteratively() {
+    use triggerlang::Expr;
+    use triggerlang::engine::{Event, evaluate};
+    use triggerlang::network::Network;
+    use triggerlang::state::MemoryStateStore;
+
+    // Deeper than any parsed condition may be; built by hand.
+    let n = 50_000;
+    let mut expr = Expr::Ident("x".into());
+    for i in 0..n {
+        expr = if i % 2 == 0 {
+            Expr::Not(Box::new(expr))
+        } else {
+            Expr::And(Box::new(Expr::Ident("x".into())), Box::new(expr))
+        };
+    }
+
+    let event = Event::new("player_join").with("x", true);
+    assert!(evaluate(&expr, &event));
+    assert_eq!(expr.depth(), n);
+    let text = expr.to_string();
+    assert!(text.starts_with("(x AND (NOT (x AND (NOT") && text.ends_with(")))"));
+
+    let mut trigger = parse_triggers_to_ast(&condition_input("x"))
+        .unwrap()
+        .remove("T")
+        .unwrap();
+    trigger.condition = Some(expr);
+    let network = Network::new(std::slice::from_ref(&trigger));
+    assert_eq!(network.matching(&event, &MemoryStateStore::new()), [0]);
+
+    // Dropping the tree would recurse, so take it apart from the top.
+    let mut expr = trigger.condition.take().unwrap();
+    loop {
+        expr = match expr {
+            Expr::Not(inner) => *inner,
+            Expr::And(_, right) => *right,
+            _ => break,
+        };
+    }
+}
+
+#[test]
+fn test_parse_limits() {
+    use triggerlang::project::load_project_with;
+    use triggerlang::{Limits, TriggerParserError};
+
+    let with = |limits: Limits| {
+        let mut options = ParseOptions::default();
+        options.limits = limits;
+        options
+    };
+    let trigger = |name: &str| {
+        format!(
+            r#"trigger {} {{ on: player_join description: "d" action: a() }};"#,
+            name
+        )
+    };
+    let three = format!(
+        "template Tpl() {{ on: player_join description: \"t\" action: a() }}; {} {} trigger C = Tpl();",
+        trigger("A"),
+        trigger("B")
+    );
+
+    let small = with(Limits {
+        max_file_size: 16,
+        ..Default::default()
+    });
+    assert!(matches!(
+        parse_triggers_to_ast_with(&three, &small),
+        Err(TriggerParserError::FileTooLarge { limit: 16, size }) if size == three.len()
+    ));
+
+    let two = with(Limits {
+        max_triggers: 2,
+        ..Default::default()
+    });
+    match parse_triggers_to_ast_with(&three, &two) {
+        Err(TriggerParserError::TooManyTriggers { limit: 2, span }) => {
+            assert_eq!(&three[span.start..span.end], "trigger C = Tpl();");
+        }
+        other => panic!("expected too many triggers, got {:?}", other),
+    }
+    assert_eq!(parse_triggers_to_ast(&three).unwrap().triggers().len(), 3);
+
+    // `if` blocks count as actions, as do the actions inside them.
+    let actions = r#"trigger T {
+        on: player_join description: "d" condition: x
+        action: a() if y { action: b() } else_action: c()
+    };"#;
+    let max_actions = |max_actions| {
+        parse_triggers_to_ast_with(
+            actions,
+            &with(Limits {
+                max_actions,
+                ..Default::default()
+            }),
+        )
+    };
+    assert!(max_actions(4).is_ok());
+    assert!(matches!(
+        max_actions(3),
+        Err(TriggerParserError::TooManyActions { trigger, limit: 3, .. }) if trigger == "T"
+    ));
+
+    let short = with(Limits {
+        max_string_len: 5,
+        ..Default::default()
+    });
+    let long_description = trigger("T").replace("\"d\"", "\"longer\"");
+    match parse_triggers_to_ast_with(&long_description, &short) {
+        Err(TriggerParserError::StringTooLong { limit: 5, span }) => {
+            assert_eq!(&long_description[span.start..span.start + 7], "\"longer");
+        }
+        other => panic!("expected a string too long, got {:?}", other),
+    }
+    assert!(parse_triggers_to_ast_with(&trigger("T"), &short).is_ok());
+
+    let unlimited = with(Limits {
+        max_depth: usize::MAX,
+        ..Default::default()
+    });
+    assert!(parse_triggers_to_ast_with(&condition_input("((x))"), &unlimited).is_ok());
+
+    // Each definition doubles the one before, which would expand to 2^40
+    // nodes.
+    let mut bomb = String::from("condition C0 = x;\n");
+    for i in 1..=40 {
+        bomb.push_str(&format!("condition C{} = C{} && C{};\n", i, i - 1, i - 1));
+    }
+    bomb.push_str(r#"trigger T { on: player_join description: "d" condition: C40 };"#);
+    assert!(matches!(
+        parse_triggers_to_ast(&bomb),
+        Err(TriggerParserError::TooManyNodes {
+            limit: 4_000_000,
+            ..
+        })
+    ));
+    // One action each for the template, `A`, `B` and the copy in `C`.
+    let max_nodes = |max_nodes| {
+        with(Limits {
+            max_nodes,
+            ..Default::default()
+        })
+    };
+    assert!(parse_triggers_to_ast_with(&three, &max_nodes(4)).is_ok());
+    match parse_triggers_to_ast_with(&three, &max_nodes(3)) {
+        Err(TriggerParserError::TooManyNodes { limit: 3, span }) => {
+            assert_eq!(&three[span.start..span.end], "trigger C = Tpl();");
+        }
+        other => panic!("expected too many nodes, got {:?}", other),
+    }
+
+    // Interned names are never freed, so names new to the process count
+    // towards a cap across every file parsed. Names already interned do not.
+    let no_new_names = with(Limits {
+        max_interned_bytes: 0,
+        ..Default::default()
+    });
+    let known = condition_input("player.is_new");
+    parse_triggers_to_ast(&known).unwrap();
+    assert!(parse_triggers_to_ast_with(&known, &no_new_names).is_ok());
+    let novel = condition_input("player.never_interned_before");
+    match parse_triggers_to_ast_with(&novel, &no_new_names) {
+        Err(TriggerParserError::TooManyNames { limit: 0, span }) => {
+            assert_eq!(&novel[span.start..span.end], "player.never_interned_before");
+        }
+        other => panic!("expected too many names, got {:?}", other),
+    }
+
+    // Projects count the triggers of all their files.
+    let dir = project_dir(
+        "limits",
+        &[
+            ("main.tl", &format!("import \"other.tl\"; {}", trigger("A"))),
+            ("other.tl", &format!("{} {}", trigger("B"), trigger("C"))),
+        ],
+    );
+    match load_project_with(&dir.join("main.tl"), &two) {
+        Err(TriggerParserError::InFile { path, source }) => {
+            assert!(path.ends_with("main.tl"));
+            assert!(matches!(
+                *source,
+                TriggerParserError::TooManyTriggers { limit: 2, .. }
+            ));
+        }
+        other => panic!("expected too many triggers, got {:?}", other),
+    }
+    // And their nodes, though each file is within the limit on its own.
+    assert!(load_project_with(&dir.join("main.tl"), &max_nodes(3)).is_ok());
+    match load_project_with(&dir.join("main.tl"), &max_nodes(2)) {
+        Err(TriggerParserError::InFile { path, source }) => {
+            assert!(path.ends_with("main.tl"));
+            assert!(matches!(
+                *source,
+                TriggerParserError::TooManyNodes { limit: 2, .. }
+            ));
+        }
+        other => panic!("expected too many nodes, got {:?}", other),
+    }
+    assert!(matches!(
+        load_project_with(&dir.join("main.tl"), &small),
+        Err(TriggerParserError::InFile { source, .. })
+            if matches!(*source, TriggerParserError::FileTooLarge { limit: 16, .. })
+    ));
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_engine_budget() {
+    use std::time::Duration;
+    use triggerlang::Limits;
+    use triggerlang::engine::{Engine, EngineError, Event};
+    use triggerlang::limits::Overrun;
+
+    // Distinct comparisons, so that the network cannot share them.
+    let costly = (0..100)
+        .map(|i| format!("score > {}", i))
+        .collect::<Vec<_>>()
+        .join(" && ");
+    let input = format!(
+        r#"
+        trigger Cheap {{ on: player_join description: "c" condition: score > 0 action: a() }};
+        trigger Costly {{ on: player_join description: "c" condition: {} action: b() }};
+        trigger Last {{ on: player_join description: "l" action: c() }};
+        "#,
+        costly
+    );
+    let engine =
+        |limits: Limits| Engine::new(parse_triggers_to_ast(&input).unwrap()).with_limits(limits);
+    let event = Event::new("player_join").with("score", 1000);
+    let names = |fired: &[triggerlang::engine::Firing]| {
+        fired.iter().map(|f| f.trigger.clone()).collect::<Vec<_>>()
+    };
+
+    let (fired, error) = engine(Limits::default()).dispatch_checked(&event);
+    assert_eq!(names(&fired), ["Cheap", "Costly", "Last"]);
+    assert!(error.is_none());
+
+    let mut few_steps = engine(Limits {
+        max_steps: 50,
+        ..Default::default()
+    });
+    let (fired, error) = few_steps.dispatch_checked(&event);
+    assert_eq!(names(&fired), ["Cheap"]);
+    match error {
+        Some(EngineError::BudgetExceeded { trigger, kind }) => {
+            assert_eq!(trigger, "Costly");
+            assert_eq!(kind, Overrun::Steps { limit: 50 });
+        }
+        other => panic!("expected the step budget to run out, got {:?}", other),
+    }
+    // The budget is per event.
+    assert_eq!(names(&few_steps.dispatch(&event)), ["Cheap"]);
+
+    let (fired, error) = engine(Limits {
+        max_time: Duration::ZERO,
+        ..Default::default()
+    })
+    .dispatch_checked(&event);
+    assert!(fired.is_empty());
+    let error = error.unwrap();
+    assert_eq!(
+        error.to_string(),
+        "Ran out of the budget of 0ns per event at trigger 'Cheap'"
+    );
+    assert!(matches!(
+        error,
+        EngineError::BudgetExceeded { trigger, kind: Overrun::Time { limit } }
+            if trigger == "Cheap" && limit == Duration::ZERO
+    ));
+
+    // An aggregate takes a step for each recorded event it counts, the
+    // current one included, besides the step of its comparison.
+    let busy = r#"trigger Busy { on: player_join description: "b" condition: count(player_join, 1h) > 0 action: a() };"#;
+    let mut engine = Engine::new(parse_triggers_to_ast(busy).unwrap()).with_limits(Limits {
+        max_steps: 50,
+        ..Default::default()
+    });
+    for _ in 0..49 {
+        let (fired, error) = engine.dispatch_checked(&Event::new("player_join"));
+        assert_eq!(names(&fired), ["Busy"]);
+        assert!(error.is_none());
+    }
+    let (fired, error) = engine.dispatch_checked(&Event::new("player_join"));
+    assert!(fired.is_empty());
+    assert!(matches!(
+        error,
+        Some(EngineError::BudgetExceeded { trigger, kind: Overrun::Steps { limit: 50 } })
+            if trigger == "Busy"
+    ));
+
+    // So do the conditions of `if` blocks.
+    let branching = format!(
+        r#"trigger Branching {{ on: player_join description: "b" action: a() if {} {{ action: b() }} }};"#,
+        costly
+    );
+    let engine = |limits: Limits| {
+        Engine::new(parse_triggers_to_ast(&branching).unwrap()).with_limits(limits)
+    };
+    let fired = engine(Limits::default()).dispatch(&event);
+    let actions: Vec<_> = fired[0].actions.iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(actions, ["a", "b"]);
+    let (fired, error) = engine(Limits {
+        max_steps: 50,
+        ..Default::default()
+    })
+    .dispatch_checked(&event);
+    assert!(fired.is_empty());
+    assert!(matches!(
+        error,
+        Some(EngineError::BudgetExceeded { trigger, kind: Overrun::Steps { limit: 50 } })
+            if trigger == "Branching"
+    ));
+}